@@ -1,82 +1,364 @@
-use std::io;
+use std::fmt;
+use chrono::Utc;
 
+use crate::data_structures::hash_map::HashMap;
 use crate::data_structures::priority_queue::PriorityQueue;
-use crate::db::db_handler::Database;
-use crate::cli_handler::{clear_terminal, get_input_string, MenuHandler};
-use crate::db::entities::{DoctorsList, Role, User};
-use crate::sha_hasher::Sha256;
-use hex;
+use crate::db::audit::{AuditAction, AuditEvent, EntityType};
+use crate::db::db_handler::{self, Database};
+use crate::db::error::DbError;
+use crate::db::repository::Repository;
+use crate::cli_handler::{clear_terminal, get_input_string, select_role, MenuHandler};
+use crate::db::entities::{AccountStatus, DoctorsList, Role, User};
+use crate::menus_logic::enroll_totp;
+use crate::password_hasher::{self, DEFAULT_ITERATIONS};
+use crate::permissions::{Permission, PermissionError};
+use crate::session::Session;
 
 
+pub fn totp_mandatory(role: &Role) -> bool {
+    matches!(role, Role::Admin | Role::Pharmacist)
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,
+    PendingApproval,
+    Locked,
+    TooManyAttempts { retry_in: i64 },
+    TotpRequired,
+    InvalidTotp,
+    Storage(DbError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "Invalid username or password"),
+            AuthError::PendingApproval => write!(f, "Your account is waiting for admin approval"),
+            AuthError::Locked => write!(f, "Your account is locked, ask an admin to unlock it"),
+            AuthError::TooManyAttempts { retry_in } => write!(f, "Too many failed attempts, try again in {} seconds", retry_in),
+            AuthError::TotpRequired => write!(f, "A two-factor authentication code is required"),
+            AuthError::InvalidTotp => write!(f, "Invalid two-factor authentication code"),
+            AuthError::Storage(e) => write!(f, "Could not save: {}", e),
+        }
+    }
+}
+
 pub struct Auth<'a> {
     pub db: &'a mut Database,
-    pub user: Option<User>,
+    pub session: Option<Session>,
+    pub hash_iterations: u32,
+    pub max_failed_logins: u32,
+    pub backoff_base_secs: i64,
+    pub max_backoff_secs: i64,
+    pub out_of_hospital_penalty: f32,
+    pub default_appointment_priority: u32,
+    // failed attempts on usernames that don't exist, they back off and lock like real accounts
+    unknown_logins: HashMap<String, (u32, i64)>,
 }
 
 impl<'a> Auth<'a> {
     pub fn new(db: &'a mut Database) -> Self {
         Auth {
             db,
-            user: None,
+            session: None,
+            hash_iterations: DEFAULT_ITERATIONS,
+            max_failed_logins: 5,
+            backoff_base_secs: 2,
+            max_backoff_secs: 300,
+            out_of_hospital_penalty: 1.2,
+            default_appointment_priority: 5,
+            unknown_logins: HashMap::new(),
+        }
+    }
+
+    pub fn login(&mut self, username: String, password: String, totp_code: Option<String>) -> Result<(), AuthError> {
+        let result = self.try_login(username.clone(), password, totp_code);
+        let role = self.db.users().get(&username).map(|user| user.role.clone());
+        let mut event = AuditEvent::new(username.clone(), role, AuditAction::Login, EntityType::User, username);
+        match result {
+            Ok(()) => {}
+            // not a failure, the caller still has to ask for the code
+            Err(AuthError::TotpRequired) => return result,
+            Err(ref e) => {
+                event.action = AuditAction::LoginFailed;
+                event.after = Some(e.to_string());
+            }
+        }
+        self.db.record_event(event);
+        self.db.commit().map_err(AuthError::Storage)?;
+        result
+    }
+
+    // seconds until the next attempt is allowed, doubling with every failed attempt
+    fn backoff_remaining(&self, failed_logins: u32, last_failed_login: Option<i64>, now: i64) -> i64 {
+        match last_failed_login {
+            Some(last_failed) if failed_logins > 0 => {
+                let exponent = (failed_logins - 1).min(16);
+                let delay = self.backoff_base_secs.saturating_mul(1 << exponent).min(self.max_backoff_secs);
+                last_failed + delay - now
+            }
+            _ => 0,
+        }
+    }
+
+    // answers exactly like a wrong password on a real account would, so nobody can probe which usernames exist
+    fn reject_unknown_user(&mut self, username: &str, password: &str, now: i64) -> AuthError {
+        let (failed_logins, last_failed) = match self.unknown_logins.get(username) {
+            Some(&(failed_logins, last_failed)) => (failed_logins, Some(last_failed)),
+            None => (0, None),
+        };
+        if failed_logins >= self.max_failed_logins {
+            return AuthError::Locked;
+        }
+        let retry_in = self.backoff_remaining(failed_logins, last_failed, now);
+        if retry_in > 0 {
+            return AuthError::TooManyAttempts { retry_in };
+        }
+        // takes as long as checking a real password
+        password_hasher::hash_password(password, self.hash_iterations);
+        self.unknown_logins.insert(username.to_string(), (failed_logins + 1, now));
+        if failed_logins + 1 >= self.max_failed_logins { AuthError::Locked } else { AuthError::InvalidCredentials }
+    }
+
+    fn try_login(&mut self, username: String, password: String, totp_code: Option<String>) -> Result<(), AuthError> {
+        let now = Utc::now().timestamp();
+        let user = match self.db.users().get(&username) {
+            Some(user) => user.clone(),
+            None => return Err(self.reject_unknown_user(&username, &password, now)),
+        };
+        if user.status == AccountStatus::Locked {
+            return Err(AuthError::Locked);
         }
+        let retry_in = self.backoff_remaining(user.failed_logins, user.last_failed_login, now);
+        if retry_in > 0 {
+            return Err(AuthError::TooManyAttempts { retry_in });
+        }
+
+        let iterations = self.hash_iterations;
+        let max_failed_logins = self.max_failed_logins;
+        let user = self.db.users_mut().get_mut(&username).unwrap();
+        if !user.verify_password(password.clone()) {
+            user.record_failed_login(now, max_failed_logins);
+            return Err(if user.status == AccountStatus::Locked { AuthError::Locked } else { AuthError::InvalidCredentials });
+        }
+        if user.totp_enabled() {
+            match totp_code {
+                None => return Err(AuthError::TotpRequired),
+                Some(code) if !user.verify_second_factor(&code, now as u64) => {
+                    user.record_failed_login(now, max_failed_logins);
+                    return Err(if user.status == AccountStatus::Locked { AuthError::Locked } else { AuthError::InvalidTotp });
+                }
+                Some(_) => {}
+            }
+        }
+        user.failed_logins = 0;
+        user.last_failed_login = None;
+        if user.status == AccountStatus::PendingApproval {
+            return Err(AuthError::PendingApproval);
+        }
+
+        // transparently move legacy or weaker hashes to the current scheme
+        if user.password_needs_rehash(iterations) {
+            user.set_password(password, iterations);
+        }
+        let user = user.clone();
+        self.start_session(user);
+        Ok(())
+    }
+
+    fn start_session(&mut self, user: User) {
+        self.session = Some(Session::new(user, Utc::now().timestamp()));
     }
 
-    pub fn login(&mut self, username: String, password: String) -> bool {
-        match self.db.get_user(username.clone()) {
-            Some(user) => {
-                if user.verify_password(password) {
-                    self.user = Some(user.clone());
-                    true
-                } else {
-                    false
+    pub fn user(&self) -> Option<&User> {
+        self.session.as_ref().map(|session| &session.user)
+    }
+
+    pub fn current_user(&self) -> Result<&User, PermissionError> {
+        self.user().ok_or(PermissionError::NotLoggedIn)
+    }
+
+    // picks up changes made to the logged in user's record
+    pub fn refresh_user(&mut self) {
+        if let Some(ref mut session) = self.session {
+            if let Some(user) = self.db.users().get(&session.user.username) {
+                session.user = user.clone();
+            }
+        }
+    }
+
+    // called on every menu iteration, logs the user out once the session policy says so
+    pub fn check_session(&mut self) -> bool {
+        let now = Utc::now().timestamp();
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => return false,
+        };
+        match session.expiry(now) {
+            None => {
+                session.touch(now);
+                true
+            }
+            Some(expiry) => {
+                let username = session.user.username.clone();
+                self.audit(AuditAction::SessionExpired, EntityType::User, username, None, Some(expiry.to_string()));
+                // the event stays in memory and goes out with the next commit that works
+                let saved = self.db.commit();
+                self.session = None;
+                clear_terminal();
+                if let Err(e) = saved {
+                    println!("{}", AuthError::Storage(e));
                 }
+                println!("{}, please log in again", expiry);
+                false
             }
-            None => false,
         }
     }
 
+    // for flows that keep asking for input, the user may walk away in the middle of one just as well
+    pub fn require_session(&mut self) -> Result<(), PermissionError> {
+        if self.check_session() {
+            Ok(())
+        } else {
+            Err(PermissionError::SessionExpired)
+        }
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<(), PermissionError> {
+        let user = self.current_user()?;
+        if self.db.role_has_permission(&user.role, permission) {
+            Ok(())
+        } else {
+            Err(PermissionError::Denied { role: user.role.clone(), permission })
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.require(permission).is_ok()
+    }
+
+    // everyone may see their own details, other people's need ViewPii or an emergency grant
+    pub fn can_view_pii_of(&self, user: &User) -> bool {
+        match self.session {
+            Some(ref session) if session.user.username == user.username => true,
+            Some(ref session) if session.emergency_grant(&user.username, Utc::now().timestamp()).is_some() => true,
+            _ => self.can(Permission::ViewPii),
+        }
+    }
+
+    // attributes the event to the logged in user, or to nobody before login
+    pub fn audit(&mut self, action: AuditAction, entity_type: EntityType, entity_key: String, before: Option<String>, after: Option<String>) {
+        let (actor, role) = match self.user() {
+            Some(user) => (user.username.clone(), Some(user.role.clone())),
+            None => ("anonymous".to_string(), None),
+        };
+        let mut event = AuditEvent::new(actor, role, action, entity_type, entity_key);
+        event.before = before;
+        event.after = after;
+        self.db.record_event(event);
+    }
+
     pub fn logout(&mut self) {
-        self.user = None;
+        let mut saved = Ok(());
+        if let Some(username) = self.user().map(|user| user.username.clone()) {
+            self.audit(AuditAction::Logout, EntityType::User, username, None, None);
+            saved = self.db.commit();
+        }
+        self.session = None;
         clear_terminal();
+        if let Err(e) = saved {
+            println!("{}", AuthError::Storage(e));
+        }
     }
 
-    pub fn register(&mut self, username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> io::Result<User> {
-        let mut hasher = Sha256::new();
-        hasher.update(password.as_bytes());
-        let password = hex::encode(hasher.finalize());
-        
-        let user = User::new(username, password, full_name, ssn, age, role);
-        self.db.insert_user(user.clone())?;
+    pub fn register(&mut self, username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> Result<User, DbError> {
+        let user = self.new_user(username, password, full_name, ssn, age, role);
+        self.add_user(user)
+    }
 
-        if user.role == Role::Doctor || user.role == Role::EmergencyDoctor{
-            self.db.insert_doctors_list(DoctorsList { doctor: user.username.clone(), patients: PriorityQueue::new() })?;
-        }
+    fn new_user(&self, username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> User {
+        let password = password_hasher::hash_password(&password, self.hash_iterations);
+        User::new(username, password, full_name, ssn, age, role)
+    }
+
+    fn add_user(&mut self, user: User) -> Result<User, DbError> {
+        // a doctor without a queue would break every appointment flow, so both go in or neither does
+        self.transaction(|auth| {
+            auth.db.users_mut().insert(user.clone())?;
 
-        self.db.commit().unwrap();
-        Ok(user)
+            if user.role == Role::Doctor || user.role == Role::EmergencyDoctor{
+                auth.db.doctors_lists_mut().insert(DoctorsList { doctor: user.username.clone(), patients: PriorityQueue::new() })?;
+            }
+
+            let after = Some(format!("role: {:?}, status: {:?}", user.role, user.status));
+            if auth.user().is_some() {
+                auth.audit(AuditAction::Create, EntityType::User, user.username.clone(), None, after);
+            } else {
+                // self-service sign ups are attributed to the new account
+                let mut event = AuditEvent::new(user.username.clone(), Some(user.role.clone()), AuditAction::Create, EntityType::User, user.username.clone());
+                event.after = after;
+                auth.db.record_event(event);
+            }
+            Ok(user)
+        })
     }
 
-    pub fn signup(&mut self, username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> io::Result<()> {
-        let user = self.register(username, password, full_name, ssn, age, role)?;
-        self.user = Some(user);
+    // Database::transaction for steps that need the session, e.g. to register users or write audit events
+    pub fn transaction<T, E: From<DbError>>(&mut self, steps: impl FnOnce(&mut Auth) -> Result<T, E>) -> Result<T, E> {
+        db_handler::transaction(self, |auth| &mut *auth.db, steps)
+    }
+
+    // self-service sign up is only open to patients
+    pub fn signup(&mut self, username: String, password: String, full_name: String, ssn: String, age: u32) -> Result<(), DbError> {
+        let user = self.register(username, password, full_name, ssn, age, Role::Patient)?;
+        self.start_session(user);
         Ok(())
     }
 
+    // staff accounts stay locked out until an admin approves them
+    pub fn request_staff_account(&mut self, username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> Result<User, DbError> {
+        if role == Role::Patient {
+            return Err(DbError::InvalidInput("patients don't need approval, sign up directly".to_string()));
+        }
+        // admin requests too, the first admin is set up with --create-admin
+        let mut user = self.new_user(username, password, full_name, ssn, age, role);
+        user.status = AccountStatus::PendingApproval;
+        self.add_user(user)
+    }
+
     pub fn authenticate(&mut self, method: String) {
         match method.as_str() {
             "Login" => {
                 println!("Login");
                 let username = get_input_string("Enter your username".to_string());
                 let password = get_input_string("Enter your password".to_string());
-                if self.login(username.clone(), password) {
-                    clear_terminal();
-                    println!("Logged in as: {:?}", username);
-                } else {
-                    println!("Login failed");
+                let mut result = self.login(username.clone(), password.clone(), None);
+                if matches!(result, Err(AuthError::TotpRequired)) {
+                    let code = get_input_string("Enter the code from your authenticator app or a recovery code".to_string());
+                    result = self.login(username.clone(), password, Some(code));
+                }
+                match result {
+                    Ok(()) => {
+                        clear_terminal();
+                        println!("Logged in as: {:?}", username);
+                        let user = self.user().unwrap();
+                        if totp_mandatory(&user.role) && !user.totp_enabled() {
+                            println!("Your role requires two-factor authentication, please set it up now");
+                            if !enroll_totp(self) {
+                                println!("Two-factor authentication was not set up, logging out");
+                                self.logout();
+                            }
+                        }
+                    }
+                    Err(e) => println!("Login failed: {}", e),
                 }
             }
             "Sign Up" => {
                 println!("Sign Up");
+                let options = ["Patient", "Staff (requires admin approval)"];
+                let account_menu = MenuHandler::new("Sign up as:".to_string(), options.into_iter());
+                let is_staff = account_menu.run() != "Patient";
                 loop {
                     let username = get_input_string("Enter a username".to_string());
                     let password = get_input_string("Enter a password".to_string());
@@ -86,25 +368,23 @@ impl<'a> Auth<'a> {
                     let age = get_input_string("Enter your age".to_string());
                     let age: u32 = age.parse().unwrap();
 
-                    let options = ["Patient", "Doctor", "Pharmacist", "TriageSupervisor", "EmergencyDoctor", "Admin"];
-                    let role_menu = MenuHandler::new("Select your role:".to_string(), options.into_iter());
-                    let role = role_menu.run();
-                    let role = match role.as_str() {
-                        "Patient" => Role::Patient,
-                        "Doctor" => Role::Doctor,
-                        "Pharmacist" => Role::Pharmacist,
-                        "TriageSupervisor" => Role::TriageSupervisor,
-                        "EmergencyDoctor" => Role::EmergencyDoctor,
-                        "Admin" => Role::Admin,
-                        _ => panic!("Invalid role"),
-                    };
-                    
-                    match self.signup(username, password, full_name, ssn, age, role) {
-                        Ok(_) => {
-                            println!("Sign up successful");
-                            break;
+                    if is_staff {
+                        let role = select_role("Select your role:".to_string(), true);
+                        match self.request_staff_account(username, password, full_name, ssn, age, role) {
+                            Ok(_) => {
+                                println!("Account requested, an admin has to approve it before you can log in");
+                                break;
+                            }
+                            Err(e) => println!("Sign up failed: {}", e),
+                        }
+                    } else {
+                        match self.signup(username, password, full_name, ssn, age) {
+                            Ok(_) => {
+                                println!("Sign up successful");
+                                break;
+                            }
+                            Err(e) => println!("Sign up failed: {}", e),
                         }
-                        Err(e) => println!("Sign up failed: {}", e),
                     }
                 }
             }
@@ -147,3 +427,66 @@ impl<'a> Auth<'a> {
 
 //     Ok(())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_with_patient(db: &mut Database) -> Auth<'_> {
+        db.users_mut().insert(User::new("ann".to_string(), password_hasher::hash_password("right", 1), String::new(), String::new(), 30, Role::Patient)).unwrap();
+        let mut auth = Auth::new(db);
+        auth.hash_iterations = 1;
+        auth.max_failed_logins = 3;
+        auth
+    }
+
+    fn attempt(auth: &mut Auth, username: &str) -> String {
+        auth.try_login(username.to_string(), "wrong".to_string(), None).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_unknown_users_fail_like_real_ones() {
+        let mut db = Database::new();
+        let mut auth = auth_with_patient(&mut db);
+        auth.backoff_base_secs = 0;
+        for _ in 0..2 {
+            assert_eq!(attempt(&mut auth, "ann"), attempt(&mut auth, "nobody"));
+        }
+        assert_eq!(attempt(&mut auth, "ann"), AuthError::Locked.to_string());
+        assert_eq!(attempt(&mut auth, "nobody"), AuthError::Locked.to_string());
+    }
+
+    #[test]
+    fn test_unknown_users_back_off() {
+        let mut db = Database::new();
+        let mut auth = auth_with_patient(&mut db);
+        attempt(&mut auth, "ann");
+        attempt(&mut auth, "nobody");
+        let backing_off = AuthError::TooManyAttempts { retry_in: auth.backoff_base_secs }.to_string();
+        assert_eq!(attempt(&mut auth, "ann"), backing_off);
+        assert_eq!(attempt(&mut auth, "nobody"), backing_off);
+    }
+
+    #[test]
+    fn test_patients_are_not_staff_requests() {
+        let mut db = Database::new();
+        let mut auth = auth_with_patient(&mut db);
+        let request = auth.request_staff_account("bob".to_string(), "pw".to_string(), String::new(), String::new(), 30, Role::Patient);
+        assert!(matches!(request, Err(DbError::InvalidInput(_))));
+        assert!(!auth.db.users().contains("bob"));
+    }
+
+    #[test]
+    fn test_emergency_doctor_needs_a_grant() {
+        let mut db = Database::new();
+        let mut auth = auth_with_patient(&mut db);
+        let patient = auth.db.users().get("ann").unwrap().clone();
+        let doctor = User::new("er".to_string(), String::new(), String::new(), String::new(), 40, Role::EmergencyDoctor);
+        let now = Utc::now().timestamp();
+        auth.session = Some(Session::new(doctor, now));
+        assert!(!auth.can_view_pii_of(&patient));
+
+        auth.session.as_mut().unwrap().grant_emergency_access("ann".to_string(), now);
+        assert!(auth.can_view_pii_of(&patient));
+    }
+}
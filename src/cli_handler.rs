@@ -1,7 +1,9 @@
 use std::io;
 
 use crate::auth::Auth;
+use crate::db::entities::Role;
 use crate::menus_logic::{
+    MenuError,
     add_drug,
     add_drug_to_gp,
     assign_patients,
@@ -11,6 +13,8 @@ use crate::menus_logic::{
     display_all_drugs,
     make_appointment,
     remove_drug,
+    import_drugs_csv,
+    export_drugs_csv,
     remove_drug_gp,
     search_drugs,
     visit_patients_wrapper,
@@ -25,7 +29,26 @@ use crate::menus_logic::{
     send_ambulance_to_patient,
     list_ambulances,
     print_logs,
+    register_user,
+    delete_user,
+    search_users,
+    list_users,
+    pending_approvals,
+    edit_role_permissions,
+    unlock_users,
+    my_account,
+    rotate_encryption_key,
+    take_snapshot,
+    list_snapshots,
+    diff_snapshots,
+    restore_snapshot,
+    view_audit_log,
+    verify_audit_chain,
+    export_audit_chain_head,
+    emergency_access,
+    review_emergency_accesses,
 };
+use crate::permissions::PermissionError;
 
 
 pub struct MenuHandler<'a, I>
@@ -79,10 +102,45 @@ pub fn get_input_string(query: String) -> String {
     input.trim().to_string()
 }
 
+const ROLES: [&str; 6] = ["Patient", "Doctor", "Pharmacist", "TriageSupervisor", "EmergencyDoctor", "Admin"];
+
+pub fn select_role(query: String, staff_only: bool) -> Role {
+    let options = if staff_only { &ROLES[1..] } else { &ROLES[..] };
+    let role_menu = MenuHandler::new(query, options.iter().copied());
+    match role_menu.run().as_str() {
+        "Patient" => Role::Patient,
+        "Doctor" => Role::Doctor,
+        "Pharmacist" => Role::Pharmacist,
+        "TriageSupervisor" => Role::TriageSupervisor,
+        "EmergencyDoctor" => Role::EmergencyDoctor,
+        "Admin" => Role::Admin,
+        _ => panic!("Invalid role"),
+    }
+}
+
+fn handle_result(result: Result<(), MenuError>) {
+    match result {
+        Ok(()) | Err(MenuError::Permission(PermissionError::SessionExpired)) => {},
+        Err(e) => println!("{}", e),
+    }
+}
+
 pub fn clear_terminal() {
     print!("\x1B[2J\x1B[1;1H");
 }
 
+// the user may have walked away while the menu waited for input, so the session is checked after it
+fn run_session_menu(auth: &mut Auth, options: &[&str]) -> Option<String> {
+    let menu = MenuHandler::new("What would you like to do?".to_string(), options.iter().copied());
+    let selected = menu.run();
+    clear_terminal();
+    if auth.check_session() {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
 // ### menus ###
 
 pub fn main_menu() -> String {
@@ -94,14 +152,15 @@ pub fn main_menu() -> String {
 
 pub fn patient_menu(auth: &mut Auth) {
     let options = ["Make an appointment", "Cancel an appointment", "My Account", "Logout"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Make an appointment" => make_appointment(auth),
-        "Cancel an appointment" => cancel_appointment(auth),
-        "My Account" => println!("My Account"),
+        "Make an appointment" => handle_result(make_appointment(auth)),
+        "Cancel an appointment" => handle_result(cancel_appointment(auth)),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
@@ -109,13 +168,14 @@ pub fn patient_menu(auth: &mut Auth) {
 
 pub fn doctor_menu(auth: &mut Auth) {
     let options = ["Visit Patients", "My Account", "Logout"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Visit Patients" => visit_patients_wrapper(auth),
-        "My Account" => println!("My Account"),
+        "Visit Patients" => handle_result(visit_patients_wrapper(auth)),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
@@ -126,6 +186,8 @@ pub fn pharmacist_menu(auth: &mut Auth) {
         "Dispense patient medications",
         "Add Drug",
         "Remove Drug",
+        "Import Drugs from CSV",
+        "Export Drugs to CSV",
         "Search Drugs",
         "Show Search Complexity",
         "Display All Drugs",
@@ -134,20 +196,23 @@ pub fn pharmacist_menu(auth: &mut Auth) {
         "My Account",
         "Logout"
     ];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Dispense patient medications" => dispense_medications(auth),
-        "Add Drug" => add_drug(auth),
-        "Remove Drug" => remove_drug(auth),
-        "Search Drugs" => search_drugs(auth),
-        "Show Search Complexity" => show_search_complexity(auth),
-        "Display All Drugs" => display_all_drugs(auth),
-        "Display Drug Groups" => display_all_drug_gps(auth),
+        "Dispense patient medications" => handle_result(dispense_medications(auth)),
+        "Add Drug" => handle_result(add_drug(auth)),
+        "Remove Drug" => handle_result(remove_drug(auth)),
+        "Import Drugs from CSV" => handle_result(import_drugs_csv(auth)),
+        "Export Drugs to CSV" => handle_result(export_drugs_csv(auth)),
+        "Search Drugs" => handle_result(search_drugs(auth)),
+        "Show Search Complexity" => handle_result(show_search_complexity(auth)),
+        "Display All Drugs" => handle_result(display_all_drugs(auth)),
+        "Display Drug Groups" => handle_result(display_all_drug_gps(auth)),
         "Drug Groups Management" => drug_groups_menu(auth),
-        "My Account" => println!("My Account"),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
@@ -155,14 +220,15 @@ pub fn pharmacist_menu(auth: &mut Auth) {
 
 pub fn drug_groups_menu(auth: &mut Auth) {
     let options = ["Create Drug Group", "Add Drug to Group", "Remove Drug from Group", "back"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Create Drug Group" => create_drug_gp(auth),
-        "Add Drug to Group" => add_drug_to_gp(auth),
-        "Remove Drug from Group" => remove_drug_gp(auth),
+        "Create Drug Group" => handle_result(create_drug_gp(auth)),
+        "Add Drug to Group" => handle_result(add_drug_to_gp(auth)),
+        "Remove Drug from Group" => handle_result(remove_drug_gp(auth)),
         "back" => pharmacist_menu(auth),
         _ => println!("Invalid option"),
     }
@@ -170,50 +236,94 @@ pub fn drug_groups_menu(auth: &mut Auth) {
 
 pub fn triage_supervisor_menu(auth: &mut Auth) {
     let options = ["Assign patients to doctors", "My Account", "Logout"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Assign patients to doctors" => assign_patients(auth),
-        "My Account" => println!("My Account"),
+        "Assign patients to doctors" => handle_result(assign_patients(auth)),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
 }
 
 pub fn emergency_doctor_menu(auth: &mut Auth) {
-    let options = ["Visit Triage patients", "My Account", "Logout"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let options = ["Visit Triage patients", "Emergency Access", "My Account", "Logout"];
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Visit Triage patients" => visit_patients_wrapper(auth),
-        "My Account" => println!("My Account"),
+        "Visit Triage patients" => handle_result(visit_patients_wrapper(auth)),
+        "Emergency Access" => handle_result(emergency_access(auth)),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
 }
 
 pub fn admin_menu(auth: &mut Auth) {
-    let options = ["Register a new user", "Delete a user", "Search for a user", "View all users", "Map & Ambulances", "My Account", "Logout"];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let options = ["Register a new user", "Delete a user", "Search for a user", "View all users", "Pending Approvals", "Unlock Accounts", "Role Permissions", "Audit Log", "Rotate Encryption Key", "Snapshots", "Map & Ambulances", "My Account", "Logout"];
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Register a new user" => println!("Register a new user"),
-        "Delete a user" => println!("Delete a user"),
-        "Search for a user" => println!("Search for a user"),
-        "View all users" => println!("View all users"),
+        "Register a new user" => handle_result(register_user(auth)),
+        "Delete a user" => handle_result(delete_user(auth)),
+        "Search for a user" => handle_result(search_users(auth)),
+        "View all users" => handle_result(list_users(auth)),
+        "Pending Approvals" => handle_result(pending_approvals(auth)),
+        "Unlock Accounts" => handle_result(unlock_users(auth)),
+        "Role Permissions" => handle_result(edit_role_permissions(auth)),
+        "Audit Log" => audit_menu(auth),
+        "Rotate Encryption Key" => handle_result(rotate_encryption_key(auth)),
+        "Snapshots" => snapshots_menu(auth),
         "Map & Ambulances" => map_ambulances_menu(auth),
-        "My Account" => println!("My Account"),
+        "My Account" => handle_result(my_account(auth)),
         "Logout" => auth.logout(),
         _ => println!("Invalid option"),
     }
 }
 
+fn audit_menu(auth: &mut Auth) {
+    let options = ["View Audit Log", "Emergency Access Review", "Verify Audit Chain", "Export Chain Head", "back"];
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
+
+    match selected.as_str() {
+        "View Audit Log" => handle_result(view_audit_log(auth)),
+        "Emergency Access Review" => handle_result(review_emergency_accesses(auth)),
+        "Verify Audit Chain" => handle_result(verify_audit_chain(auth)),
+        "Export Chain Head" => handle_result(export_audit_chain_head(auth)),
+        "back" => admin_menu(auth),
+        _ => println!("Invalid option"),
+    }
+}
+
+fn snapshots_menu(auth: &mut Auth) {
+    let options = ["Take Snapshot", "List Snapshots", "Diff Snapshots", "Restore Snapshot", "back"];
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
+
+    match selected.as_str() {
+        "Take Snapshot" => handle_result(take_snapshot(auth)),
+        "List Snapshots" => handle_result(list_snapshots(auth)),
+        "Diff Snapshots" => handle_result(diff_snapshots(auth)),
+        "Restore Snapshot" => handle_result(restore_snapshot(auth)),
+        "back" => admin_menu(auth),
+        _ => println!("Invalid option"),
+    }
+}
+
 fn map_ambulances_menu(auth: &mut Auth) {
     let options = [
         "Add Location",
@@ -227,20 +337,21 @@ fn map_ambulances_menu(auth: &mut Auth) {
         "History",
         "back"
     ];
-    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
-    let selected = menu.run();
-    clear_terminal();
+    let selected = match run_session_menu(auth, &options) {
+        Some(selected) => selected,
+        None => return,
+    };
 
     match selected.as_str() {
-        "Add Location" => add_location(auth),
-        "Remove Location" => remove_location(auth),
-        "Print Map" => print_map(auth),
-        "Add Ambulance" => add_ambulance(auth),
-        "Remove Ambulance" => remove_ambulance(auth),
-        "Move Ambulance" => move_ambulance(auth),
-        "List Ambulances" => list_ambulances(auth),
-        "Send Ambulance to Patient" => send_ambulance_to_patient(auth),
-        "History" => print_logs(auth),
+        "Add Location" => handle_result(add_location(auth)),
+        "Remove Location" => handle_result(remove_location(auth)),
+        "Print Map" => handle_result(print_map(auth)),
+        "Add Ambulance" => handle_result(add_ambulance(auth)),
+        "Remove Ambulance" => handle_result(remove_ambulance(auth)),
+        "Move Ambulance" => handle_result(move_ambulance(auth)),
+        "List Ambulances" => handle_result(list_ambulances(auth)),
+        "Send Ambulance to Patient" => handle_result(send_ambulance_to_patient(auth)),
+        "History" => handle_result(print_logs(auth)),
         "back" => admin_menu(auth),
         _ => println!("Invalid option"),
     }
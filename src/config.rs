@@ -0,0 +1,330 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+
+use crate::data_structures::max_heap::DEFAULT_MAX_HEAP_SIZE;
+use crate::db::db_handler::{StorageOptions, BACKUP_COUNT, DB_FILE};
+use crate::db::journal::COMPACT_THRESHOLD;
+use crate::db::snapshot::SNAPSHOT_DIR;
+use crate::password_hasher::DEFAULT_ITERATIONS;
+
+
+// lowest to highest precedence: defaults, config file, HOSPITAL_* environment variables, command line flags
+pub const DEFAULT_CONFIG_FILE: &str = "hospital.conf";
+pub const CONFIG_ENV: &str = "HOSPITAL_CONFIG";
+const ENV_PREFIX: &str = "HOSPITAL_";
+// flags that pick a mode instead of setting a value
+pub const MODE_FLAGS: [&str; 6] = ["--check-format", "--salvage", "--print-config", "--merge", "--fsck", "--repair"];
+// same, but followed by a path
+const MODE_OPTIONS: [&str; 5] = ["config", "export", "import", "create-admin", "unlock"];
+
+pub const KEYS: [&str; 11] = [
+    "data_path",
+    "backup_count",
+    "compact_threshold",
+    "out_of_hospital_penalty",
+    "default_appointment_priority",
+    "max_heap_size",
+    "snapshot_dir",
+    "hash_iterations",
+    "max_failed_logins",
+    "backoff_base_secs",
+    "max_backoff_secs",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String),
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::Flag => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    UnknownKey { key: String, source: Source },
+    InvalidValue { key: String, value: String, source: Source },
+    MissingValue { flag: String },
+    Unreadable { path: String, error: io::Error },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::UnknownKey { key, source } => write!(f, "Unknown setting '{}' ({})", key, source),
+            ConfigError::InvalidValue { key, value, source } => write!(f, "Invalid value '{}' for {} ({})", value, key, source),
+            ConfigError::MissingValue { flag } => write!(f, "{} needs a value", flag),
+            ConfigError::Unreadable { path, error } => write!(f, "Could not read {}: {}", path, error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub data_path: String,
+    pub backup_count: usize,
+    pub compact_threshold: u64,
+    // ambulances based at another hospital look this much further away
+    pub out_of_hospital_penalty: f32,
+    // patients booking their own appointment, lower numbers are seen first
+    pub default_appointment_priority: u32,
+    pub max_heap_size: usize,
+    pub snapshot_dir: String,
+    // for new and rehashed passwords, stored hashes keep the count they were made with
+    pub hash_iterations: u32,
+    // failed logins in a row before an account is locked
+    pub max_failed_logins: u32,
+    // wait after the first failed login, doubling with every further one up to max_backoff_secs
+    pub backoff_base_secs: i64,
+    pub max_backoff_secs: i64,
+    sources: Vec<Source>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_path: DB_FILE.to_string(),
+            backup_count: BACKUP_COUNT,
+            compact_threshold: COMPACT_THRESHOLD,
+            out_of_hospital_penalty: 1.2,
+            default_appointment_priority: 5,
+            max_heap_size: DEFAULT_MAX_HEAP_SIZE,
+            snapshot_dir: SNAPSHOT_DIR.to_string(),
+            hash_iterations: DEFAULT_ITERATIONS,
+            max_failed_logins: 5,
+            backoff_base_secs: 2,
+            max_backoff_secs: 300,
+            sources: vec![Source::Default; KEYS.len()],
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str, source: &Source, valid: impl Fn(&T) -> bool) -> Result<T, ConfigError> {
+    match value.parse::<T>() {
+        Ok(parsed) if valid(&parsed) => Ok(parsed),
+        _ => Err(ConfigError::InvalidValue { key: key.to_string(), value: value.to_string(), source: source.clone() }),
+    }
+}
+
+impl Config {
+    // config file (--config, HOSPITAL_CONFIG or hospital.conf), then the environment, then the flags
+    pub fn load(args: &[String]) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+        let explicit = flag_value(args, "--config")?.or_else(|| std::env::var(CONFIG_ENV).ok());
+        let path = explicit.clone().unwrap_or(DEFAULT_CONFIG_FILE.to_string());
+        match fs::read_to_string(&path) {
+            Ok(contents) => config.apply_file(&contents, &path)?,
+            // only a file that was asked for has to exist
+            Err(e) if e.kind() == ErrorKind::NotFound && explicit.is_none() => {}
+            Err(error) => return Err(ConfigError::Unreadable { path, error }),
+        }
+        config.apply_env(std::env::vars())?;
+        config.apply_args(args)?;
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str, source: Source) -> Result<(), ConfigError> {
+        let value = value.trim();
+        match key {
+            "data_path" if !value.is_empty() => self.data_path = value.to_string(),
+            "backup_count" => self.backup_count = parse(key, value, &source, |_| true)?,
+            "compact_threshold" => self.compact_threshold = parse(key, value, &source, |threshold| *threshold > 0)?,
+            "out_of_hospital_penalty" => self.out_of_hospital_penalty = parse(key, value, &source, |penalty: &f32| penalty.is_finite() && *penalty > 0.0)?,
+            "default_appointment_priority" => self.default_appointment_priority = parse(key, value, &source, |_| true)?,
+            "max_heap_size" => self.max_heap_size = parse(key, value, &source, |size| *size > 0)?,
+            "snapshot_dir" if !value.is_empty() => self.snapshot_dir = value.to_string(),
+            "hash_iterations" => self.hash_iterations = parse(key, value, &source, |iterations| *iterations > 0)?,
+            "max_failed_logins" => self.max_failed_logins = parse(key, value, &source, |attempts| *attempts > 0)?,
+            // 0 turns the back-off off
+            "backoff_base_secs" => self.backoff_base_secs = parse(key, value, &source, |secs| *secs >= 0)?,
+            "max_backoff_secs" => self.max_backoff_secs = parse(key, value, &source, |secs| *secs >= 0)?,
+            "data_path" | "snapshot_dir" => return Err(ConfigError::InvalidValue { key: key.to_string(), value: value.to_string(), source }),
+            _ => return Err(ConfigError::UnknownKey { key: key.to_string(), source }),
+        }
+        let index = KEYS.iter().position(|known| *known == key).unwrap();
+        self.sources[index] = source;
+        Ok(())
+    }
+
+    // key = value per line, # starts a comment
+    pub fn apply_file(&mut self, contents: &str, path: &str) -> Result<(), ConfigError> {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let source = Source::File(path.to_string());
+            match line.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value, source)?,
+                None => return Err(ConfigError::MissingValue { flag: line.to_string() }),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_env<I: Iterator<Item = (String, String)>>(&mut self, vars: I) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) if name != CONFIG_ENV => key.to_lowercase(),
+                _ => continue,
+            };
+            if KEYS.contains(&key.as_str()) {
+                self.set(&key, &value, Source::Env(name.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    // --data-path PATH or --data-path=PATH, the first argument is the program name
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), ConfigError> {
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            if MODE_FLAGS.contains(&arg.as_str()) {
+                continue;
+            }
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => return Err(ConfigError::UnknownKey { key: arg.clone(), source: Source::Flag }),
+            };
+            let (name, inline_value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            let value = match inline_value.or_else(|| args.next().cloned()) {
+                Some(value) => value,
+                None => return Err(ConfigError::MissingValue { flag: arg.clone() }),
+            };
+            if !MODE_OPTIONS.contains(&name) {
+                self.set(&name.replace('-', "_"), &value, Source::Flag)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn storage(&self) -> StorageOptions {
+        StorageOptions {
+            path: self.data_path.clone(),
+            backup_count: self.backup_count,
+            compact_threshold: self.compact_threshold,
+            snapshot_dir: self.snapshot_dir.clone(),
+        }
+    }
+}
+
+pub fn flag_value(args: &[String], flag: &str) -> Result<Option<String>, ConfigError> {
+    let prefix = format!("{}=", flag);
+    for (index, arg) in args.iter().enumerate() {
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Ok(Some(value.to_string()));
+        }
+        if arg == flag {
+            return match args.get(index + 1) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(ConfigError::MissingValue { flag: flag.to_string() }),
+            };
+        }
+    }
+    Ok(None)
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values = [
+            self.data_path.clone(),
+            self.backup_count.to_string(),
+            self.compact_threshold.to_string(),
+            self.out_of_hospital_penalty.to_string(),
+            self.default_appointment_priority.to_string(),
+            self.max_heap_size.to_string(),
+            self.snapshot_dir.clone(),
+            self.hash_iterations.to_string(),
+            self.max_failed_logins.to_string(),
+            self.backoff_base_secs.to_string(),
+            self.max_backoff_secs.to_string(),
+        ];
+        for ((key, value), source) in KEYS.iter().zip(values.iter()).zip(self.sources.iter()) {
+            writeln!(f, "{} = {}  # {}", key, value, source)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut config = Config::default();
+        config.apply_file("# tuned for the night shift\ndata_path = /var/lib/hospital/db.bin\nbackup_count = 5\nmax_heap_size=50 # per doctor\n", "hospital.conf").unwrap();
+        let env = vec![("HOSPITAL_BACKUP_COUNT".to_string(), "7".to_string()), ("PATH".to_string(), "/bin".to_string())];
+        config.apply_env(env.into_iter()).unwrap();
+        config.apply_args(&args(&["hospital", "--salvage", "--max-heap-size", "20", "--out-of-hospital-penalty=1.5", "--hash-iterations", "200000"])).unwrap();
+
+        assert_eq!(config.data_path, "/var/lib/hospital/db.bin");
+        assert_eq!(config.backup_count, 7);
+        assert_eq!(config.max_heap_size, 20);
+        assert_eq!(config.out_of_hospital_penalty, 1.5);
+        assert_eq!(config.default_appointment_priority, 5);
+        assert_eq!(config.hash_iterations, 200_000);
+
+        let printed = config.to_string();
+        assert!(printed.contains("data_path = /var/lib/hospital/db.bin  # file hospital.conf"));
+        assert!(printed.contains("backup_count = 7  # env HOSPITAL_BACKUP_COUNT"));
+        assert!(printed.contains("max_heap_size = 20  # command line"));
+        assert!(printed.contains("default_appointment_priority = 5  # default"));
+    }
+
+    #[test]
+    fn test_rejects_bad_settings() {
+        let mut config = Config::default();
+        assert!(matches!(config.apply_file("colour = blue", "x"), Err(ConfigError::UnknownKey { .. })));
+        assert!(matches!(config.apply_file("max_heap_size = 0", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(config.apply_file("out_of_hospital_penalty = -1", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(config.apply_file("hash_iterations = 0", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(config.apply_args(&args(&["hospital", "--backup-count"])), Err(ConfigError::MissingValue { .. })));
+        assert!(matches!(config.apply_args(&args(&["hospital", "--backup-count", "many"])), Err(ConfigError::InvalidValue { .. })));
+        assert_eq!(config.backup_count, BACKUP_COUNT);
+    }
+
+    #[test]
+    fn test_lockout_settings() {
+        let mut config = Config::default();
+        config.apply_file("max_failed_logins = 3\nbackoff_base_secs = 0\n", "hospital.conf").unwrap();
+        config.apply_env(vec![("HOSPITAL_MAX_BACKOFF_SECS".to_string(), "60".to_string())].into_iter()).unwrap();
+        config.apply_args(&args(&["hospital", "--max-failed-logins=4"])).unwrap();
+        assert_eq!(config.max_failed_logins, 4);
+        assert_eq!(config.backoff_base_secs, 0);
+        assert_eq!(config.max_backoff_secs, 60);
+        assert!(config.to_string().contains("max_backoff_secs = 60  # env HOSPITAL_MAX_BACKOFF_SECS"));
+
+        assert!(matches!(config.apply_file("max_failed_logins = 0", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(config.apply_file("backoff_base_secs = -2", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(config.apply_file("max_backoff_secs = soon", "x"), Err(ConfigError::InvalidValue { .. })));
+        assert_eq!(config.max_failed_logins, 4);
+    }
+
+    #[test]
+    fn test_config_flag_is_read_up_front() {
+        let arguments = args(&["hospital", "--config", "night.conf", "--check-format", "--import=dump.json", "--merge"]);
+        assert_eq!(flag_value(&arguments, "--config").unwrap(), Some("night.conf".to_string()));
+        let mut config = Config::default();
+        config.apply_args(&arguments).unwrap();
+        assert_eq!(config.data_path, DB_FILE);
+    }
+}
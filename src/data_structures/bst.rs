@@ -71,6 +71,25 @@ impl<T: Ord + Debug + Clone> TreeNode<T> {
         }
     }
 
+    pub fn get_by_uniq_attr_mut(&mut self, uniq_attr: String) -> Option<&mut T>
+    where
+        T: UniqueAttribute,
+    {
+        if self.value.uattr() == uniq_attr {
+            Some(&mut self.value)
+        } else if uniq_attr < self.value.uattr() {
+            match self.left {
+                Some(ref mut left_child) => left_child.get_by_uniq_attr_mut(uniq_attr),
+                None => None,
+            }
+        } else {
+            match self.right {
+                Some(ref mut right_child) => right_child.get_by_uniq_attr_mut(uniq_attr),
+                None => None,
+            }
+        }
+    }
+
     pub fn max(&self) -> T
     where
         T: Clone,
@@ -93,19 +112,61 @@ impl<T: Ord + Debug + Clone> TreeNode<T> {
         BstIterator::new(Some(self))
     }
 
-    fn extract_min_value(node: &mut TreeNode<T>) -> Option<T> {
-        let mut current = node;
-        while let Some(ref mut left) = current.left {
-            current = left;
+    // in-order, the closure must not change the ordering of the values
+    pub fn for_each_mut<F: FnMut(&mut T)>(&mut self, f: &mut F) {
+        if let Some(ref mut left_child) = self.left {
+            left_child.for_each_mut(f);
         }
-        
-        // Now current points to the node with minimum value
-        if let Some(min_node) = current.left.take() {
-            // Replace current node with its right child (if any)
-            current.left = min_node.right;
-            Some(min_node.value)
+        f(&mut self.value);
+        if let Some(ref mut right_child) = self.right {
+            right_child.for_each_mut(f);
+        }
+    }
+
+    // Detaches the minimum node of the given subtree, returning its value and what is left of the subtree
+    fn extract_min_value(mut node: Box<TreeNode<T>>) -> (T, Option<Box<TreeNode<T>>>) {
+        match node.left.take() {
+            Some(left) => {
+                let (min_value, rest) = Self::extract_min_value(left);
+                node.left = rest;
+                (min_value, Some(node))
+            }
+            None => (node.value, node.right),
+        }
+    }
+
+    // Removes the given node and reattaches its children
+    fn remove_root(mut node: Box<TreeNode<T>>) -> Option<Box<TreeNode<T>>> {
+        match (node.left.take(), node.right.take()) {
+            // No children or only right child
+            (None, right) => right,
+            // Only left child
+            (left, None) => left,
+            // Both children present
+            (left, Some(right)) => {
+                let (successor_value, rest) = Self::extract_min_value(right);
+                node.value = successor_value;
+                node.left = left;
+                node.right = rest;
+                Some(node)
+            }
+        }
+    }
+
+    pub fn remove_by_uniq_attr(root: Option<Box<TreeNode<T>>>, uniq_attr: String) -> Option<Box<TreeNode<T>>>
+    where
+        T: UniqueAttribute,
+    {
+        let mut node = root?;
+        let attr = node.value.uattr();
+        if uniq_attr < attr {
+            node.left = Self::remove_by_uniq_attr(node.left.take(), uniq_attr);
+            Some(node)
+        } else if uniq_attr > attr {
+            node.right = Self::remove_by_uniq_attr(node.right.take(), uniq_attr);
+            Some(node)
         } else {
-            None
+            Self::remove_root(node)
         }
     }
 
@@ -250,24 +311,6 @@ impl TreeNode<Drug> {
         None
     }
 
-    pub fn get_drug_by_name_mut(&mut self, name: String) -> Option<&mut Drug> {
-        let mut queue = LinkedList::new();
-        queue.push_front(self);
-
-        while let Some(node) = queue.remove_last_node() {
-            if node.value.name == name {
-                return Some(&mut node.value);
-            }
-            if let Some(ref mut left_child) = node.left {
-                queue.push_front(left_child);
-            }
-            if let Some(ref mut right_child) = node.right {
-                queue.push_front(right_child);
-            }
-        }
-        None
-    }
-
     pub fn remove_drug_by_id(root: Option<Box<TreeNode<Drug>>>, id: u32) -> Option<Box<TreeNode<Drug>>> {
         if let Some(mut node) = root {
             if id < node.value.id {
@@ -280,25 +323,7 @@ impl TreeNode<Drug> {
                 Some(node)
             } else {
                 // This is the node to delete
-                match (node.left.take(), node.right.take()) {
-                    // No children or only right child
-                    (None, right) => right,
-                    // Only left child
-                    (left, None) => left,
-                    // Both children present
-                    (left, right) => {
-                        if let Some(mut right_tree) = right {
-                            if let Some(successor_value) = Self::extract_min_value(&mut right_tree) {
-                                node.value = successor_value;
-                                node.right = Some(right_tree);
-                                node.left = left;
-                                return Some(node);
-                            }
-                        }
-                        // This case should never happen if the tree is valid
-                        left
-                    }
-                }
+                Self::remove_root(node)
             }
         } else {
             None
@@ -393,4 +418,45 @@ mod tests {
             assert!(root.get_drug_by_name("Aspirin".to_string()).is_none());
         }
     }
+
+    #[test]
+    fn test_remove_drug_with_two_children() {
+        let mut root = TreeNode::new(Drug::new(5, "Aspirin".to_string(), 100.0, 5));
+        root.insert(Drug::new(2, "Paracetamol".to_string(), 200.0, 10));
+        root.insert(Drug::new(8, "Ibuprofen".to_string(), 150.0, 20));
+        root.insert(Drug::new(7, "Amoxicillin".to_string(), 50.0, 30));
+        root.insert(Drug::new(9, "Azithromycin".to_string(), 70.0, 40));
+
+        let root = TreeNode::remove_drug_by_id(Some(Box::new(root)), 5).unwrap();
+        let ids: Vec<_> = root.iter().map(|drug| drug.id).collect();
+        assert_eq!(ids, vec![2, 7, 8, 9]);
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Named(String);
+
+    impl UniqueAttribute for Named {
+        fn uattr(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_remove_by_uniq_attr() {
+        let mut root = TreeNode::new(Named("m".to_string()));
+        for name in ["c", "t", "a", "e", "p", "z", "d"] {
+            root.insert(Named(name.to_string()));
+        }
+
+        let mut wrapper = Some(Box::new(root));
+        for name in ["m", "c", "z", "missing"] {
+            wrapper = TreeNode::remove_by_uniq_attr(wrapper, name.to_string());
+        }
+
+        let root = wrapper.unwrap();
+        let names: Vec<_> = root.iter().map(|named| named.0.as_str()).collect();
+        assert_eq!(names, vec!["a", "d", "e", "p", "t"]);
+        assert!(root.get_by_uniq_attr("m".to_string()).is_none());
+        assert!(root.get_by_uniq_attr("p".to_string()).is_some());
+    }
 }
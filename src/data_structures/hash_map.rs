@@ -5,8 +5,10 @@ use std::borrow::Borrow;
 use serde::{Deserialize, Serialize};
 
 const INITIAL_CAPACITY: usize = 16;
+// buckets are doubled once there are more entries than this many per bucket on average
+const MAX_LOAD: f64 = 0.75;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HashMap<K: Clone, V: Clone> {
     buckets: Vec<Vec<(K, V)>>,
     size: usize,
@@ -40,6 +42,19 @@ impl<K: Eq + Hash + Clone, V: Clone> HashMap<K, V> {
         }
         self.buckets[index].push((key, value));
         self.size += 1;
+        if self.size as f64 > self.buckets.len() as f64 * MAX_LOAD {
+            self.grow();
+        }
+    }
+
+    // keeps the buckets short so lookups stay constant time on average
+    fn grow(&mut self) {
+        let capacity = self.buckets.len() * 2;
+        let old = std::mem::replace(&mut self.buckets, vec![Vec::new(); capacity]);
+        for (key, value) in old.into_iter().flatten() {
+            let index = self.hash(&key);
+            self.buckets[index].push((key, value));
+        }
     }
 
     pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
@@ -190,4 +205,15 @@ mod tests {
         map.insert("key1", "value3");
         assert_eq!(map.get(&"key1"), Some(&"value3"));
     }
+
+    #[test]
+    fn test_grows() {
+        let mut map = HashMap::new();
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.len(), 1000);
+        assert!(map.buckets.len() >= 1000);
+        assert!((0..1000).all(|i| map.get(&i) == Some(&(i * 2))));
+    }
 }
\ No newline at end of file
@@ -48,7 +48,7 @@ impl PartialOrd for State {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Graph {
     pub nodes: HashMap<String, Node>,
     pub edges: HashMap<String, LinkedList<String>>,
@@ -81,9 +81,8 @@ impl Graph {
         self.nodes.remove(&id);
         self.edges.remove(&id);
         for edges in self.edges.values_mut() {
-            edges.remove(&id);
+            while edges.remove(&id) {}
         }
-
     }
 
     pub fn add_object_to_node(&mut self, node_id: &str, object: Object) {
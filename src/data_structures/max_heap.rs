@@ -1,5 +1,5 @@
 use std::fmt::Debug;
-use std::iter::repeat;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use serde::ser::SerializeSeq;
@@ -8,11 +8,22 @@ use std::fmt;
 
 use crate::db::entities::UniqueAttribute;
 
-const MAX_HEAP_SIZE: usize = 100;
+pub const DEFAULT_MAX_HEAP_SIZE: usize = 100;
+
+// set once at startup from the configuration, applies to every heap
+static MAX_HEAP_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_HEAP_SIZE);
+
+pub fn set_max_heap_size(size: usize) {
+    MAX_HEAP_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn max_heap_size() -> usize {
+    MAX_HEAP_SIZE.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone)]
 pub struct MaxHeap<T: Ord + Clone> {
-    pub data: [Option<T>; MAX_HEAP_SIZE],
+    pub data: Vec<Option<T>>,   // Always exactly `size` long
     pub size: usize,            // Current size of the heap
 }
 
@@ -20,34 +31,46 @@ impl<T: Ord + Clone + Debug> MaxHeap<T> {
     // Create a new empty heap
     pub fn new() -> Self {
         Self {
-            data: repeat(None).take(MAX_HEAP_SIZE).collect::<Vec<_>>().try_into().unwrap(),
+            data: Vec::new(),
             size: 0,
         }
     }
 
+    pub fn is_full(&self) -> bool {
+        self.size >= max_heap_size()
+    }
+
     pub fn push(&mut self, value: T) {
-        if self.size >= self.data.len() {
-            panic!("Heap overflow!"); // No dynamic resizing, fixed capacity
+        if self.is_full() {
+            panic!("Heap overflow!"); // Callers check is_full first
         }
-        self.data[self.size] = Some(value);
+        self.data.push(Some(value));
         self.size += 1;
         self.bubble_up(self.size - 1);
     }
 
     pub fn pop(&mut self) -> Option<T> {
-        if self.size == 0 {
+        self.remove_at(0)
+    }
+
+    // Remove the element at `index`, filling the gap with the last element
+    pub fn remove_at(&mut self, index: usize) -> Option<T> {
+        if index >= self.size {
             return None;
         }
-        let max_value = self.data[0].clone();
-        self.data[0] = self.data[self.size - 1].clone(); // Move the last element to the root
-        self.data[self.size - 1] = None;
+        let last = self.data.pop().unwrap();
         self.size -= 1;
-        self.bubble_down(0);
-        max_value
+        if index == self.size {
+            return last;
+        }
+        let removed = std::mem::replace(&mut self.data[index], last);
+        self.bubble_up(index);
+        self.bubble_down(index);
+        removed
     }
 
     pub fn peek(&self) -> Option<&T> {
-        self.data[0].as_ref()
+        self.data.first().and_then(|value| value.as_ref())
     }
 
     // Helper function to maintain the heap property after insertion
@@ -124,16 +147,20 @@ impl<'de, T: Ord + Clone + Deserialize<'de> + Debug> Deserialize<'de> for MaxHea
             }
 
             fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
-                let mut data: [Option<T>; 100] = repeat(None).take(MAX_HEAP_SIZE).collect::<Vec<_>>().try_into().unwrap();
-                let mut size = 0;
-
-                while let Some(value) = seq.next_element()? {
-                    if size < MAX_HEAP_SIZE {
-                        data[size] = value;
-                        size += 1;
+                // everything stored is kept, a lower limit only stops new pushes. files from before removals
+                // shrank the heap have empty slots where cancelled entries were, those are dropped
+                let mut data = Vec::new();
+                while let Some(value) = seq.next_element::<Option<T>>()? {
+                    if value.is_some() {
+                        data.push(value);
                     }
                 }
-                Ok(MaxHeap { data, size })
+                let size = data.len();
+                let mut heap = MaxHeap { data, size };
+                for index in (0..size / 2).rev() {
+                    heap.bubble_down(index);
+                }
+                Ok(heap)
             }
         }
 
@@ -40,6 +40,19 @@ impl<T: Ord + Clone + Debug> PriorityQueue<T> {
         self.heap.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.heap.size
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.heap.is_full()
+    }
+
+    // items in heap order, not priority order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.heap.data[..self.heap.size].iter().filter_map(|item| item.as_ref().map(|Reverse(item)| item))
+    }
+
     pub fn get_by_uniq_attr(&mut self, uniq_attr: String) -> Option<&mut T>
     where 
         T: UniqueAttribute,
@@ -58,7 +71,7 @@ impl<T: Ord + Clone + Debug> PriorityQueue<T> {
     {
         for i in 0..self.heap.size {
             if self.heap.data[i].as_mut().unwrap().0.uattr() == uniq_attr {
-                self.heap.data[i] = None;
+                self.heap.remove_at(i);
                 return true;
             }
         }
@@ -81,3 +94,45 @@ impl<T: Ord + Clone + Debug> PriorityQueue<T> {
 //     }
 // }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Item(u32, String);
+
+    impl UniqueAttribute for Item {
+        fn uattr(&self) -> String {
+            self.1.clone()
+        }
+    }
+
+    #[test]
+    fn test_pop_order() {
+        let mut pq = PriorityQueue::new();
+        for (priority, name) in [(5, "a"), (1, "b"), (3, "c")] {
+            pq.push(Item(priority, name.to_string()));
+        }
+        assert_eq!(pq.pop().unwrap().1, "b");
+        assert_eq!(pq.pop().unwrap().1, "c");
+        assert_eq!(pq.pop().unwrap().1, "a");
+        assert!(pq.is_empty());
+    }
+
+    #[test]
+    fn test_remove_by_uniq_attr() {
+        let mut pq = PriorityQueue::new();
+        for (priority, name) in [(5, "a"), (1, "b"), (3, "c"), (4, "d"), (2, "e")] {
+            pq.push(Item(priority, name.to_string()));
+        }
+        assert!(pq.remove_by_uniq_attr("b".to_string()));
+        assert!(!pq.remove_by_uniq_attr("b".to_string()));
+        assert!(pq.get_by_uniq_attr("c".to_string()).is_some());
+
+        let mut order = Vec::new();
+        while let Some(item) = pq.pop() {
+            order.push(item.1);
+        }
+        assert_eq!(order, vec!["e", "c", "d", "a"]);
+    }
+}
@@ -48,6 +48,26 @@ impl<T> Stack<T> {
     pub fn is_empty(&self) -> bool {
         self.top.is_none()
     }
+
+    // Iterate from the top of the Stack down without popping
+    pub fn iter(&self) -> StackIter<'_, T> {
+        StackIter { next: self.top.as_deref() }
+    }
+}
+
+pub struct StackIter<'a, T> {
+    next: Option<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for StackIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next.map(|node| {
+            self.next = node.next.as_deref();
+            &node.value
+        })
+    }
 }
 
 // fn main() {
@@ -0,0 +1,339 @@
+use std::fmt;
+use chrono::{Local, TimeZone};
+use serde::{Serialize, Deserialize};
+
+use crate::db::entities::{Role, UniqueAttribute};
+use crate::sha_hasher::Sha256;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    Logout,
+    SessionExpired,
+    Create,
+    Update,
+    Delete,
+    Approve,
+    Reject,
+    Unlock,
+    Dispense,
+    Move,
+    KeyRotation,
+    EmergencyAccess,
+    Export,
+    Import,
+    Restore,
+}
+
+pub const ALL_ACTIONS: [AuditAction; 17] = [
+    AuditAction::Login,
+    AuditAction::LoginFailed,
+    AuditAction::Logout,
+    AuditAction::SessionExpired,
+    AuditAction::Create,
+    AuditAction::Update,
+    AuditAction::Delete,
+    AuditAction::Approve,
+    AuditAction::Reject,
+    AuditAction::Unlock,
+    AuditAction::Dispense,
+    AuditAction::Move,
+    AuditAction::KeyRotation,
+    AuditAction::EmergencyAccess,
+    AuditAction::Export,
+    AuditAction::Import,
+    AuditAction::Restore,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EntityType {
+    User,
+    Appointment,
+    Prescription,
+    Drug,
+    DrugGroup,
+    Location,
+    Route,
+    Ambulance,
+    RolePermissions,
+    EncryptionKey,
+    Database,
+    Snapshot,
+}
+
+pub const ALL_ENTITY_TYPES: [EntityType; 12] = [
+    EntityType::User,
+    EntityType::Appointment,
+    EntityType::Prescription,
+    EntityType::Drug,
+    EntityType::DrugGroup,
+    EntityType::Location,
+    EntityType::Route,
+    EntityType::Ambulance,
+    EntityType::RolePermissions,
+    EntityType::EncryptionKey,
+    EntityType::Database,
+    EntityType::Snapshot,
+];
+
+// prev_hash of the very first event
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// before/after never hold encrypted user fields, the log itself is stored in plaintext
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub id: u64,
+    pub timestamp: i64,
+    pub actor: String,
+    pub actor_role: Option<Role>,
+    pub action: AuditAction,
+    pub entity_type: EntityType,
+    pub entity_key: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl UniqueAttribute for AuditEvent {
+    fn uattr(&self) -> String {
+        self.id.to_string()
+    }
+}
+
+impl AuditEvent {
+    // id and timestamp are filled in by Database::record_event
+    pub fn new(actor: String, actor_role: Option<Role>, action: AuditAction, entity_type: EntityType, entity_key: String) -> Self {
+        AuditEvent {
+            id: 0,
+            timestamp: 0,
+            actor,
+            actor_role,
+            action,
+            entity_type,
+            entity_key,
+            before: None,
+            after: None,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    // sha256 over everything but the hash itself, prev_hash included so entries can't be reordered
+    pub fn content_hash(&self) -> String {
+        let content = (
+            self.id,
+            self.timestamp,
+            &self.actor,
+            &self.actor_role,
+            self.action,
+            self.entity_type,
+            &self.entity_key,
+            &self.before,
+            &self.after,
+            &self.prev_hash,
+        );
+        let mut hasher = Sha256::new();
+        hasher.update(&bincode::serialize(&content).unwrap());
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn link(&mut self, prev_hash: String) {
+        self.prev_hash = prev_hash;
+        self.hash = self.content_hash();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainHead {
+    pub id: u64,
+    pub hash: String,
+    pub length: usize,
+}
+
+impl fmt::Display for ChainHead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.id, self.hash, self.length)
+    }
+}
+
+impl ChainHead {
+    // parses what Display writes, which is also the export format
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+        let id = parts.next()?.parse().ok()?;
+        let hash = parts.next()?.to_string();
+        let length = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || hash.len() != GENESIS_HASH.len() {
+            return None;
+        }
+        Some(ChainHead { id, hash, length })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    ContentModified { id: u64 },
+    BrokenLink { id: u64 },
+    // an exported head no longer matches, i.e. history was rewritten or truncated
+    HeadMismatch { id: u64 },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::ContentModified { id } => write!(f, "Event #{} was modified after it was recorded", id),
+            ChainError::BrokenLink { id } => write!(f, "Event #{} does not follow the event before it, entries were removed or reordered", id),
+            ChainError::HeadMismatch { id } => write!(f, "Event #{} no longer matches the exported chain head", id),
+        }
+    }
+}
+
+// events oldest first, returns the current head or the first broken link
+pub fn verify_chain<'a, I>(events: I) -> Result<Option<ChainHead>, ChainError>
+where
+    I: Iterator<Item = &'a AuditEvent>,
+{
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut head = None;
+    for (index, event) in events.enumerate() {
+        if event.prev_hash != prev_hash {
+            return Err(ChainError::BrokenLink { id: event.id });
+        }
+        if event.content_hash() != event.hash {
+            return Err(ChainError::ContentModified { id: event.id });
+        }
+        prev_hash = event.hash.clone();
+        head = Some(ChainHead { id: event.id, hash: event.hash.clone(), length: index + 1 });
+    }
+    Ok(head)
+}
+
+// an earlier head must still be part of the chain, at the same position
+pub fn check_head<'a, I>(events: I, exported: &ChainHead) -> Result<(), ChainError>
+where
+    I: Iterator<Item = &'a AuditEvent>,
+{
+    let mut events = events.skip(exported.length.saturating_sub(1));
+    match events.next() {
+        Some(event) if exported.length > 0 && event.id == exported.id && event.hash == exported.hash => Ok(()),
+        _ => Err(ChainError::HeadMismatch { id: exported.id }),
+    }
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = Local.timestamp_opt(self.timestamp, 0).single().map_or(self.timestamp.to_string(), |time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+        let role = self.actor_role.as_ref().map_or(String::new(), |role| format!(" ({:?})", role));
+        write!(f, "#{} {} {}{} {:?} {:?} '{}'", self.id, time, self.actor, role, self.action, self.entity_type, self.entity_key)?;
+        match (&self.before, &self.after) {
+            (Some(before), Some(after)) => write!(f, ": {} -> {}", before, after),
+            (Some(before), None) => write!(f, ": was {}", before),
+            (None, Some(after)) => write!(f, ": {}", after),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditFilter {
+    All,
+    Actor(String),
+    Action(AuditAction),
+    EntityType(EntityType),
+    EntityKey(String),
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        match self {
+            AuditFilter::All => true,
+            AuditFilter::Actor(actor) => event.actor == *actor,
+            AuditFilter::Action(action) => event.action == *action,
+            AuditFilter::EntityType(entity_type) => event.entity_type == *entity_type,
+            AuditFilter::EntityKey(key) => event.entity_key == *key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(length: u64) -> Vec<AuditEvent> {
+        let mut events: Vec<AuditEvent> = Vec::new();
+        for id in 1..=length {
+            let mut event = AuditEvent::new("admin".to_string(), Some(Role::Admin), AuditAction::Create, EntityType::Drug, format!("drug{}", id));
+            event.id = id;
+            event.timestamp = 1_700_000_000 + id as i64;
+            let prev_hash = events.last().map_or(GENESIS_HASH.to_string(), |event| event.hash.clone());
+            event.link(prev_hash);
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        assert_eq!(verify_chain(chain(0).iter()), Ok(None));
+        let events = chain(5);
+        let head = verify_chain(events.iter()).unwrap().unwrap();
+        assert_eq!(head, ChainHead { id: 5, hash: events[4].hash.clone(), length: 5 });
+        assert_eq!(ChainHead::parse(&head.to_string()), Some(head));
+    }
+
+    #[test]
+    fn test_detects_first_broken_link() {
+        let mut events = chain(5);
+        events[2].after = Some("quantity: 1000".to_string());
+        events[3].entity_key = "forged".to_string();
+        assert_eq!(verify_chain(events.iter()), Err(ChainError::ContentModified { id: 3 }));
+
+        // recomputing the hash of an edited entry breaks the next link instead
+        let mut events = chain(5);
+        events[2].after = Some("quantity: 1000".to_string());
+        events[2].hash = events[2].content_hash();
+        assert_eq!(verify_chain(events.iter()), Err(ChainError::BrokenLink { id: 4 }));
+
+        let mut events = chain(5);
+        events.remove(1);
+        assert_eq!(verify_chain(events.iter()), Err(ChainError::BrokenLink { id: 3 }));
+    }
+
+    #[test]
+    fn test_check_head() {
+        let events = chain(5);
+        let head = verify_chain(events[..3].iter()).unwrap().unwrap();
+        assert_eq!(check_head(events.iter(), &head), Ok(()));
+
+        // rebuilding the whole chain after an edit is caught by the exported head
+        let mut rewritten = chain(5);
+        rewritten[0].actor = "someone".to_string();
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for event in rewritten.iter_mut() {
+            event.link(prev_hash);
+            prev_hash = event.hash.clone();
+        }
+        assert!(verify_chain(rewritten.iter()).is_ok());
+        assert_eq!(check_head(rewritten.iter(), &head), Err(ChainError::HeadMismatch { id: 3 }));
+        assert_eq!(check_head(events[..2].iter(), &head), Err(ChainError::HeadMismatch { id: 3 }));
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let mut event = AuditEvent::new("pharm".to_string(), Some(Role::Pharmacist), AuditAction::Update, EntityType::Drug, "Aspirin".to_string());
+        event.before = Some("quantity: 50".to_string());
+        event.after = Some("quantity: 40".to_string());
+
+        assert!(AuditFilter::All.matches(&event));
+        assert!(AuditFilter::Actor("pharm".to_string()).matches(&event));
+        assert!(!AuditFilter::Actor("admin".to_string()).matches(&event));
+        assert!(AuditFilter::Action(AuditAction::Update).matches(&event));
+        assert!(!AuditFilter::Action(AuditAction::Delete).matches(&event));
+        assert!(AuditFilter::EntityType(EntityType::Drug).matches(&event));
+        assert!(AuditFilter::EntityKey("Aspirin".to_string()).matches(&event));
+        assert!(event.to_string().ends_with("pharm (Pharmacist) Update Drug 'Aspirin': quantity: 50 -> quantity: 40"));
+    }
+}
@@ -1,266 +1,1119 @@
-use std::fs::File;
-use std::io::{self, Write, Read, Error, ErrorKind};
+use std::fs::{self, File};
+use std::path::Path;
+use std::io::{self, Write, Read};
 use bincode;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use bincode::Options;
 use std::fmt::Debug;
 
 use crate::data_structures::map::Graph;
-use crate::db::entities::User;
+use crate::db::audit::{self, AuditEvent, AuditFilter, ChainError, ChainHead, GENESIS_HASH};
+use crate::db::entities::{UniqueAttribute, User};
+use crate::db::error::DbError;
+use crate::db::format::{self, FORMAT_VERSION};
+use crate::db::index::{Indexed, Savepoints, SecondaryIndexes};
+use crate::db::journal::{self, JournalOp, Section};
+use crate::db::relations::{self, Relation};
+use crate::db::repository::Repository;
+use crate::db::snapshot::{self, SnapshotInfo};
 use crate::data_structures::bst::TreeNode;
 use crate::data_structures::linked_list::LinkedList;
-use crate::data_structures::hash_map::HashMap;
+use crate::field_cipher::{self, FieldKey};
+use crate::permissions::{self, Permission, RolePermissions};
 
-use super::entities::{Clinic, DoctorsList, Prescription, Drug, DrugGP, Ambulance};
+use super::entities::{Clinic, DoctorsList, Prescription, Drug, DrugGP, Ambulance, Role};
 use chrono::Local;
 
 
-#[derive(Serialize, Deserialize, Debug)]
+pub const DB_FILE: &str = "database.bin";
+// previous versions kept as database.bin.1 (newest) up to database.bin.<BACKUP_COUNT>
+pub const BACKUP_COUNT: usize = 3;
+
+pub fn backup_path(filename: &str, index: usize) -> String {
+    format!("{}.{}", filename, index)
+}
+
+fn rotate_backups(filename: &str, count: usize) -> io::Result<()> {
+    if count == 0 || !Path::new(filename).exists() {
+        return Ok(());
+    }
+    for index in (1..count).rev() {
+        let from = backup_path(filename, index);
+        if Path::new(&from).exists() {
+            fs::rename(&from, backup_path(filename, index + 1))?;
+        }
+    }
+    // copy rather than move, the original has to stay in place until the new file replaces it
+    fs::copy(filename, backup_path(filename, 1))?;
+    Ok(())
+}
+
+// makes the rename itself durable, not every platform allows opening a directory so errors are ignored
+fn sync_parent_dir(filename: &str) {
+    let parent = match Path::new(filename).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
+// where and how the database is written, set from the config at startup
+#[derive(Debug, Clone)]
+pub struct StorageOptions {
+    pub path: String,
+    pub backup_count: usize,
+    pub compact_threshold: u64,
+    pub snapshot_dir: String,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            path: DB_FILE.to_string(),
+            backup_count: BACKUP_COUNT,
+            compact_threshold: journal::COMPACT_THRESHOLD,
+            snapshot_dir: snapshot::SNAPSHOT_DIR.to_string(),
+        }
+    }
+}
+
+fn decode_section<T: DeserializeOwned>(section: Section, bytes: &[u8]) -> Result<T, DbError> {
+    bincode::deserialize(bytes).map_err(|e| DbError::Corrupt(format!("journaled {:?} could not be read: {}", section, e)))
+}
+
+// what changed in a section that is journaled a record at a time
+fn record_changes<S: Repository<T>, T: SecondaryIndexes + Serialize>(section: Section, records: &Indexed<S, T>, ops: &mut Vec<JournalOp>) {
+    for key in records.changed_keys() {
+        ops.push(match records.get(&key) {
+            Some(record) => JournalOp::PutRecord(section, bincode::serialize(record).unwrap()),
+            None => JournalOp::DeleteRecord(section, key),
+        });
+    }
+}
+
+fn put_record<T: UniqueAttribute + DeserializeOwned>(section: Section, records: &mut impl Repository<T>, bytes: &[u8]) -> Result<(), DbError> {
+    let record: T = decode_section(section, bytes)?;
+    if records.contains(&record.uattr()) {
+        records.update(record)?;
+    } else {
+        records.store(record);
+    }
+    Ok(())
+}
+
+fn only_whole(section: Section) -> DbError {
+    DbError::Corrupt(format!("journaled {:?} can only be set whole", section))
+}
+
+// Database::transaction for whatever holds the database, so the steps can get at the rest of it too
+pub fn transaction<O, T, E: From<DbError>>(owner: &mut O, db: impl Fn(&mut O) -> &mut Database, steps: impl FnOnce(&mut O) -> Result<T, E>) -> Result<T, E> {
+    db(owner).begin();
+    match steps(owner) {
+        Ok(value) => {
+            db(owner).finish()?;
+            Ok(value)
+        },
+        Err(e) => {
+            db(owner).rollback();
+            Err(e)
+        },
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Database {
-    pub users_data: Option<TreeNode<User>>,
-    pub clinics_data: Option<LinkedList<Clinic>>,
-    pub doctors_data: Option<LinkedList<DoctorsList>>,
-    pub prescriptions_data: Option<LinkedList<Prescription>>,
-    pub drugs_data: Option<Box<TreeNode<Drug>>>,
-    pub drug_gps: Option<LinkedList<DrugGP>>,
-    pub map: Graph,
-    pub ambulances_data: Option<LinkedList<Ambulance>>,
-    pub logs_data: HashMap<String, String>,
+    pub users_data: Indexed<Option<TreeNode<User>>, User>,
+    pub clinics_data: Indexed<Option<LinkedList<Clinic>>, Clinic>,
+    pub doctors_data: Indexed<Option<TreeNode<DoctorsList>>, DoctorsList>,
+    pub prescriptions_data: Indexed<Option<LinkedList<Prescription>>, Prescription>,
+    pub drugs_data: Indexed<Option<Box<TreeNode<Drug>>>, Drug>,
+    pub drug_gps: Indexed<Option<LinkedList<DrugGP>>, DrugGP>,
+    map: Graph,
+    pub ambulances_data: Indexed<Option<LinkedList<Ambulance>>, Ambulance>,
+    pub audit_log: LinkedList<AuditEvent>,
+    pub next_audit_id: u64,
+    pub role_permissions: Indexed<Option<LinkedList<RolePermissions>>, RolePermissions>,
+    // the permissions this file's role permissions were stored next to, newer ones get their defaults on load
+    pub known_permissions: LinkedList<Permission>,
+    pub field_key_salt: Vec<u8>,
+    pub field_key_check: String,
+    // the journal only counts when its header carries the same generation as the snapshot
+    pub journal_generation: u64,
+    #[serde(skip)]
+    field_key: Option<FieldKey>,
+    // the sections journaled whole that changed since the last commit, the keyed ones keep track themselves
+    #[serde(skip)]
+    changed_sections: Vec<Section>,
+    #[serde(skip)]
+    persisted_audit_id: u64,
+    #[serde(skip)]
+    journal_len: u64,
+    // format version of the file this was loaded from when it had to be migrated
+    #[serde(skip)]
+    migrated_from: Option<u32>,
+    #[serde(skip)]
+    storage: StorageOptions,
+    // opened by begin(), innermost last, commits are held back while any are open
+    #[serde(skip)]
+    savepoints: Vec<Savepoint>,
+}
+
+// what begin() needs to undo whatever isn't kept in a collection, those log their own changes. the sections
+// journaled whole are copied the first time they change
+#[derive(Debug, Clone)]
+struct Savepoint {
+    audit_len: usize,
+    next_audit_id: u64,
+    changed_sections: Vec<Section>,
+    map: Option<Graph>,
+    field_key: Option<(Vec<u8>, String, Option<FieldKey>)>,
+    known_permissions: Option<LinkedList<Permission>>,
 }
 
 impl Database {
     pub fn new() -> Self {
         Database {
-            users_data: None,
-            clinics_data: None,
-            doctors_data: None,
-            prescriptions_data: None,
-            drugs_data: None,
-            drug_gps: None,
+            users_data: Indexed::new(None),
+            clinics_data: Indexed::new(None),
+            doctors_data: Indexed::new(None),
+            prescriptions_data: Indexed::new(None),
+            drugs_data: Indexed::new(None),
+            drug_gps: Indexed::new(None),
             map: Graph::new(),
-            ambulances_data: None,
-            logs_data: HashMap::new(),
+            ambulances_data: Indexed::new(None),
+            audit_log: LinkedList::new(),
+            next_audit_id: 1,
+            role_permissions: Indexed::new(None),
+            known_permissions: permissions::all_permissions(),
+            field_key_salt: Vec::new(),
+            field_key_check: String::new(),
+            journal_generation: 0,
+            field_key: None,
+            changed_sections: Vec::new(),
+            persisted_audit_id: 1,
+            journal_len: 0,
+            migrated_from: None,
+            storage: StorageOptions::default(),
+            savepoints: Vec::new(),
         }
     }
 
-    pub fn insert_user(&mut self, user: User) -> io::Result<()> {
-        match self.users_data {
-            Some(ref mut data) => {
-                if data.get_by_uniq_attr(user.username.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Username already exists"));
-                }
-                data.insert(user);
-                Ok(())
-            },
-            None => {
-                self.users_data = Some(TreeNode::new(user));
-                Ok(())
-            },
+    // one repository per kind of record, inserts check uniqueness and removes report a missing key there
+    pub fn users(&self) -> &impl Repository<User> {
+        &self.users_data
+    }
+
+    pub fn users_mut(&mut self) -> &mut impl Repository<User> {
+        &mut self.users_data
+    }
+
+    pub fn clinics(&self) -> &impl Repository<Clinic> {
+        &self.clinics_data
+    }
+
+    pub fn clinics_mut(&mut self) -> &mut impl Repository<Clinic> {
+        &mut self.clinics_data
+    }
+
+    pub fn doctors_lists(&self) -> &impl Repository<DoctorsList> {
+        &self.doctors_data
+    }
+
+    pub fn doctors_lists_mut(&mut self) -> &mut impl Repository<DoctorsList> {
+        &mut self.doctors_data
+    }
+
+    pub fn prescriptions(&self) -> &impl Repository<Prescription> {
+        &self.prescriptions_data
+    }
+
+    pub fn prescriptions_mut(&mut self) -> &mut impl Repository<Prescription> {
+        &mut self.prescriptions_data
+    }
+
+    pub fn drugs(&self) -> &impl Repository<Drug> {
+        &self.drugs_data
+    }
+
+    pub fn drugs_mut(&mut self) -> &mut impl Repository<Drug> {
+        &mut self.drugs_data
+    }
+
+    pub fn drug_groups(&self) -> &impl Repository<DrugGP> {
+        &self.drug_gps
+    }
+
+    pub fn drug_groups_mut(&mut self) -> &mut impl Repository<DrugGP> {
+        &mut self.drug_gps
+    }
+
+    pub fn ambulances(&self) -> &impl Repository<Ambulance> {
+        &self.ambulances_data
+    }
+
+    pub fn ambulances_mut(&mut self) -> &mut impl Repository<Ambulance> {
+        &mut self.ambulances_data
+    }
+
+    pub fn map(&self) -> &Graph {
+        &self.map
+    }
+
+    // the map is journaled whole, so changes to it have to come through here
+    pub fn map_mut(&mut self) -> &mut Graph {
+        self.mark_section(Section::Map);
+        &mut self.map
+    }
+
+    // called before the section changes
+    fn mark_section(&mut self, section: Section) {
+        if let Some(saved) = self.savepoints.last_mut() {
+            match section {
+                Section::Map if saved.map.is_none() => saved.map = Some(self.map.clone()),
+                Section::FieldKey if saved.field_key.is_none() => {
+                    saved.field_key = Some((self.field_key_salt.clone(), self.field_key_check.clone(), self.field_key.clone()));
+                },
+                Section::KnownPermissions if saved.known_permissions.is_none() => saved.known_permissions = Some(self.known_permissions.clone()),
+                _ => {},
+            }
+        }
+        if !self.changed_sections.contains(&section) {
+            self.changed_sections.push(section);
         }
     }
 
-    pub fn insert_clinic(&mut self, clinic: Clinic) -> io::Result<()> {
-        match self.clinics_data {
-            Some(ref mut data) => {
-                if data.get_by_uniq_attr(clinic.name.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Clinic name already exists"));
-                }
-                data.insert(clinic);
-                Ok(())
-            },
-            None => {
-                self.clinics_data = Some(LinkedList::new());
-                self.clinics_data.as_mut().unwrap().insert(clinic);
-                Ok(())
-            },
+    // newest events first
+    pub fn record_event(&mut self, mut event: AuditEvent) {
+        event.id = self.next_audit_id;
+        event.timestamp = Local::now().timestamp();
+        let prev_hash = self.audit_log.iter().next().map_or(GENESIS_HASH.to_string(), |last| last.hash.clone());
+        event.link(prev_hash);
+        self.next_audit_id += 1;
+        self.audit_log.push_front(event);
+    }
+
+    pub fn audit_chain_oldest_first(&self) -> Vec<&AuditEvent> {
+        let mut events = self.audit_log.iter().collect::<Vec<_>>();
+        events.reverse();
+        events
+    }
+
+    pub fn verify_audit_chain(&self) -> Result<Option<ChainHead>, ChainError> {
+        audit::verify_chain(self.audit_chain_oldest_first().into_iter())
+    }
+
+    pub fn audit_events(&self, filter: &AuditFilter) -> Vec<&AuditEvent> {
+        self.audit_log.iter().filter(|event| filter.matches(event)).collect()
+    }
+
+    // nothing stored at all, not even audit events
+    pub fn is_empty(&self) -> bool {
+        self.users().count() == 0
+            && self.clinics().count() == 0
+            && self.doctors_lists().count() == 0
+            && self.prescriptions().count() == 0
+            && self.drugs().count() == 0
+            && self.drug_groups().count() == 0
+            && self.map.nodes.is_empty()
+            && self.ambulances().count() == 0
+            && Repository::count(&self.role_permissions) == 0
+            && self.audit_log.is_empty()
+    }
+
+    // non-key lookups go through the secondary indexes declared on the entities, see db::index
+    pub fn get_drug_by_name(&mut self, name: String) -> Option<&mut Drug> {
+        let id = self.find_drug_by_name(&name)?.uattr();
+        self.drugs_mut().get_mut(&id)
+    }
+
+    pub fn find_drug_by_name(&self, name: &str) -> Option<&Drug> {
+        self.drugs_data.find("name", name).into_iter().next()
+    }
+
+    pub fn find_users_by_ssn(&self, ssn: &str) -> Vec<&User> {
+        self.users_data.find("ssn", ssn)
+    }
+
+    pub fn find_users_by_full_name(&self, full_name: &str) -> Vec<&User> {
+        self.users_data.find("full name", &full_name.to_lowercase())
+    }
+
+    // the queues of the doctors the patient has an appointment with
+    pub fn queues_with_patient(&self, patient: &str) -> Vec<&DoctorsList> {
+        self.doctors_data.find("patient", patient)
+    }
+
+    // roles without a stored entry fall back to their default permission set
+    pub fn get_role_permissions(&mut self, role: Role) -> &mut RolePermissions {
+        let key = format!("{:?}", role);
+        if !Repository::contains(&self.role_permissions, &key) {
+            self.role_permissions.store(RolePermissions::default_for(role));
         }
+        self.role_permissions.get_mut(&key).unwrap()
     }
 
-    pub fn insert_doctors_list(&mut self, doctors_list: DoctorsList) -> io::Result<()> {
-        match self.doctors_data {
-            Some(ref mut data) => {
-                if data.get_by_uniq_attr(doctors_list.doctor.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Doctors list already exists"));
-                }
-                data.insert(doctors_list);
-                Ok(())
-            },
-            None => {
-                self.doctors_data = Some(LinkedList::new());
-                self.doctors_data.as_mut().unwrap().insert(doctors_list);
-                Ok(())
-            },
+    // see RolePermissions::merge_defaults, runs on every load until the merged entries are saved
+    pub fn merge_new_permissions(&mut self) {
+        if permissions::ALL_PERMISSIONS.iter().all(|permission| self.known_permissions.contains(permission)) {
+            return;
         }
+        self.mark_section(Section::KnownPermissions);
+        let known = &self.known_permissions;
+        self.role_permissions.change_all(|entries| if let Some(entries) = entries {
+            for entry in entries.iter_mut() {
+                entry.merge_defaults(known);
+            }
+        });
+        self.known_permissions = permissions::all_permissions();
     }
 
-    pub fn insert_prescription(&mut self, prescription: Prescription) -> io::Result<()> {
-        match self.prescriptions_data {
-            Some(ref mut data) => {
-                data.insert(prescription);
-                Ok(())
-            },
-            None => {
-                self.prescriptions_data = Some(LinkedList::new());
-                self.prescriptions_data.as_mut().unwrap().insert(prescription);
-                Ok(())
-            },
+    pub fn role_has_permission(&self, role: &Role, permission: Permission) -> bool {
+        let stored = self.role_permissions.as_ref().and_then(|data| data.iter().find(|entry| entry.role == *role));
+        match stored {
+            Some(entry) => entry.has(permission),
+            None => RolePermissions::default_for(role.clone()).has(permission),
         }
     }
 
-    pub fn insert_drug(&mut self, drug: Drug) -> io::Result<()> {
-        match self.drugs_data {
-            Some(ref mut data) => {
-                if data.get_drug_by_id(drug.id).is_some() || data.get_drug_by_name(drug.name.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Drug with the same id or name already exists"));
-                }
-                data.insert(drug);
-                data.balance();
-                Ok(())
-            },
-            None => {
-                self.drugs_data = Some(Box::new(TreeNode::new(drug)));
-                Ok(())
-            },
+    pub fn remove_user(&mut self, uniq_attr: String) -> Result<User, DbError> {
+        if !self.users().contains(&uniq_attr) {
+            return Err(DbError::NotFound { entity: "user", key: uniq_attr });
+        }
+        let queues = self.doctors_lists().iterate().collect::<Vec<_>>();
+        let waiting = queues.iter().filter(|list| list.doctor == uniq_attr).flat_map(|list| list.patients.iter().map(|patient| patient.name.clone())).collect();
+        let queued_with: Vec<String> = queues.iter().filter(|list| list.patients.iter().any(|patient| patient.name == uniq_attr)).map(|list| list.doctor.clone()).collect();
+        let clinics: Vec<String> = self.clinics().iterate().filter(|clinic| clinic.doctors.contains(&uniq_attr)).map(|clinic| clinic.name.clone()).collect();
+        let prescriptions = self.prescriptions().iterate().filter(|prescription| prescription.patient_name == uniq_attr).map(|prescription| prescription.patient_name.clone()).collect();
+        relations::check_delete("user", &uniq_attr, &[
+            (Relation::DoctorsQueue, waiting),
+            (Relation::PatientInQueue, queued_with.clone()),
+            (Relation::DoctorInClinic, clinics.clone()),
+            (Relation::PatientPrescription, prescriptions),
+        ])?;
+        let user = self.users_mut().remove(&uniq_attr)?;
+
+        // the rules allowed the delete, so whatever still refers to the user by name goes with it
+        let _ = self.doctors_lists_mut().remove(&uniq_attr);
+        for doctor in queued_with {
+            if let Some(doctors_list) = self.doctors_lists_mut().get_mut(&doctor) {
+                while doctors_list.patients.remove_by_uniq_attr(uniq_attr.clone()) {}
+            }
+        }
+        for clinic in clinics {
+            if let Some(clinic) = self.clinics_mut().get_mut(&clinic) {
+                while clinic.doctors.remove(&uniq_attr) {}
+            }
         }
+        while self.prescriptions_mut().remove(&uniq_attr).is_ok() {}
+        Ok(user)
     }
 
-    pub fn insert_drug_gp(&mut self, drug_gp: DrugGP) -> io::Result<()> {
-        match self.drug_gps {
-            Some(ref mut data) => {
-                if data.get_by_uniq_attr(drug_gp.name.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Drug group already exists"));
-                }
-                data.insert(drug_gp);
-                Ok(())
-            },
-            None => {
-                self.drug_gps = Some(LinkedList::new());
-                self.drug_gps.as_mut().unwrap().insert(drug_gp);
-                Ok(())
+    pub fn remove_drug(&mut self, id: u32) -> Result<Drug, DbError> {
+        let drug = match self.drugs().get(&id.to_string()) {
+            Some(drug) => drug.clone(),
+            None => return Err(DbError::NotFound { entity: "drug", key: id.to_string() }),
+        };
+        let groups: Vec<String> = self.drug_groups().iterate().filter(|group| group.drugs.contains(&id)).map(|group| group.name.clone()).collect();
+        relations::check_delete("drug", &drug.name, &[(Relation::DrugInGroup, groups.clone())])?;
+        self.drugs_mut().remove(&id.to_string())?;
+        for group in groups {
+            if let Some(group) = self.drug_groups_mut().get_mut(&group) {
+                while group.drugs.remove(&id) {}
+            }
+        }
+        Ok(drug)
+    }
+
+    // the ambulance's object on the map goes with it
+    pub fn remove_ambulance(&mut self, uniq_attr: String) -> bool {
+        match self.ambulances_mut().remove(&uniq_attr) {
+            Ok(ambulance) => {
+                self.map_mut().remove_object_from_node(&ambulance.location, &uniq_attr);
+                true
             },
+            Err(_) => false,
+        }
+    }
+
+    pub fn remove_location(&mut self, name: &str) -> Result<(), DbError> {
+        if !self.map.nodes.contains_key(name) {
+            return Err(DbError::NotFound { entity: "location", key: name.to_string() });
+        }
+        let ambulances = self.ambulances().iterate().collect::<Vec<_>>();
+        let parked = ambulances.iter().filter(|ambulance| ambulance.location == name).map(|ambulance| ambulance.name.clone()).collect();
+        let based = ambulances.iter().filter(|ambulance| ambulance.hospital == name).map(|ambulance| ambulance.name.clone()).collect();
+        let mut routes = self.map.edges.get(name).map_or(Vec::new(), |targets| targets.iter().map(|to| format!("{} - {}", name, to)).collect::<Vec<_>>());
+        for (from, targets) in self.map.edges.iter().filter(|(from, _)| from.as_str() != name) {
+            if targets.contains(&name.to_string()) {
+                routes.push(format!("{} - {}", from, name));
+            }
+        }
+        relations::check_delete("location", name, &[
+            (Relation::AmbulanceAtLocation, parked),
+            (Relation::AmbulanceBase, based),
+            (Relation::RouteToLocation, routes),
+        ])?;
+        self.map_mut().remove_node(name.to_string());
+        Ok(())
+    }
+
+    fn current_field_key(&self) -> Result<FieldKey, DbError> {
+        match self.field_key {
+            Some(ref key) => Ok(key.clone()),
+            None => Err(DbError::Locked),
         }
     }
 
-    pub fn insert_ambulance(&mut self, ambulance: Ambulance) -> io::Result<()> {
-        match self.ambulances_data {
-            Some(ref mut data) => {
-                if data.get_by_uniq_attr(ambulance.name.clone()).is_some() {
-                    return Err(Error::new(ErrorKind::AlreadyExists, "Ambulance already exists"));
+    fn section_bytes(&self, section: Section) -> Vec<u8> {
+        match section {
+            Section::Clinics => bincode::serialize(&self.clinics_data),
+            // journaled whole the queues keep the list layout they had before format 3
+            Section::DoctorsLists => {
+                let mut lists = LinkedList::new();
+                for list in self.doctors_data.iterate() {
+                    lists.insert(list.clone());
                 }
-                data.insert(ambulance);
-                Ok(())
+                bincode::serialize(&Some(lists))
             },
-            None => {
-                self.ambulances_data = Some(LinkedList::new());
-                self.ambulances_data.as_mut().unwrap().insert(ambulance);
-                Ok(())
+            Section::Prescriptions => bincode::serialize(&self.prescriptions_data),
+            Section::DrugGroups => bincode::serialize(&self.drug_gps),
+            Section::Ambulances => bincode::serialize(&self.ambulances_data),
+            Section::RolePermissions => bincode::serialize(&self.role_permissions),
+            Section::Map => bincode::serialize(&self.map),
+            Section::FieldKey => bincode::serialize(&(&self.field_key_salt, &self.field_key_check)),
+            Section::KnownPermissions => bincode::serialize(&self.known_permissions),
+        }.unwrap()
+    }
+
+    fn set_section(&mut self, section: Section, bytes: &[u8]) -> Result<(), DbError> {
+        match section {
+            Section::Clinics => self.clinics_data = decode_section(section, bytes)?,
+            Section::DoctorsLists => {
+                let lists: Option<LinkedList<DoctorsList>> = decode_section(section, bytes)?;
+                let mut queues: Option<TreeNode<DoctorsList>> = None;
+                for list in lists.iter().flat_map(|lists| lists.iter()) {
+                    queues.store(list.clone());
+                }
+                self.doctors_data = Indexed::new(queues);
             },
+            Section::Prescriptions => self.prescriptions_data = decode_section(section, bytes)?,
+            Section::DrugGroups => self.drug_gps = decode_section(section, bytes)?,
+            Section::Ambulances => self.ambulances_data = decode_section(section, bytes)?,
+            Section::RolePermissions => self.role_permissions = decode_section(section, bytes)?,
+            Section::Map => self.map = decode_section(section, bytes)?,
+            Section::FieldKey => (self.field_key_salt, self.field_key_check) = decode_section(section, bytes)?,
+            Section::KnownPermissions => self.known_permissions = decode_section(section, bytes)?,
         }
+        Ok(())
     }
 
-    pub fn insert_log(&mut self, data_str: String) {
-        let datetime = Local::now().to_string();
-        self.logs_data.insert(datetime, data_str);
+    // everything in memory is on disk now
+    fn mark_persisted(&mut self) {
+        self.users_data.clear_changes();
+        self.clinics_data.clear_changes();
+        self.doctors_data.clear_changes();
+        self.prescriptions_data.clear_changes();
+        self.drugs_data.clear_changes();
+        self.drug_gps.clear_changes();
+        self.ambulances_data.clear_changes();
+        self.role_permissions.clear_changes();
+        self.changed_sections.clear();
+        self.persisted_audit_id = self.next_audit_id;
     }
 
-    pub fn get_user(&self, uniq_attr: String) -> Option<&User> {
-        match self.users_data {
-            Some(ref data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+    // the records changed since the last commit, changed users get sealed on the way
+    fn pending_changes(&mut self, key: &FieldKey) -> Vec<JournalOp> {
+        let mut ops = Vec::new();
+        for username in self.users_data.changed_keys() {
+            match self.users_data.get_mut(&username) {
+                Some(user) => {
+                    user.seal_pii(key);
+                    ops.push(JournalOp::PutUser(user.clone()));
+                },
+                None => ops.push(JournalOp::DeleteUser(username)),
+            }
         }
+        for id in self.drugs_data.changed_keys() {
+            match self.drugs_data.get(&id) {
+                Some(drug) => ops.push(JournalOp::PutDrug(drug.clone())),
+                None => ops.push(JournalOp::DeleteDrug(id.parse().unwrap())),
+            }
+        }
+        record_changes(Section::Clinics, &self.clinics_data, &mut ops);
+        record_changes(Section::DoctorsLists, &self.doctors_data, &mut ops);
+        record_changes(Section::Prescriptions, &self.prescriptions_data, &mut ops);
+        record_changes(Section::DrugGroups, &self.drug_gps, &mut ops);
+        record_changes(Section::Ambulances, &self.ambulances_data, &mut ops);
+        record_changes(Section::RolePermissions, &self.role_permissions, &mut ops);
+        for section in self.changed_sections.iter() {
+            ops.push(JournalOp::SetSection(*section, self.section_bytes(*section)));
+        }
+        let mut new_events = self.audit_log.iter().take_while(|event| event.id >= self.persisted_audit_id).cloned().collect::<Vec<_>>();
+        new_events.reverse();
+        ops.extend(new_events.into_iter().map(JournalOp::AppendAudit));
+        ops
     }
 
-    pub fn get_doctors_list(&mut self, uniq_attr: String) -> Option<&mut DoctorsList> {
-        match self.doctors_data {
-            Some(ref mut data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+    // replays one journaled change, user deletes skip the cascade since its effects were journaled too
+    fn apply(&mut self, op: JournalOp) -> Result<(), DbError> {
+        match op {
+            JournalOp::PutUser(user) => {
+                if self.users().contains(&user.username) {
+                    self.users_mut().update(user)?;
+                } else {
+                    self.users_mut().insert(user)?;
+                }
+            },
+            JournalOp::DeleteUser(username) => {
+                let _ = self.users_mut().remove(&username);
+            },
+            // stored without the name check, a rename may only be valid once the later ops are in
+            JournalOp::PutDrug(drug) => {
+                if self.drugs().contains(&drug.uattr()) {
+                    self.drugs_mut().update(drug)?;
+                } else {
+                    self.drugs_mut().store(drug);
+                }
+            },
+            JournalOp::DeleteDrug(id) => {
+                let _ = self.drugs_mut().remove(&id.to_string());
+            },
+            JournalOp::AppendAudit(event) => {
+                if event.id >= self.next_audit_id {
+                    self.next_audit_id = event.id + 1;
+                    self.audit_log.push_front(event);
+                }
+            },
+            JournalOp::SetSection(section, bytes) => self.set_section(section, &bytes)?,
+            JournalOp::PutRecord(section, bytes) => match section {
+                Section::Clinics => put_record(section, &mut self.clinics_data, &bytes)?,
+                Section::DoctorsLists => put_record(section, &mut self.doctors_data, &bytes)?,
+                Section::Prescriptions => put_record(section, &mut self.prescriptions_data, &bytes)?,
+                Section::DrugGroups => put_record(section, &mut self.drug_gps, &bytes)?,
+                Section::Ambulances => put_record(section, &mut self.ambulances_data, &bytes)?,
+                Section::RolePermissions => put_record(section, &mut self.role_permissions, &bytes)?,
+                Section::Map | Section::FieldKey | Section::KnownPermissions => return Err(only_whole(section)),
+            },
+            // like user and drug deletes, a record that is already gone is fine
+            JournalOp::DeleteRecord(section, key) => {
+                let _ = match section {
+                    Section::Clinics => self.clinics_data.remove(&key).map(|_| ()),
+                    Section::DoctorsLists => self.doctors_data.remove(&key).map(|_| ()),
+                    Section::Prescriptions => self.prescriptions_data.remove(&key).map(|_| ()),
+                    Section::DrugGroups => self.drug_gps.remove(&key).map(|_| ()),
+                    Section::Ambulances => self.ambulances_data.remove(&key).map(|_| ()),
+                    Section::RolePermissions => self.role_permissions.remove(&key).map(|_| ()),
+                    Section::Map | Section::FieldKey | Section::KnownPermissions => return Err(only_whole(section)),
+                };
+            },
         }
+        Ok(())
     }
 
-    pub fn get_clinic(&mut self, uniq_attr: String) -> Option<&mut Clinic> {
-        match self.clinics_data {
-            Some(ref mut data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+    // appends what changed to the journal, the snapshot is only rewritten when the journal gets long
+    pub fn commit(&mut self) -> Result<(), DbError> {
+        if !self.savepoints.is_empty() {
+            return Ok(());
         }
-    }
+        if !Path::new(&self.storage.path).exists() || self.migrated_from.is_some() {
+            return self.compact();
+        }
+        let key = self.current_field_key()?;
+        let ops = self.pending_changes(&key);
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let path = journal::journal_path(&self.storage.path);
+        if self.journal_len < journal::HEADER_LEN {
+            self.journal_len = journal::reset(&path, self.journal_generation)?;
+        }
+        self.journal_len = journal::append(&path, self.journal_len, &ops)?;
+        self.mark_persisted();
 
-    pub fn get_prescription(&mut self, uniq_attr: String) -> Option<&mut Prescription> {
-        match self.prescriptions_data {
-            Some(ref mut data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+        if self.journal_len > self.storage.compact_threshold {
+            self.compact()?;
         }
+        Ok(())
+    }
+
+    // runs the steps and commits everything they changed as one journal frame, or puts it all back if a step fails
+    pub fn transaction<T, E: From<DbError>>(&mut self, steps: impl FnOnce(&mut Database) -> Result<T, E>) -> Result<T, E> {
+        transaction(self, |db| db, steps)
     }
 
-    pub fn get_drug_by_id(&mut self, id: u32) -> Option<&mut Drug> {
-        match self.drugs_data {
-            Some(ref mut data) => data.get_drug_by_id_mut(id),
-            None => None,
+    fn collections(&mut self) -> [&mut dyn Savepoints; 8] {
+        [
+            &mut self.users_data,
+            &mut self.clinics_data,
+            &mut self.doctors_data,
+            &mut self.prescriptions_data,
+            &mut self.drugs_data,
+            &mut self.drug_gps,
+            &mut self.ambulances_data,
+            &mut self.role_permissions,
+        ]
+    }
+
+    pub fn begin(&mut self) {
+        for collection in self.collections() {
+            collection.begin();
         }
+        self.savepoints.push(Savepoint {
+            audit_len: self.audit_log.len(),
+            next_audit_id: self.next_audit_id,
+            changed_sections: self.changed_sections.clone(),
+            map: None,
+            field_key: None,
+            known_permissions: None,
+        });
     }
 
-    pub fn get_drug_by_name(&mut self, name: String) -> Option<&mut Drug> {
-        match self.drugs_data {
-            Some(ref mut data) => data.get_drug_by_name_mut(name),
-            None => None,
+    // back to how things were at the matching begin()
+    pub fn rollback(&mut self) {
+        if let Some(saved) = self.savepoints.pop() {
+            self.restore(saved);
         }
     }
 
-    pub fn get_drug_gp(&mut self, uniq_attr: String) -> Option<&mut DrugGP> {
-        match self.drug_gps {
-            Some(ref mut data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+    // an inner transaction becomes part of the outer one, only the outermost writes to disk
+    pub fn finish(&mut self) -> Result<(), DbError> {
+        let saved = match self.savepoints.pop() {
+            Some(saved) => saved,
+            None => return self.commit(),
+        };
+        if let Some(outer) = self.savepoints.last_mut() {
+            outer.map = outer.map.take().or(saved.map);
+            outer.field_key = outer.field_key.take().or(saved.field_key);
+            outer.known_permissions = outer.known_permissions.take().or(saved.known_permissions);
+            for collection in self.collections() {
+                collection.release();
+            }
+            return Ok(());
+        }
+        if let Err(e) = self.commit() {
+            self.restore(saved);
+            return Err(e);
         }
+        for collection in self.collections() {
+            collection.release();
+        }
+        Ok(())
     }
 
-    pub fn get_ambulance(&mut self, uniq_attr: String) -> Option<&mut Ambulance> {
-        match self.ambulances_data {
-            Some(ref mut data) => data.get_by_uniq_attr(uniq_attr),
-            None => None,
+    fn restore(&mut self, saved: Savepoint) {
+        for collection in self.collections() {
+            collection.rollback();
+        }
+        // events are only ever added at the front
+        while self.audit_log.len() > saved.audit_len {
+            self.audit_log.pop();
+        }
+        self.next_audit_id = saved.next_audit_id;
+        self.changed_sections = saved.changed_sections;
+        if let Some(map) = saved.map {
+            self.map = map;
+        }
+        if let Some((salt, check, key)) = saved.field_key {
+            self.field_key_salt = salt;
+            self.field_key_check = check;
+            self.field_key = key;
+        }
+        if let Some(known) = saved.known_permissions {
+            self.known_permissions = known;
         }
     }
 
-    pub fn remove_prescription(&mut self, uniq_attr: String) -> bool {
-        match self.prescriptions_data {
-            Some(ref mut data) => data.remove_by_uniq_attr(uniq_attr),
-            None => false,
+    // folds the journal into a new snapshot, a crash in between leaves a journal of the old generation that is ignored
+    pub fn compact(&mut self) -> Result<(), DbError> {
+        self.journal_generation += 1;
+        let path = self.storage.path.clone();
+        if let Err(e) = self.save_to_file(&path) {
+            self.journal_generation -= 1;
+            return Err(e);
         }
+        self.journal_len = journal::reset(&journal::journal_path(&path), self.journal_generation)?;
+        self.mark_persisted();
+        self.migrated_from = None;
+        Ok(())
+    }
+
+    // loading doesn't know the options, so main hands them over before the first commit
+    pub fn set_storage(&mut self, storage: StorageOptions) {
+        self.storage = storage;
+    }
+
+    pub fn migrated_from(&self) -> Option<u32> {
+        self.migrated_from
+    }
+
+    fn set_field_key(&mut self, passphrase: &str) {
+        let salt = field_cipher::generate_salt();
+        let key = FieldKey::derive(passphrase, &salt, field_cipher::KEY_ITERATIONS);
+        self.mark_section(Section::FieldKey);
+        self.field_key_salt = salt;
+        self.field_key_check = key.check_value();
+        self.field_key = Some(key);
     }
 
-    pub fn remove_drug(&mut self, id: u32) {
-        if let Some(ref mut _data) = self.drugs_data {
-            self.drugs_data = TreeNode::remove_drug_by_id(self.drugs_data.take(), id);
+    // derives the field key and decrypts every user, a fresh database adopts the passphrase
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), DbError> {
+        let unreadable = self.open_with(passphrase)?;
+        if !unreadable.is_empty() {
+            self.field_key = None;
+            return Err(DbError::Integrity(format!("encrypted fields of {} could not be decrypted", unreadable.join(", "))));
         }
+        Ok(())
     }
 
-    pub fn remove_drug_gp(&mut self, uniq_attr: String) -> bool {
-        match self.drug_gps {
-            Some(ref mut data) => data.remove_by_uniq_attr(uniq_attr),
-            None => false,
+    // for salvaged databases, users whose sealed fields are damaged keep going without personal details
+    pub fn unlock_salvaged(&mut self, passphrase: &str) -> Result<Vec<String>, DbError> {
+        self.open_with(passphrase)
+    }
+
+    // returns the users whose personal details could not be decrypted
+    fn open_with(&mut self, passphrase: &str) -> Result<Vec<String>, DbError> {
+        if self.field_key_salt.is_empty() {
+            self.users_data.rewrite_all(|users| if let Some(users) = users {
+                users.for_each_mut(&mut |user: &mut User| {
+                    user.open_unsealed_pii();
+                });
+            });
+            self.set_field_key(passphrase);
+            self.mark_persisted();
+            return Ok(Vec::new());
+        }
+        let key = FieldKey::derive(passphrase, &self.field_key_salt, field_cipher::KEY_ITERATIONS);
+        if key.check_value() != self.field_key_check {
+            return Err(DbError::WrongPassphrase);
         }
+
+        let unreadable = self.open_users(&key);
+        self.field_key = Some(key);
+        Ok(unreadable)
     }
 
-    pub fn remove_ambulance(&mut self, uniq_attr: String) -> bool {
-        match self.ambulances_data {
-            Some(ref mut data) => data.remove_by_uniq_attr(uniq_attr),
-            None => false,
+    fn open_users(&mut self, key: &FieldKey) -> Vec<String> {
+        let mut unreadable = Vec::new();
+        self.users_data.rewrite_all(|users| if let Some(users) = users {
+            users.for_each_mut(&mut |user: &mut User| {
+                if !user.open_pii(key) {
+                    unreadable.push(user.username.clone());
+                }
+            });
+        });
+        unreadable
+    }
+
+    pub fn verify_passphrase(&self, passphrase: &str) -> bool {
+        FieldKey::derive(passphrase, &self.field_key_salt, field_cipher::KEY_ITERATIONS).check_value() == self.field_key_check
+    }
+
+    // re-encrypts every user under a key derived from the new passphrase
+    // compacting drops the journal too, so nothing sealed under the old key is left behind
+    pub fn rotate_field_key(&mut self, new_passphrase: &str) -> Result<(), DbError> {
+        if self.field_key.is_none() {
+            return Err(DbError::Locked);
         }
+        self.set_field_key(new_passphrase);
+        self.compact()
     }
 
-    pub fn commit(&mut self) -> io::Result<()> {
-        self.save_to_file("database.bin")
+    fn encode_sealed(&mut self) -> Result<Vec<u8>, DbError> {
+        let key = self.current_field_key()?;
+        self.users_data.rewrite_all(|users| if let Some(users) = users {
+            users.for_each_mut(&mut |user: &mut User| user.seal_pii(&key));
+        });
+        Ok(format::encode(&bincode::serialize(self).map_err(io::Error::other)?))
     }
 
-    pub fn save_to_file(&self, filename: &str) -> io::Result<()> {
-        let encoded = bincode::serialize(self).unwrap();
-        let mut file = File::create(filename)?;
-        file.write_all(&encoded)?;
+    pub fn save_to_file(&mut self, filename: &str) -> Result<(), DbError> {
+        let encoded = self.encode_sealed()?;
+
+        // write everything next to the original first so a crash never leaves a truncated database
+        let temp_path = write_temp(filename, &encoded)?;
+        rotate_backups(filename, self.storage.backup_count)?;
+        fs::rename(&temp_path, filename)?;
+        sync_parent_dir(filename);
         Ok(())
     }
 
-    pub fn load_from_file(filename: &str) -> io::Result<Self> {
+    pub fn load_from_file(filename: &str) -> Result<Self, DbError> {
         let mut file = File::open(filename)?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-        let database: Database = bincode::deserialize(&buffer).unwrap();
-        Ok(database)
+        let (version, payload) = format::upgrade(&buffer).map_err(|e| in_file(filename, e))?;
+        let mut db: Database = bincode::deserialize(&payload).map_err(|e| DbError::Corrupt(format!("{} is not a readable database: {}", filename, e)))?;
+        if version != FORMAT_VERSION {
+            db.migrated_from = Some(version);
+        }
+        db.replay_journal(filename)?;
+        db.merge_new_permissions();
+        Ok(db)
+    }
+
+    // a sealed copy of everything as <snapshot_dir>/<time>-<name>.snapshot, in the same format as the database file
+    pub fn save_snapshot(&mut self, name: &str) -> Result<SnapshotInfo, DbError> {
+        if !snapshot::valid_name(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' can't be used as a snapshot name", name)).into());
+        }
+        let info = SnapshotInfo::new(&self.storage.snapshot_dir, name, Local::now().naive_local());
+        if Path::new(&info.path).exists() {
+            return Err(DbError::Duplicate { entity: "snapshot", key: info.id() });
+        }
+        fs::create_dir_all(&self.storage.snapshot_dir)?;
+        let encoded = self.encode_sealed()?;
+        let temp_path = write_temp(&info.path, &encoded)?;
+        fs::rename(&temp_path, &info.path)?;
+        sync_parent_dir(&info.path);
+        Ok(info)
+    }
+
+    // opened with the current key, a snapshot from before a key rotation needs the passphrase it was taken under
+    pub fn load_snapshot(&self, info: &SnapshotInfo, passphrase: Option<&str>) -> Result<Database, DbError> {
+        let mut snapshot = Database::load_from_file(&info.path)?;
+        let key = match passphrase {
+            Some(passphrase) => FieldKey::derive(passphrase, &snapshot.field_key_salt, field_cipher::KEY_ITERATIONS),
+            None if snapshot.field_key_check == self.field_key_check => self.current_field_key()?,
+            None => return Err(DbError::WrongPassphrase),
+        };
+        if key.check_value() != snapshot.field_key_check {
+            return Err(DbError::WrongPassphrase);
+        }
+        let unreadable = snapshot.open_users(&key);
+        if !unreadable.is_empty() {
+            return Err(DbError::Integrity(format!("encrypted fields of {} in {} could not be decrypted", unreadable.join(", "), info.id())));
+        }
+        Ok(snapshot)
+    }
+
+    // takes over the records of the snapshot, the audit log, encryption key and journal stay as they are
+    // so history keeps its chain and the next commit journals the restored records like any other change
+    pub fn restore_snapshot(&mut self, snapshot: Database) {
+        let Database {
+            users_data,
+            clinics_data,
+            doctors_data,
+            prescriptions_data,
+            drugs_data,
+            drug_gps,
+            map,
+            ambulances_data,
+            role_permissions,
+            ..
+        } = snapshot;
+        self.users_data.replace_with(users_data);
+        self.clinics_data.replace_with(clinics_data);
+        self.doctors_data.replace_with(doctors_data);
+        self.prescriptions_data.replace_with(prescriptions_data);
+        self.drugs_data.replace_with(drugs_data);
+        self.drug_gps.replace_with(drug_gps);
+        *self.map_mut() = map;
+        self.ambulances_data.replace_with(ambulances_data);
+        self.role_permissions.replace_with(role_permissions);
+    }
+
+    pub fn snapshot_dir(&self) -> &str {
+        &self.storage.snapshot_dir
+    }
+
+    // a journal from another generation was already folded into the snapshot
+    fn replay_journal(&mut self, filename: &str) -> Result<usize, DbError> {
+        let journal_path = journal::journal_path(filename);
+        let mut replayed = 0;
+        if let Some(replay) = journal::read(&journal_path).map_err(|e| in_file(&journal_path, e))? {
+            if replay.generation == self.journal_generation {
+                // the journal holds records in the layout it was written with, there is no migrating those
+                if !format::journal_readable(replay.format_version) && replay.valid_len > journal::HEADER_LEN {
+                    return Err(DbError::Corrupt(format!("{} holds uncompacted changes in format {}, open it with the matching version first", journal_path, replay.format_version)));
+                }
+                replayed = replay.batches.len();
+                for op in replay.batches.into_iter().flatten() {
+                    self.apply(op)?;
+                }
+                if replay.format_version == FORMAT_VERSION {
+                    self.journal_len = replay.valid_len;
+                }
+            }
+        }
+        self.mark_persisted();
+        Ok(replayed)
+    }
+
+    // decodes the sections of a damaged file in order, everything from the first undecodable section on is lost
+    pub fn salvage_from_file(filename: &str) -> Result<(Self, SalvageReport), DbError> {
+        let buffer = fs::read(filename)?;
+        let (version, payload) = format::split(&buffer);
+        if version != FORMAT_VERSION {
+            return Err(DbError::Corrupt(format!("{} claims format {}, salvage only reads format {}", filename, version, FORMAT_VERSION)));
+        }
+
+        let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+        let mut decoder = bincode::Deserializer::from_slice(payload, options);
+        let mut report = SalvageReport::default();
+        let mut db = Database::new();
+        if let Some(users) = report.next("users", &mut decoder) { db.users_data = users; }
+        if let Some(clinics) = report.next("clinics", &mut decoder) { db.clinics_data = clinics; }
+        if let Some(doctors) = report.next("doctors' queues", &mut decoder) { db.doctors_data = doctors; }
+        if let Some(prescriptions) = report.next("prescriptions", &mut decoder) { db.prescriptions_data = prescriptions; }
+        if let Some(drugs) = report.next("drugs", &mut decoder) { db.drugs_data = drugs; }
+        if let Some(drug_gps) = report.next("drug groups", &mut decoder) { db.drug_gps = drug_gps; }
+        if let Some(map) = report.next("map", &mut decoder) { db.map = map; }
+        if let Some(ambulances) = report.next("ambulances", &mut decoder) { db.ambulances_data = ambulances; }
+        if let Some(audit_log) = report.next("audit log", &mut decoder) { db.audit_log = audit_log; }
+        if let Some(next_audit_id) = report.next("audit counter", &mut decoder) { db.next_audit_id = next_audit_id; }
+        if let Some(role_permissions) = report.next("role permissions", &mut decoder) { db.role_permissions = role_permissions; }
+        if let Some(known_permissions) = report.next("known permissions", &mut decoder) { db.known_permissions = known_permissions; }
+        let salt: Option<Vec<u8>> = report.next("encryption key salt", &mut decoder);
+        let check: Option<String> = report.next("encryption key check", &mut decoder);
+        if let (Some(salt), Some(check)) = (salt, check) {
+            db.field_key_salt = salt;
+            db.field_key_check = check;
+        }
+        if let Some(generation) = report.next("journal generation", &mut decoder) {
+            db.journal_generation = generation;
+            report.replayed_commits = db.replay_journal(filename)?;
+        }
+
+        db.merge_new_permissions();
+        // never hand out an audit id twice, even if the counter was lost
+        let head_id = db.audit_log.iter().next().map_or(0, |event| event.id);
+        db.next_audit_id = db.next_audit_id.max(head_id + 1);
+        db.persisted_audit_id = db.next_audit_id;
+        Ok((db, report))
+    }
+}
+
+fn write_temp(filename: &str, bytes: &[u8]) -> io::Result<String> {
+    let temp_path = format!("{}.tmp", filename);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(temp_path)
+}
+
+fn in_file(filename: &str, e: DbError) -> DbError {
+    match e {
+        DbError::Corrupt(reason) => DbError::Corrupt(format!("{}: {}", filename, reason)),
+        e => e,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    pub recovered: Vec<&'static str>,
+    pub lost: Vec<&'static str>,
+    pub replayed_commits: usize,
+}
+
+impl SalvageReport {
+    // once a section fails the offsets of everything after it are unknown
+    fn next<'de, T: Deserialize<'de>, D: serde::Deserializer<'de>>(&mut self, section: &'static str, decoder: D) -> Option<T> {
+        if !self.lost.is_empty() {
+            self.lost.push(section);
+            return None;
+        }
+        match T::deserialize(decoder) {
+            Ok(value) => {
+                self.recovered.push(section);
+                Some(value)
+            }
+            Err(_) => {
+                self.lost.push(section);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::map::LocationType;
+
+    #[test]
+    fn test_commit_journals_only_what_changed() {
+        let mut db = Database::new();
+        db.field_key = Some(FieldKey::derive("passphrase", &field_cipher::generate_salt(), 1));
+        for name in ["Heart", "Eyes"] {
+            db.clinics_mut().insert(Clinic { name: name.to_string(), doctors: LinkedList::new() }).unwrap();
+        }
+        for (id, name) in [(0, "Aspirin"), (1, "Ibuprofen")] {
+            db.drugs_mut().insert(Drug::new(id, name.to_string(), 1.0, 5)).unwrap();
+        }
+        db.mark_persisted();
+        let mut replica = db.clone();
+
+        db.clinics_mut().get_mut("Eyes").unwrap().doctors.insert("doc1".to_string());
+        db.remove_drug(1).unwrap();
+        db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+        let key = db.current_field_key().unwrap();
+        let ops = db.pending_changes(&key);
+        assert_eq!(ops.len(), 3);
+        assert!(ops.iter().any(|op| matches!(op, JournalOp::PutRecord(Section::Clinics, _))));
+        assert!(ops.iter().any(|op| matches!(op, JournalOp::DeleteDrug(1))));
+        assert!(ops.iter().any(|op| matches!(op, JournalOp::SetSection(Section::Map, _))));
+
+        for op in ops {
+            replica.apply(op).unwrap();
+        }
+        assert_eq!(replica.clinics().get("Eyes").unwrap().doctors.len(), 1);
+        assert!(replica.clinics().get("Heart").unwrap().doctors.is_empty());
+        assert!(!replica.drugs().contains("1"));
+        assert!(replica.map().nodes.contains_key("Hospital A"));
+
+        db.mark_persisted();
+        assert!(db.pending_changes(&key).is_empty());
+    }
+
+    #[test]
+    fn test_rollback_puts_back_changes() {
+        let mut db = Database::new();
+        db.field_key = Some(FieldKey::derive("passphrase", &field_cipher::generate_salt(), 1));
+        for name in ["Heart", "Eyes", "Lungs"] {
+            db.clinics_mut().insert(Clinic { name: name.to_string(), doctors: LinkedList::new() }).unwrap();
+        }
+        for (id, name) in [(0, "Aspirin"), (1, "Ibuprofen")] {
+            db.drugs_mut().insert(Drug::new(id, name.to_string(), 1.0, 5)).unwrap();
+        }
+        db.mark_persisted();
+        let clinics = |db: &Database| {
+            let mut names = db.clinics().iterate().map(|clinic| clinic.name.clone()).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        db.begin();
+        db.clinics_mut().get_mut("Eyes").unwrap().doctors.insert("doc1".to_string());
+        db.clinics_mut().insert(Clinic { name: "Skin".to_string(), doctors: LinkedList::new() }).unwrap();
+        db.remove_drug(1).unwrap();
+        db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+        db.record_event(AuditEvent::new("admin".to_string(), None, audit::AuditAction::Delete, audit::EntityType::Drug, "1".to_string()));
+        // an inner transaction that went through is undone with the outer one
+        db.begin();
+        db.clinics_mut().remove("Heart").unwrap();
+        db.clinics_mut().get_mut("Eyes").unwrap().doctors.insert("doc2".to_string());
+        db.finish().unwrap();
+        db.rollback();
+
+        assert_eq!(clinics(&db), vec!["Eyes", "Heart", "Lungs"]);
+        assert!(db.clinics().get("Eyes").unwrap().doctors.is_empty());
+        assert_eq!(db.drugs().get("1").unwrap().name, "Ibuprofen");
+        assert!(db.map().nodes.is_empty());
+        assert!(db.audit_log.is_empty());
+        assert_eq!(db.next_audit_id, 1);
+        let key = db.current_field_key().unwrap();
+        assert!(db.pending_changes(&key).is_empty());
+
+        // a failed step leaves things as they were too
+        let result = db.transaction(|db| {
+            db.clinics_mut().remove("Lungs")?;
+            db.clinics_mut().remove("Skin")
+        });
+        assert!(result.is_err());
+        assert!(db.clinics().contains("Lungs"));
     }
 }
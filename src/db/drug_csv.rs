@@ -0,0 +1,268 @@
+use std::fmt;
+
+use crate::data_structures::hash_map::HashMap;
+use crate::db::db_handler::Database;
+use crate::db::entities::Drug;
+
+
+pub const INVENTORY_HEADER: &str = "id,name,price,quantity";
+pub const GROUPS_HEADER: &str = "group,drug_id,drug_name";
+
+// one line of a stocktake, a missing id means "match by name or give it the next free one"
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrugRow {
+    pub line: usize,
+    pub id: Option<u32>,
+    pub name: String,
+    pub price: f32,
+    pub quantity: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CsvError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Create(Drug),
+    Update { before: Drug, after: Drug },
+    Unchanged(Drug),
+    Conflict { line: usize, reason: String },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Create(drug) => write!(f, "create #{} {}: price {}, quantity {}", drug.id, drug.name, drug.price, drug.quantity),
+            Change::Update { before, after } => write!(f, "update #{} {}: price {} -> {}, quantity {} -> {}", after.id, after.name, before.price, after.price, before.quantity, after.quantity),
+            Change::Unchanged(drug) => write!(f, "unchanged #{} {}", drug.id, drug.name),
+            Change::Conflict { line, reason } => write!(f, "conflict on line {}: {}", line, reason),
+        }
+    }
+}
+
+// splits one line on commas, double quotes protect commas and "" is a literal quote
+fn split_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    fields.push(field);
+    Some(fields)
+}
+
+fn quote(field: &str) -> String {
+    if field.contains(',') || field.contains('"') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn parse(csv: &str) -> Result<Vec<DrugRow>, CsvError> {
+    let mut lines = csv.lines().enumerate().map(|(index, line)| (index + 1, line.trim_end_matches('\r')));
+    match lines.next() {
+        Some((_, header)) if header.replace(' ', "").eq_ignore_ascii_case(INVENTORY_HEADER) => {},
+        _ => return Err(CsvError { line: 1, reason: format!("the first line must be the header {}", INVENTORY_HEADER) }),
+    }
+
+    let mut rows = Vec::new();
+    for (line, text) in lines.filter(|(_, text)| !text.trim().is_empty()) {
+        let error = |reason: String| CsvError { line, reason };
+        let fields = split_line(text).ok_or_else(|| error("unterminated quote".to_string()))?;
+        if fields.len() != 4 {
+            return Err(error(format!("expected 4 fields, found {}", fields.len())));
+        }
+        let id = match fields[0].trim() {
+            "" => None,
+            id => Some(id.parse::<u32>().map_err(|_| error(format!("'{}' is not a drug id", id)))?),
+        };
+        let name = fields[1].trim().to_string();
+        if name.is_empty() {
+            return Err(error("the name is empty".to_string()));
+        }
+        let price = fields[2].trim().parse::<f32>().ok().filter(|price| price.is_finite() && *price >= 0.0)
+            .ok_or_else(|| error(format!("'{}' is not a price", fields[2].trim())))?;
+        let quantity = fields[3].trim().parse::<u32>().map_err(|_| error(format!("'{}' is not a quantity", fields[3].trim())))?;
+        rows.push(DrugRow { line, id, name, price, quantity });
+    }
+    Ok(rows)
+}
+
+// what importing the rows would do, nothing is changed
+pub fn plan(db: &Database, rows: &[DrugRow]) -> Vec<Change> {
+    let drugs = db.drugs_data.as_deref();
+    let mut next_id = drugs.map_or(0, |drugs| drugs.max().id + 1);
+    // new drugs without an id get one the file doesn't give to another row further down
+    let mut explicit_ids = HashMap::new();
+    for id in rows.iter().filter_map(|row| row.id) {
+        explicit_ids.insert(id, ());
+    }
+    let mut seen_ids = HashMap::new();
+    let mut seen_names = HashMap::new();
+    let mut changes = Vec::new();
+
+    for row in rows {
+        let conflict = |reason: String| Change::Conflict { line: row.line, reason };
+        let by_name = db.find_drug_by_name(&row.name);
+        let existing = match row.id {
+            Some(id) => drugs.and_then(|drugs| drugs.get_drug_by_id(id)),
+            None => by_name,
+        };
+        let id = match (row.id, existing) {
+            (Some(id), _) => id,
+            (None, Some(drug)) => drug.id,
+            (None, None) => {
+                while explicit_ids.contains_key(&next_id) {
+                    next_id += 1;
+                }
+                next_id
+            },
+        };
+
+        if let Some(first) = seen_ids.get(&id) {
+            changes.push(conflict(format!("drug #{} is already on line {}", id, first)));
+            continue;
+        }
+        if let Some(first) = seen_names.get(&row.name) {
+            changes.push(conflict(format!("{} is already on line {}", row.name, first)));
+            continue;
+        }
+        seen_ids.insert(id, row.line);
+        seen_names.insert(row.name.clone(), row.line);
+
+        let after = Drug::new(id, row.name.clone(), row.price, row.quantity);
+        let change = match existing {
+            Some(drug) if drug.name != row.name => conflict(format!("drug #{} is called {}, not {}", id, drug.name, row.name)),
+            Some(drug) if drug.price == row.price && drug.quantity == row.quantity => Change::Unchanged(drug.clone()),
+            Some(drug) => Change::Update { before: drug.clone(), after },
+            None => match by_name {
+                Some(other) => conflict(format!("{} already exists as drug #{}", row.name, other.id)),
+                None => {
+                    next_id = next_id.max(id + 1);
+                    Change::Create(after)
+                },
+            },
+        };
+        changes.push(change);
+    }
+    changes
+}
+
+pub fn has_conflicts(changes: &[Change]) -> bool {
+    changes.iter().any(|change| matches!(change, Change::Conflict { .. }))
+}
+
+pub fn inventory_csv(db: &Database) -> String {
+    let mut csv = format!("{}\n", INVENTORY_HEADER);
+    for drug in db.drugs_data.as_ref().into_iter().flat_map(|drugs| drugs.iter()) {
+        csv.push_str(&format!("{},{},{},{}\n", drug.id, quote(&drug.name), drug.price, drug.quantity));
+    }
+    csv
+}
+
+// one line per drug in a group, drugs that no longer exist keep an empty name
+pub fn groups_csv(db: &Database) -> String {
+    let mut csv = format!("{}\n", GROUPS_HEADER);
+    let drugs = db.drugs_data.as_deref();
+    for group in db.drug_gps.as_ref().into_iter().flat_map(|groups| groups.iter()) {
+        for id in group.drugs.iter() {
+            let name = drugs.and_then(|drugs| drugs.get_drug_by_id(*id)).map_or(String::new(), |drug| drug.name.clone());
+            csv.push_str(&format!("{},{},{}\n", quote(&group.name), id, quote(&name)));
+        }
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::linked_list::LinkedList;
+    use crate::db::entities::DrugGP;
+    use crate::db::repository::Repository;
+
+    fn database() -> Database {
+        let mut db = Database::new();
+        db.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        db.drugs_mut().insert(Drug::new(1, "Ibuprofen".to_string(), 12.5, 10)).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_parse() {
+        let rows = parse("id,name,price,quantity\r\n0,Aspirin,30,45\r\n\n,\"Cough syrup, \"\"night\"\"\",8.5,12\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], DrugRow { line: 2, id: Some(0), name: "Aspirin".to_string(), price: 30.0, quantity: 45 });
+        assert_eq!(rows[1].id, None);
+        assert_eq!(rows[1].name, "Cough syrup, \"night\"");
+        assert_eq!(rows[1].line, 4);
+
+        assert_eq!(parse("name,price\n").unwrap_err().line, 1);
+        assert_eq!(parse("id,name,price,quantity\n0,Aspirin,-1,4\n").unwrap_err().line, 2);
+        assert_eq!(parse("id,name,price,quantity\n0,Aspirin,1\n").unwrap_err().reason, "expected 4 fields, found 3");
+        assert!(parse("id,name,price,quantity\nx,Aspirin,1,1\n").is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        let db = database();
+        let rows = parse("id,name,price,quantity\n0,Aspirin,30,45\n1,Ibuprofen,12.5,10\n,Paracetamol,5,100\n7,Morphine,90,3\n1,Codeine,4,4\n,Aspirin,1,1\n3,Ibuprofen,1,1\n").unwrap();
+        let changes = plan(&db, &rows);
+        assert!(matches!(&changes[0], Change::Update { before, after } if before.quantity == 50 && after.quantity == 45));
+        assert!(matches!(&changes[1], Change::Unchanged(drug) if drug.id == 1));
+        assert_eq!(changes[2], Change::Create(Drug::new(2, "Paracetamol".to_string(), 5.0, 100)));
+        assert_eq!(changes[3], Change::Create(Drug::new(7, "Morphine".to_string(), 90.0, 3)));
+        assert_eq!(changes[4], Change::Conflict { line: 6, reason: "drug #1 is already on line 3".to_string() });
+        assert_eq!(changes[5], Change::Conflict { line: 7, reason: "drug #0 is already on line 2".to_string() });
+        assert_eq!(changes[6], Change::Conflict { line: 8, reason: "Ibuprofen is already on line 3".to_string() });
+        assert!(has_conflicts(&changes));
+
+        let changes = plan(&db, &parse("id,name,price,quantity\n1,Codeine,4,4\n5,Aspirin,1,1\n").unwrap());
+        assert_eq!(changes[0], Change::Conflict { line: 2, reason: "drug #1 is called Ibuprofen, not Codeine".to_string() });
+        assert_eq!(changes[1], Change::Conflict { line: 3, reason: "Aspirin already exists as drug #0".to_string() });
+
+        // ids given further down aren't handed out to rows without one
+        let changes = plan(&db, &parse("id,name,price,quantity\n,Paracetamol,5,100\n2,Morphine,90,3\n,Codeine,4,4\n3,Syrup,8,2\n").unwrap());
+        assert_eq!(changes, vec![
+            Change::Create(Drug::new(4, "Paracetamol".to_string(), 5.0, 100)),
+            Change::Create(Drug::new(2, "Morphine".to_string(), 90.0, 3)),
+            Change::Create(Drug::new(5, "Codeine".to_string(), 4.0, 4)),
+            Change::Create(Drug::new(3, "Syrup".to_string(), 8.0, 2)),
+        ]);
+        assert!(!has_conflicts(&changes));
+    }
+
+    #[test]
+    fn test_export() {
+        let mut db = database();
+        db.drugs_mut().insert(Drug::new(2, "Syrup, night".to_string(), 8.5, 12)).unwrap();
+        db.drug_groups_mut().insert(DrugGP { name: "Painkillers".to_string(), drugs: { let mut drugs = LinkedList::new(); drugs.insert(1); drugs.insert(9); drugs } }).unwrap();
+
+        let inventory = inventory_csv(&db);
+        assert_eq!(inventory, "id,name,price,quantity\n0,Aspirin,32.99,50\n1,Ibuprofen,12.5,10\n2,\"Syrup, night\",8.5,12\n");
+        assert!(plan(&db, &parse(&inventory).unwrap()).iter().all(|change| matches!(change, Change::Unchanged(_))));
+        assert_eq!(groups_csv(&db), "group,drug_id,drug_name\nPainkillers,9,\nPainkillers,1,Ibuprofen\n");
+    }
+}
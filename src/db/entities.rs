@@ -5,11 +5,16 @@ use std::fmt::Debug;
 use crate::data_structures::linked_list::LinkedList;
 use crate::data_structures::priority_queue::PriorityQueue;
 use crate::data_structures::stack::Stack;
-use crate::sha_hasher::Sha256;
-use hex;
+use crate::db::index::{IndexSpec, SecondaryIndexes};
+use crate::field_cipher::FieldKey;
+use crate::password_hasher;
+use crate::totp;
 
 
 pub trait UniqueAttribute {
+    // what a record is called in errors
+    const ENTITY: &'static str = "record";
+
     fn uattr(&self) -> String;
 }
 
@@ -23,14 +28,42 @@ pub enum Role {
     Admin,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AccountStatus {
+    Active,
+    PendingApproval,
+    Locked,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserPii {
+    full_name: String,
+    ssn: String,
+    age: u32,
+    contact: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     pub username: String,
     password: String,
+    // personal details only exist in memory, on disk they live in sealed_pii
+    #[serde(skip)]
     pub full_name: String,
+    #[serde(skip)]
     pub ssn: String,
+    #[serde(skip)]
     pub age: u32,
+    #[serde(skip)]
+    pub contact: String,
+    sealed_pii: Vec<u8>,
     pub role: Role,
+    pub status: AccountStatus,
+    pub failed_logins: u32,
+    pub last_failed_login: Option<i64>,
+    pub totp_secret: Option<String>,
+    pub totp_last_step: u64,
+    pub recovery_codes: LinkedList<String>,
 }
 
 impl Ord for User {
@@ -54,11 +87,21 @@ impl PartialEq for User {
 impl Eq for User {}
 
 impl UniqueAttribute for User {
+    const ENTITY: &'static str = "user";
+
     fn uattr(&self) -> String {
         self.username.clone()
     }
 }
 
+// full names are matched ignoring case
+impl SecondaryIndexes for User {
+    const INDEXES: &'static [IndexSpec<Self>] = &[
+        IndexSpec { name: "ssn", values: |user| vec![user.ssn.clone()], unique: false },
+        IndexSpec { name: "full name", values: |user| vec![user.full_name.to_lowercase()], unique: false },
+    ];
+}
+
 impl User {
     pub fn new(username: String, password: String, full_name: String, ssn: String, age: u32, role: Role) -> Self {
         User {
@@ -67,16 +110,126 @@ impl User {
             full_name,
             ssn,
             age,
+            contact: String::new(),
             role,
+            status: AccountStatus::Active,
+            failed_logins: 0,
+            last_failed_login: None,
+            totp_secret: None,
+            totp_last_step: 0,
+            recovery_codes: LinkedList::new(),
+            sealed_pii: Vec::new(),
         }
     }
 
     pub fn verify_password(&self, password: String) -> bool {
-        let mut hasher = Sha256::new();
-        hasher.update(password.as_bytes());
-        let password = hex::encode(hasher.finalize());
-        
-        self.password == password
+        password_hasher::verify_password(&password, &self.password)
+    }
+
+    pub fn password_needs_rehash(&self, iterations: u32) -> bool {
+        password_hasher::needs_rehash(&self.password, iterations)
+    }
+
+    pub fn record_failed_login(&mut self, now: i64, max_failed_logins: u32) {
+        self.failed_logins += 1;
+        self.last_failed_login = Some(now);
+        if self.failed_logins >= max_failed_logins && self.status == AccountStatus::Active {
+            self.status = AccountStatus::Locked;
+        }
+    }
+
+    pub fn unlock(&mut self) {
+        self.status = AccountStatus::Active;
+        self.failed_logins = 0;
+        self.last_failed_login = None;
+    }
+
+    pub fn totp_enabled(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+
+    pub fn enable_totp(&mut self, secret: String, recovery_codes: &[String]) {
+        self.totp_secret = Some(secret);
+        self.totp_last_step = 0;
+        self.set_recovery_codes(recovery_codes);
+    }
+
+    pub fn disable_totp(&mut self) {
+        self.totp_secret = None;
+        self.recovery_codes = LinkedList::new();
+    }
+
+    pub fn set_recovery_codes(&mut self, recovery_codes: &[String]) {
+        self.recovery_codes = LinkedList::new();
+        for code in recovery_codes {
+            self.recovery_codes.insert(totp::hash_recovery_code(code));
+        }
+    }
+
+    // accepts a current TOTP code once, or burns one of the recovery codes
+    pub fn verify_second_factor(&mut self, code: &str, unix_time: u64) -> bool {
+        if let Some(ref secret) = self.totp_secret {
+            if let Some(step) = totp::verify(secret, code, unix_time) {
+                if step <= self.totp_last_step {
+                    return false;
+                }
+                self.totp_last_step = step;
+                return true;
+            }
+        }
+        self.recovery_codes.remove(&totp::hash_recovery_code(code))
+    }
+
+    fn pii_bytes(&self) -> Vec<u8> {
+        let pii = UserPii {
+            full_name: self.full_name.clone(),
+            ssn: self.ssn.clone(),
+            age: self.age,
+            contact: self.contact.clone(),
+        };
+        bincode::serialize(&pii).unwrap()
+    }
+
+    pub fn seal_pii(&mut self, key: &FieldKey) {
+        self.sealed_pii = key.seal(&self.pii_bytes());
+    }
+
+    pub fn open_pii(&mut self, key: &FieldKey) -> bool {
+        let pii = match key.open(&self.sealed_pii).and_then(|plain| bincode::deserialize::<UserPii>(&plain).ok()) {
+            Some(pii) => pii,
+            None => return false,
+        };
+        self.full_name = pii.full_name;
+        self.ssn = pii.ssn;
+        self.age = pii.age;
+        self.contact = pii.contact;
+        true
+    }
+
+    // databases that never had a field key keep the details unsealed, see format::v0_to_v1
+    pub fn open_unsealed_pii(&mut self) -> bool {
+        let pii = match bincode::deserialize::<UserPii>(&self.sealed_pii) {
+            Ok(pii) => pii,
+            Err(_) => return false,
+        };
+        self.full_name = pii.full_name;
+        self.ssn = pii.ssn;
+        self.age = pii.age;
+        self.contact = pii.contact;
+        true
+    }
+
+    pub fn set_password(&mut self, password: String, iterations: u32) {
+        self.password = password_hasher::hash_password(&password, iterations);
+    }
+
+    // the stored hash as is, exports carry it over without knowing the password
+    pub fn password_hash(&self) -> &str {
+        &self.password
+    }
+
+    pub fn set_password_hash(&mut self, hash: String) {
+        self.password = hash;
     }
 }
 
@@ -87,23 +240,59 @@ pub struct Clinic {
 }
 
 impl UniqueAttribute for Clinic {
+    const ENTITY: &'static str = "clinic";
+
     fn uattr(&self) -> String {
         self.name.clone()
     }
 }
 
+// clinics, prescriptions, drug groups and ambulances are only ever looked up by key
+impl SecondaryIndexes for Clinic {
+    const INDEXES: &'static [IndexSpec<Self>] = &[];
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DoctorsList {
     pub doctor: String,
     pub patients: PriorityQueue<Patient>,
 }
 
+impl Ord for DoctorsList {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.doctor.cmp(&other.doctor)
+    }
+}
+
+impl PartialOrd for DoctorsList {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for DoctorsList {
+    fn eq(&self, other: &Self) -> bool {
+        self.doctor == other.doctor
+    }
+}
+
+impl Eq for DoctorsList {}
+
 impl UniqueAttribute for DoctorsList {
+    const ENTITY: &'static str = "doctors list";
+
     fn uattr(&self) -> String {
         self.doctor.clone()
     }
 }
 
+// the doctors a patient has an appointment with
+impl SecondaryIndexes for DoctorsList {
+    const INDEXES: &'static [IndexSpec<Self>] = &[
+        IndexSpec { name: "patient", values: |list| list.patients.iter().map(|patient| patient.name.clone()).collect(), unique: false },
+    ];
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Patient {
     pub name: String,
@@ -111,6 +300,8 @@ pub struct Patient {
 }
 
 impl UniqueAttribute for Patient {
+    const ENTITY: &'static str = "patient";
+
     fn uattr(&self) -> String {
         self.name.clone()
     }
@@ -144,11 +335,17 @@ pub struct Prescription {
 }
 
 impl UniqueAttribute for Prescription {
+    const ENTITY: &'static str = "prescription";
+
     fn uattr(&self) -> String {
         self.patient_name.clone()
     }
 }
 
+impl SecondaryIndexes for Prescription {
+    const INDEXES: &'static [IndexSpec<Self>] = &[];
+}
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Drug {
@@ -189,6 +386,22 @@ impl PartialEq for Drug {
 
 impl Eq for Drug {}
 
+// keyed by id, the tree is ordered by it
+impl UniqueAttribute for Drug {
+    const ENTITY: &'static str = "drug";
+
+    fn uattr(&self) -> String {
+        self.id.to_string()
+    }
+}
+
+// no two drugs share a name
+impl SecondaryIndexes for Drug {
+    const INDEXES: &'static [IndexSpec<Self>] = &[
+        IndexSpec { name: "name", values: |drug| vec![drug.name.clone()], unique: true },
+    ];
+}
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DrugGP {
@@ -197,11 +410,17 @@ pub struct DrugGP {
 }
 
 impl UniqueAttribute for DrugGP {
+    const ENTITY: &'static str = "drug group";
+
     fn uattr(&self) -> String {
         self.name.clone()
     }
 }
 
+impl SecondaryIndexes for DrugGP {
+    const INDEXES: &'static [IndexSpec<Self>] = &[];
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Ambulance {
     pub name: String,
@@ -220,7 +439,13 @@ impl Ambulance {
 }
 
 impl UniqueAttribute for Ambulance {
+    const ENTITY: &'static str = "ambulance";
+
     fn uattr(&self) -> String {
         self.name.clone()
     }
 }
+
+impl SecondaryIndexes for Ambulance {
+    const INDEXES: &'static [IndexSpec<Self>] = &[];
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::io;
+
+
+#[derive(Debug)]
+pub enum DbError {
+    NotFound { entity: &'static str, key: String },
+    Duplicate { entity: &'static str, key: String },
+    // a delete refused by a restrict rule, see db::relations
+    InUse { entity: &'static str, key: String, dependents: Vec<String> },
+    // the file or journal can't be decoded, or was written by a build this one can't read
+    Corrupt(String),
+    // the data decodes but contradicts itself, e.g. a sealed field fails its tag check
+    Integrity(String),
+    // the request can't be carried out as asked, nothing stored is wrong
+    InvalidInput(String),
+    Locked,
+    WrongPassphrase,
+    Io(io::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NotFound { entity, key } => write!(f, "No {} named '{}'", entity, key),
+            DbError::Duplicate { entity, key } => write!(f, "A {} named '{}' already exists", entity, key),
+            DbError::InUse { entity, key, dependents } => write!(f, "Can't delete {} '{}', it is still referenced by: {}", entity, key, dependents.join(", ")),
+            DbError::Corrupt(reason) => write!(f, "Database is corrupt: {}", reason),
+            DbError::Integrity(reason) => write!(f, "Integrity violation: {}", reason),
+            DbError::InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
+            DbError::Locked => write!(f, "Database is locked, unlock it with the passphrase first"),
+            DbError::WrongPassphrase => write!(f, "Wrong database passphrase"),
+            DbError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+impl DbError {
+    pub fn is_missing_file(&self) -> bool {
+        matches!(self, DbError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+}
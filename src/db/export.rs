@@ -0,0 +1,529 @@
+use std::fmt::Debug;
+use chrono::Local;
+use serde::{Serialize, Deserialize};
+
+use crate::data_structures::hash_map::HashMap;
+use crate::data_structures::linked_list::LinkedList;
+use crate::data_structures::map::{LocationType, Object};
+use crate::data_structures::max_heap::max_heap_size;
+use crate::data_structures::priority_queue::PriorityQueue;
+use crate::data_structures::stack::Stack;
+use crate::db::audit::{self, AuditEvent};
+use crate::db::db_handler::Database;
+use crate::db::entities::{AccountStatus, Ambulance, Clinic, DoctorsList, Drug, DrugGP, Patient, Prescription, Role, UniqueAttribute, User};
+use crate::db::error::DbError;
+use crate::db::format::FORMAT_VERSION;
+use crate::db::repository::Repository;
+use crate::permissions::{Permission, RolePermissions};
+
+
+// the whole database as plain lists instead of trees, heaps and linked lists, so it can be read, diffed and fixed by hand
+// personal details are written in the clear, the file has to be kept as safe as the passphrase
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Export {
+    pub format_version: u32,
+    pub exported_at: String,
+    pub users: Vec<UserRecord>,
+    pub clinics: Vec<ClinicRecord>,
+    pub queues: Vec<QueueRecord>,
+    pub prescriptions: Vec<PrescriptionRecord>,
+    pub drugs: Vec<Drug>,
+    pub drug_groups: Vec<DrugGroupRecord>,
+    pub locations: Vec<LocationRecord>,
+    pub ambulances: Vec<Ambulance>,
+    pub role_permissions: Vec<RolePermissionsRecord>,
+    // oldest first
+    pub audit_log: Vec<AuditEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserRecord {
+    pub username: String,
+    pub password_hash: String,
+    pub full_name: String,
+    pub ssn: String,
+    pub age: u32,
+    pub contact: String,
+    pub role: Role,
+    pub status: AccountStatus,
+    pub failed_logins: u32,
+    pub last_failed_login: Option<i64>,
+    pub totp_secret: Option<String>,
+    pub totp_last_step: u64,
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClinicRecord {
+    pub name: String,
+    pub doctors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueueRecord {
+    pub doctor: String,
+    pub patients: Vec<Patient>,
+}
+
+// medications in the order they are dispensed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrescriptionRecord {
+    pub patient_name: String,
+    pub medications: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrugGroupRecord {
+    pub name: String,
+    pub drugs: Vec<u32>,
+}
+
+// routes are one way, from this location to the ones listed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocationRecord {
+    pub name: String,
+    pub location_type: LocationType,
+    pub objects: Vec<String>,
+    pub routes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RolePermissionsRecord {
+    pub role: Role,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    // the database must not hold anything yet, the audit log is taken over as well
+    Empty,
+    // records are added next to the existing ones, any clash is a conflict and the audit log is left out
+    Merge,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub records: usize,
+    pub skipped_audit_events: usize,
+}
+
+// iterating the result gives the items in the order they were passed in
+fn list_from<T: Debug>(items: Vec<T>) -> LinkedList<T> {
+    let mut list = LinkedList::new();
+    for item in items.into_iter().rev() {
+        list.push_front(item);
+    }
+    list
+}
+
+fn stack_from<T>(items: Vec<T>) -> Stack<T> {
+    let mut stack = Stack::new();
+    for item in items.into_iter().rev() {
+        stack.push(item);
+    }
+    stack
+}
+
+fn in_list<T: UniqueAttribute + Debug>(list: &Option<LinkedList<T>>, key: &str) -> bool {
+    list.as_ref().is_some_and(|list| list.iter().any(|item| item.uattr() == key))
+}
+
+// records every key once, reports the ones seen before or already in the database
+fn check_unique<I: Iterator<Item = String>>(entity: &str, keys: I, existing: impl Fn(&str) -> bool, problems: &mut Vec<String>) {
+    let mut seen = HashMap::new();
+    for key in keys {
+        if key.is_empty() {
+            problems.push(format!("a {} has an empty name", entity));
+        } else if seen.contains_key(&key) {
+            problems.push(format!("{} '{}' appears more than once", entity, key));
+        } else if existing(&key) {
+            problems.push(format!("{} '{}' already exists in the database", entity, key));
+        }
+        seen.insert(key, ());
+    }
+}
+
+impl Export {
+    // expects an unlocked database, otherwise the personal details are empty
+    pub fn from_database(db: &Database) -> Self {
+        let users = db.users_data.as_ref().map_or(Vec::new(), |users| users.iter().map(|user| UserRecord {
+            username: user.username.clone(),
+            password_hash: user.password_hash().to_string(),
+            full_name: user.full_name.clone(),
+            ssn: user.ssn.clone(),
+            age: user.age,
+            contact: user.contact.clone(),
+            role: user.role.clone(),
+            status: user.status.clone(),
+            failed_logins: user.failed_logins,
+            last_failed_login: user.last_failed_login,
+            totp_secret: user.totp_secret.clone(),
+            totp_last_step: user.totp_last_step,
+            recovery_codes: user.recovery_codes.iter().cloned().collect(),
+        }).collect());
+
+        let clinics = db.clinics_data.as_ref().map_or(Vec::new(), |clinics| clinics.iter().map(|clinic| ClinicRecord {
+            name: clinic.name.clone(),
+            doctors: clinic.doctors.iter().cloned().collect(),
+        }).collect());
+
+        let queues = db.doctors_data.as_ref().map_or(Vec::new(), |doctors| doctors.iter().map(|list| {
+            let mut patients = list.patients.iter().cloned().collect::<Vec<Patient>>();
+            patients.sort_by_key(|patient| patient.priority);
+            QueueRecord { doctor: list.doctor.clone(), patients }
+        }).collect());
+
+        let prescriptions = db.prescriptions_data.as_ref().map_or(Vec::new(), |prescriptions| prescriptions.iter().map(|prescription| PrescriptionRecord {
+            patient_name: prescription.patient_name.clone(),
+            medications: prescription.medications.iter().cloned().collect(),
+        }).collect());
+
+        let drug_groups = db.drug_gps.as_ref().map_or(Vec::new(), |groups| groups.iter().map(|group| DrugGroupRecord {
+            name: group.name.clone(),
+            drugs: group.drugs.iter().cloned().collect(),
+        }).collect());
+
+        let mut locations = db.map().nodes.iter().map(|(name, node)| LocationRecord {
+            name: name.clone(),
+            location_type: node.location_type.clone(),
+            objects: node.objects.iter().map(|object| object.name.clone()).collect(),
+            routes: db.map().edges.get(name).map_or(Vec::new(), |routes| routes.iter().cloned().collect()),
+        }).collect::<Vec<_>>();
+        locations.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Export {
+            format_version: FORMAT_VERSION,
+            exported_at: Local::now().to_rfc3339(),
+            users,
+            clinics,
+            queues,
+            prescriptions,
+            drugs: db.drugs_data.as_ref().map_or(Vec::new(), |drugs| drugs.iter().cloned().collect()),
+            drug_groups,
+            locations,
+            ambulances: db.ambulances_data.as_ref().map_or(Vec::new(), |ambulances| ambulances.iter().cloned().collect()),
+            role_permissions: db.role_permissions.as_ref().map_or(Vec::new(), |entries| entries.iter().map(|entry| RolePermissionsRecord {
+                role: entry.role.clone(),
+                permissions: entry.permissions.iter().cloned().collect(),
+            }).collect()),
+            audit_log: db.audit_chain_oldest_first().into_iter().cloned().collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, DbError> {
+        serde_json::from_str(json).map_err(|e| DbError::Corrupt(format!("not a database export: {}", e)))
+    }
+
+    // everything that would make the import fail or leave dangling references, empty when it's safe to apply
+    pub fn validate(&self, db: &Database, mode: ImportMode) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.format_version != FORMAT_VERSION {
+            problems.push(format!("exported from format {}, this build imports format {}", self.format_version, FORMAT_VERSION));
+            return problems;
+        }
+        if mode == ImportMode::Empty && !db.is_empty() {
+            problems.push("the database already holds data, import with --merge to add to it".to_string());
+            return problems;
+        }
+
+        check_unique("user", self.users.iter().map(|user| user.username.clone()), |key| db.users().contains(key), &mut problems);
+        let mut roles = HashMap::new();
+        if let Some(ref users) = *db.users_data {
+            for user in users.iter() {
+                roles.insert(user.username.clone(), user.role.clone());
+            }
+        }
+        for user in self.users.iter() {
+            if user.password_hash.is_empty() {
+                problems.push(format!("user '{}' has no password hash", user.username));
+            }
+            roles.insert(user.username.clone(), user.role.clone());
+        }
+        let mut expect_role = |username: &str, expected: &[Role], context: String| {
+            match roles.get(username) {
+                Some(found) if expected.contains(found) => {}
+                Some(found) => {
+                    let expected = expected.iter().map(|role| format!("{:?}", role)).collect::<Vec<_>>().join(" or ");
+                    problems.push(format!("{}: '{}' is a {:?}, not a {}", context, username, found, expected));
+                }
+                None => problems.push(format!("{}: no user '{}'", context, username)),
+            }
+        };
+
+        // emergency doctors work in clinics and take patients like any other doctor, see fsck::is_doctor
+        let doctors = [Role::Doctor, Role::EmergencyDoctor];
+        for clinic in self.clinics.iter() {
+            for doctor in clinic.doctors.iter() {
+                expect_role(doctor, &doctors, format!("clinic '{}'", clinic.name));
+            }
+        }
+        for queue in self.queues.iter() {
+            expect_role(&queue.doctor, &doctors, format!("queue of '{}'", queue.doctor));
+            for patient in queue.patients.iter() {
+                expect_role(&patient.name, &[Role::Patient], format!("queue of '{}'", queue.doctor));
+            }
+        }
+        for prescription in self.prescriptions.iter() {
+            expect_role(&prescription.patient_name, &[Role::Patient], "prescription".to_string());
+        }
+
+        check_unique("clinic", self.clinics.iter().map(|clinic| clinic.name.clone()), |key| in_list(&db.clinics_data, key), &mut problems);
+        check_unique("queue", self.queues.iter().map(|queue| queue.doctor.clone()), |key| db.doctors_lists().contains(key), &mut problems);
+        for queue in self.queues.iter() {
+            check_unique("patient in the queue", queue.patients.iter().map(|patient| patient.name.clone()), |_| false, &mut problems);
+            if queue.patients.len() > max_heap_size() {
+                problems.push(format!("queue of '{}' holds {} patients, the limit is {}", queue.doctor, queue.patients.len(), max_heap_size()));
+            }
+        }
+        // a patient has one pending prescription, more medications go on top of it
+        check_unique("prescription", self.prescriptions.iter().map(|prescription| prescription.patient_name.clone()), |key| in_list(&db.prescriptions_data, key), &mut problems);
+        for prescription in self.prescriptions.iter().filter(|prescription| prescription.medications.is_empty()) {
+            problems.push(format!("prescription for '{}' has no medications", prescription.patient_name));
+        }
+
+        let drugs = db.drugs_data.as_ref();
+        check_unique("drug id", self.drugs.iter().map(|drug| drug.id.to_string()), |key| drugs.is_some_and(|drugs| drugs.iter().any(|drug| drug.id.to_string() == key)), &mut problems);
+        check_unique("drug", self.drugs.iter().map(|drug| drug.name.clone()), |key| db.find_drug_by_name(key).is_some(), &mut problems);
+        for drug in self.drugs.iter().filter(|drug| !drug.price.is_finite() || drug.price < 0.0) {
+            problems.push(format!("drug '{}' has an invalid price {}", drug.name, drug.price));
+        }
+        check_unique("drug group", self.drug_groups.iter().map(|group| group.name.clone()), |key| in_list(&db.drug_gps, key), &mut problems);
+        for group in self.drug_groups.iter() {
+            for id in group.drugs.iter() {
+                let known = self.drugs.iter().any(|drug| drug.id == *id) || drugs.is_some_and(|drugs| drugs.get_drug_by_id(*id).is_some());
+                if !known {
+                    problems.push(format!("drug group '{}': no drug with id {}", group.name, id));
+                }
+            }
+        }
+
+        check_unique("location", self.locations.iter().map(|location| location.name.clone()), |key| db.map().nodes.contains_key(key), &mut problems);
+        let known_location = |name: &str| self.locations.iter().any(|location| location.name == name) || db.map().nodes.contains_key(name);
+        for location in self.locations.iter() {
+            for route in location.routes.iter().filter(|route| !known_location(route)) {
+                problems.push(format!("route from '{}' leads to unknown location '{}'", location.name, route));
+            }
+        }
+        check_unique("ambulance", self.ambulances.iter().map(|ambulance| ambulance.name.clone()), |key| in_list(&db.ambulances_data, key), &mut problems);
+        for ambulance in self.ambulances.iter() {
+            for place in [&ambulance.hospital, &ambulance.location] {
+                if !known_location(place) {
+                    problems.push(format!("ambulance '{}' refers to unknown location '{}'", ambulance.name, place));
+                }
+            }
+        }
+
+        check_unique("role permissions for", self.role_permissions.iter().map(|entry| format!("{:?}", entry.role)), |_| false, &mut problems);
+        for entry in self.role_permissions.iter() {
+            let stored = db.role_permissions.as_ref().and_then(|entries| entries.iter().find(|stored| stored.role == entry.role));
+            if stored.is_some_and(|stored| stored.permissions.iter().cloned().collect::<Vec<_>>() != entry.permissions) {
+                problems.push(format!("permissions for {:?} differ from the ones already in the database", entry.role));
+            }
+        }
+
+        if mode == ImportMode::Empty {
+            if let Err(e) = audit::verify_chain(self.audit_log.iter()) {
+                problems.push(format!("audit log: {}", e));
+            }
+        }
+        problems
+    }
+
+    // validates first, nothing is changed unless the whole export can be applied
+    pub fn apply(self, db: &mut Database, mode: ImportMode) -> Result<ImportSummary, DbError> {
+        let problems = self.validate(db, mode);
+        if !problems.is_empty() {
+            return Err(DbError::Integrity(problems.join("; ")));
+        }
+        let mut summary = ImportSummary::default();
+
+        for record in self.users {
+            let mut user = User::new(record.username, String::new(), record.full_name, record.ssn, record.age, record.role);
+            user.set_password_hash(record.password_hash);
+            user.contact = record.contact;
+            user.status = record.status;
+            user.failed_logins = record.failed_logins;
+            user.last_failed_login = record.last_failed_login;
+            user.totp_secret = record.totp_secret;
+            user.totp_last_step = record.totp_last_step;
+            user.recovery_codes = list_from(record.recovery_codes);
+            db.users_mut().insert(user)?;
+            summary.records += 1;
+        }
+        // exports list users and drugs in order, which would leave the trees as long chains
+        db.users_data.change_all(|users| if let Some(users) = users {
+            users.balance();
+        });
+        // the lists take new records at the front, going backwards keeps the exported order
+        for record in self.clinics.into_iter().rev() {
+            db.clinics_mut().insert(Clinic { name: record.name, doctors: list_from(record.doctors) })?;
+            summary.records += 1;
+        }
+        for record in self.queues.into_iter().rev() {
+            let mut patients = PriorityQueue::new();
+            for patient in record.patients {
+                patients.insert(patient);
+            }
+            db.doctors_lists_mut().insert(DoctorsList { doctor: record.doctor, patients })?;
+            summary.records += 1;
+        }
+        for record in self.prescriptions.into_iter().rev() {
+            db.prescriptions_mut().insert(Prescription { patient_name: record.patient_name, medications: stack_from(record.medications) })?;
+            summary.records += 1;
+        }
+        for drug in self.drugs {
+            db.drugs_mut().insert(drug)?;
+            summary.records += 1;
+        }
+        db.drugs_data.change_all(|drugs| if let Some(drugs) = drugs {
+            drugs.balance();
+        });
+        for record in self.drug_groups.into_iter().rev() {
+            db.drug_groups_mut().insert(DrugGP { name: record.name, drugs: list_from(record.drugs) })?;
+            summary.records += 1;
+        }
+
+        // every location has to exist before routes can point at it
+        for location in self.locations.iter() {
+            db.map_mut().add_node(location.name.clone(), location.location_type.clone());
+            for object in location.objects.iter().rev() {
+                db.map_mut().add_object_to_node(&location.name, Object { name: object.clone() });
+            }
+            summary.records += 1;
+        }
+        for location in self.locations {
+            for route in location.routes.into_iter().rev() {
+                db.map_mut().add_edge(location.name.clone(), route);
+            }
+        }
+        for ambulance in self.ambulances.into_iter().rev() {
+            db.ambulances_mut().insert(ambulance)?;
+            summary.records += 1;
+        }
+        // roles that already have permissions here keep them
+        for record in self.role_permissions.into_iter().rev() {
+            if db.role_permissions.insert(RolePermissions { role: record.role, permissions: list_from(record.permissions) }).is_ok() {
+                summary.records += 1;
+            }
+        }
+
+        match mode {
+            ImportMode::Empty => {
+                for event in self.audit_log {
+                    db.next_audit_id = event.id + 1;
+                    db.audit_log.push_front(event);
+                }
+            },
+            ImportMode::Merge => summary.skipped_audit_events = self.audit_log.len(),
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::map::LocationType;
+
+    fn user(username: &str, full_name: &str, ssn: &str, role: Role) -> User {
+        let mut user = User::new(username.to_string(), String::new(), full_name.to_string(), ssn.to_string(), 40, role);
+        user.set_password("pw".to_string(), 1000);
+        user
+    }
+
+    fn sample_database() -> Database {
+        let mut db = Database::new();
+        db.users_mut().insert(user("doc1", "Doc One", "111", Role::Doctor)).unwrap();
+        db.users_mut().insert(user("er1", "Er One", "333", Role::EmergencyDoctor)).unwrap();
+        db.users_mut().insert(user("patient1", "Pat One", "222", Role::Patient)).unwrap();
+        db.clinics_mut().insert(Clinic { name: "Heart".to_string(), doctors: list_from(vec!["doc1".to_string(), "er1".to_string()]) }).unwrap();
+        for doctor in ["er1", "doc1"] {
+            let mut patients = PriorityQueue::new();
+            patients.insert(Patient { name: "patient1".to_string(), priority: 2 });
+            db.doctors_lists_mut().insert(DoctorsList { doctor: doctor.to_string(), patients }).unwrap();
+        }
+        db.prescriptions_mut().insert(Prescription { patient_name: "patient1".to_string(), medications: stack_from(vec!["Aspirin".to_string(), "Ibuprofen".to_string()]) }).unwrap();
+        db.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        db.drugs_mut().insert(Drug::new(1, "Ibuprofen".to_string(), 12.5, 10)).unwrap();
+        db.drug_groups_mut().insert(DrugGP { name: "Painkillers".to_string(), drugs: list_from(vec![0, 1]) }).unwrap();
+        db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+        db.map_mut().add_node("Home A".to_string(), LocationType::Home);
+        db.map_mut().add_edge("Hospital A".to_string(), "Home A".to_string());
+        db.map_mut().add_object_to_node("Hospital A", Object { name: "Ambulance A".to_string() });
+        db.ambulances_mut().insert(Ambulance::new("Ambulance A".to_string(), "Hospital A".to_string(), "Hospital A".to_string())).unwrap();
+        db.get_role_permissions(Role::Doctor);
+        db.record_event(AuditEvent::new("system".to_string(), None, audit::AuditAction::Create, audit::EntityType::User, "doc1".to_string()));
+        db
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let db = sample_database();
+        let json = Export::from_database(&db).to_json();
+        assert!(json.contains("\"ssn\": \"222\""));
+
+        let mut imported = Database::new();
+        let summary = Export::from_json(&json).unwrap().apply(&mut imported, ImportMode::Empty).unwrap();
+        assert_eq!(summary.skipped_audit_events, 0);
+        assert_eq!(imported.users().get("patient1").unwrap().full_name, "Pat One");
+        assert!(imported.users().get("doc1").unwrap().verify_password("pw".to_string()));
+        assert_eq!(imported.users().get("er1").unwrap().role, Role::EmergencyDoctor);
+        assert!(imported.clinics().get("Heart").unwrap().doctors.contains(&"er1".to_string()));
+        assert_eq!(imported.doctors_lists().get("er1").unwrap().patients.len(), 1);
+        assert_eq!(imported.prescriptions().get("patient1").unwrap().medications.peek().unwrap(), "Aspirin");
+        assert_eq!(imported.map().shortest_path("Hospital A", "Home A").unwrap().len(), 2);
+        assert_eq!(imported.next_audit_id, db.next_audit_id);
+        assert!(imported.verify_audit_chain().unwrap().is_some());
+
+        // exporting again gives the same document apart from the time
+        let mut again = Export::from_database(&imported);
+        again.exported_at = Export::from_json(&json).unwrap().exported_at;
+        assert_eq!(again.to_json(), json);
+    }
+
+    #[test]
+    fn test_validation() {
+        let db = sample_database();
+        let mut export = Export::from_database(&db);
+        assert!(!export.validate(&db, ImportMode::Empty).is_empty());
+        let conflicts = export.validate(&db, ImportMode::Merge);
+        assert!(conflicts.iter().any(|problem| problem == "user 'doc1' already exists in the database"));
+
+        export.drug_groups[0].drugs.push(9);
+        export.queues[0].patients.push(Patient { name: "doc1".to_string(), priority: 1 });
+        export.audit_log[0].entity_key = "someone else".to_string();
+        let problems = export.validate(&Database::new(), ImportMode::Empty);
+        assert!(problems.contains(&"drug group 'Painkillers': no drug with id 9".to_string()));
+        assert!(problems.contains(&"queue of 'doc1': 'doc1' is a Doctor, not a Patient".to_string()));
+        export.clinics[0].doctors.push("patient1".to_string());
+        let second = PrescriptionRecord { patient_name: "patient1".to_string(), medications: vec!["Aspirin".to_string()] };
+        export.prescriptions.push(second);
+        let problems = export.validate(&Database::new(), ImportMode::Empty);
+        assert!(problems.contains(&"clinic 'Heart': 'patient1' is a Patient, not a Doctor or EmergencyDoctor".to_string()));
+        assert!(problems.contains(&"prescription 'patient1' appears more than once".to_string()));
+        assert!(problems.iter().any(|problem| problem.starts_with("audit log:")));
+
+        let mut empty = Database::new();
+        assert!(export.apply(&mut empty, ImportMode::Empty).is_err());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut db = sample_database();
+        let mut other = Database::new();
+        other.users_mut().insert(user("patient2", "Pat Two", "333", Role::Patient)).unwrap();
+        other.prescriptions_mut().insert(Prescription { patient_name: "patient2".to_string(), medications: stack_from(vec!["Aspirin".to_string()]) }).unwrap();
+        other.record_event(AuditEvent::new("system".to_string(), None, audit::AuditAction::Create, audit::EntityType::User, "patient2".to_string()));
+
+        let summary = Export::from_database(&other).apply(&mut db, ImportMode::Merge).unwrap();
+        assert_eq!(summary.records, 2);
+        assert_eq!(summary.skipped_audit_events, 1);
+        assert!(db.users().contains("patient2"));
+        assert_eq!(db.verify_audit_chain().unwrap().unwrap().length, 1);
+    }
+}
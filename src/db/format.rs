@@ -0,0 +1,519 @@
+use std::io;
+use chrono::DateTime;
+use serde::{Serialize, Deserialize};
+
+use crate::data_structures::hash_map::HashMap;
+use crate::data_structures::linked_list::LinkedList;
+use crate::data_structures::map::Graph;
+use crate::db::audit::{AuditAction, AuditEvent, EntityType, GENESIS_HASH};
+use crate::db::error::DbError;
+use crate::db::entities::{AccountStatus, Ambulance, Clinic, DoctorsList, Drug, DrugGP, Prescription, Role};
+use crate::permissions::{Permission, RolePermissions};
+
+
+// file layout: MAGIC, format version (u32 LE), then the bincode encoded Database
+// files without the magic are from before versioning and count as version 0
+const MAGIC: &[u8; 8] = b"HOSPDB\0\0";
+const HEADER_LEN: usize = 12;
+pub const FORMAT_VERSION: u32 = 3;
+
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    // the step leaves users, drugs, audit events and sections as they were, so journals of the old format still replay
+    keeps_journal: bool,
+    upgrade: fn(&[u8]) -> Result<Vec<u8>, DbError>,
+}
+
+// one step per version bump, each step only knows the layouts it converts between
+pub const MIGRATIONS: [Migration; 3] = [
+    Migration {
+        from: 0,
+        description: "add account security fields, keep personal details for sealing, turn the ambulance log into audit events",
+        keeps_journal: false,
+        upgrade: v0_to_v1,
+    },
+    Migration {
+        from: 1,
+        description: "remember which permissions the stored role permissions know about, roles get the defaults of newer ones",
+        keeps_journal: true,
+        upgrade: v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        description: "keep the doctors' queues in a tree ordered by doctor so they are found without a scan",
+        keeps_journal: true,
+        upgrade: v2_to_v3,
+    },
+];
+
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+// the version a file was written with and the payload after the header
+pub fn split(bytes: &[u8]) -> (u32, &[u8]) {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return (0, bytes);
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&bytes[MAGIC.len()..HEADER_LEN]);
+    (u32::from_le_bytes(version), &bytes[HEADER_LEN..])
+}
+
+// the steps that take a file of the given version to the current one
+pub fn pending_migrations(version: u32) -> Result<Vec<&'static Migration>, DbError> {
+    if version > FORMAT_VERSION {
+        return Err(DbError::Corrupt(format!("written by a newer version (format {}), this build reads up to format {}", version, FORMAT_VERSION)));
+    }
+    let mut steps = Vec::new();
+    for from in version..FORMAT_VERSION {
+        match MIGRATIONS.iter().find(|migration| migration.from == from) {
+            Some(migration) => steps.push(migration),
+            None => return Err(DbError::Corrupt(format!("no migration from format {}", from))),
+        }
+    }
+    Ok(steps)
+}
+
+// whether journal frames written with this version can be replayed onto a database of the current one
+pub fn journal_readable(version: u32) -> bool {
+    pending_migrations(version).is_ok_and(|steps| steps.iter().all(|migration| migration.keeps_journal))
+}
+
+// returns the version the file was written with and the payload in the current layout
+pub fn upgrade(bytes: &[u8]) -> Result<(u32, Vec<u8>), DbError> {
+    let (version, payload) = split(bytes);
+    let mut payload = payload.to_vec();
+    for migration in pending_migrations(version)? {
+        payload = (migration.upgrade)(&payload).map_err(|e| match e {
+            DbError::Corrupt(reason) => DbError::Corrupt(format!("migration from format {} failed: {}", migration.from, reason)),
+            e => e,
+        })?;
+    }
+    Ok((version, payload))
+}
+
+fn decode<'a, T: Deserialize<'a>>(version: u32, payload: &'a [u8]) -> Result<T, DbError> {
+    bincode::deserialize(payload).map_err(|e| DbError::Corrupt(format!("not a format {} database: {}", version, e)))
+}
+
+// same encoding as bst::TreeNode, so old trees can be rebuilt with their shape intact
+#[derive(Serialize, Deserialize)]
+struct Node<T> {
+    value: T,
+    left: Option<Box<Node<T>>>,
+    right: Option<Box<Node<T>>>,
+}
+
+impl<T> Node<T> {
+    // a balanced tree of values that are already in order
+    fn balanced(mut values: Vec<T>) -> Option<Node<T>> {
+        if values.is_empty() {
+            return None;
+        }
+        let right = values.split_off(values.len() / 2 + 1);
+        let value = values.pop().unwrap();
+        Some(Node {
+            value,
+            left: Node::balanced(values).map(Box::new),
+            right: Node::balanced(right).map(Box::new),
+        })
+    }
+
+    fn map<U, F: Fn(T) -> U + Copy>(self, f: F) -> Node<U> {
+        Node {
+            value: f(self.value),
+            left: self.left.map(|node| Box::new(node.map(f))),
+            right: self.right.map(|node| Box::new(node.map(f))),
+        }
+    }
+}
+
+// the layouts below are frozen copies, don't change them when the entities change
+#[derive(Serialize, Deserialize)]
+struct UserV0 {
+    username: String,
+    password: String,
+    full_name: String,
+    ssn: String,
+    age: u32,
+    role: Role,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseV0 {
+    users_data: Option<Node<UserV0>>,
+    clinics_data: Option<LinkedList<Clinic>>,
+    doctors_data: Option<LinkedList<DoctorsList>>,
+    prescriptions_data: Option<LinkedList<Prescription>>,
+    drugs_data: Option<Box<Node<Drug>>>,
+    drug_gps: Option<LinkedList<DrugGP>>,
+    map: Graph,
+    ambulances_data: Option<LinkedList<Ambulance>>,
+    logs_data: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PiiV1 {
+    full_name: String,
+    ssn: String,
+    age: u32,
+    contact: String,
+}
+
+// sealed_pii holds the plain PiiV1 bytes as long as the database has no field key
+#[derive(Serialize, Deserialize)]
+struct UserV1 {
+    username: String,
+    password: String,
+    sealed_pii: Vec<u8>,
+    role: Role,
+    status: AccountStatus,
+    failed_logins: u32,
+    last_failed_login: Option<i64>,
+    totp_secret: Option<String>,
+    totp_last_step: u64,
+    recovery_codes: LinkedList<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseV1 {
+    users_data: Option<Node<UserV1>>,
+    clinics_data: Option<LinkedList<Clinic>>,
+    doctors_data: Option<LinkedList<DoctorsList>>,
+    prescriptions_data: Option<LinkedList<Prescription>>,
+    drugs_data: Option<Box<Node<Drug>>>,
+    drug_gps: Option<LinkedList<DrugGP>>,
+    map: Graph,
+    ambulances_data: Option<LinkedList<Ambulance>>,
+    audit_log: LinkedList<AuditEvent>,
+    next_audit_id: u64,
+    role_permissions: Option<LinkedList<RolePermissions>>,
+    field_key_salt: Vec<u8>,
+    field_key_check: String,
+    journal_generation: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseV2 {
+    users_data: Option<Node<UserV1>>,
+    clinics_data: Option<LinkedList<Clinic>>,
+    doctors_data: Option<LinkedList<DoctorsList>>,
+    prescriptions_data: Option<LinkedList<Prescription>>,
+    drugs_data: Option<Box<Node<Drug>>>,
+    drug_gps: Option<LinkedList<DrugGP>>,
+    map: Graph,
+    ambulances_data: Option<LinkedList<Ambulance>>,
+    audit_log: LinkedList<AuditEvent>,
+    next_audit_id: u64,
+    role_permissions: Option<LinkedList<RolePermissions>>,
+    known_permissions: LinkedList<Permission>,
+    field_key_salt: Vec<u8>,
+    field_key_check: String,
+    journal_generation: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseV3 {
+    users_data: Option<Node<UserV1>>,
+    clinics_data: Option<LinkedList<Clinic>>,
+    doctors_data: Option<Node<DoctorsList>>,
+    prescriptions_data: Option<LinkedList<Prescription>>,
+    drugs_data: Option<Box<Node<Drug>>>,
+    drug_gps: Option<LinkedList<DrugGP>>,
+    map: Graph,
+    ambulances_data: Option<LinkedList<Ambulance>>,
+    audit_log: LinkedList<AuditEvent>,
+    next_audit_id: u64,
+    role_permissions: Option<LinkedList<RolePermissions>>,
+    known_permissions: LinkedList<Permission>,
+    field_key_salt: Vec<u8>,
+    field_key_check: String,
+    journal_generation: u64,
+}
+
+fn v0_to_v1(payload: &[u8]) -> Result<Vec<u8>, DbError> {
+    let old: DatabaseV0 = decode(0, payload)?;
+    let users_data = old.users_data.map(|users| users.map(|user| {
+        let pii = PiiV1 { full_name: user.full_name, ssn: user.ssn, age: user.age, contact: String::new() };
+        UserV1 {
+            username: user.username,
+            // legacy sha256 hashes still verify and get upgraded on the next login
+            password: user.password,
+            sealed_pii: bincode::serialize(&pii).unwrap(),
+            role: user.role,
+            status: AccountStatus::Active,
+            failed_logins: 0,
+            last_failed_login: None,
+            totp_secret: None,
+            totp_last_step: 0,
+            recovery_codes: LinkedList::new(),
+        }
+    }));
+
+    // the keys are Local::now() strings, oldest first once sorted
+    let mut logs = old.logs_data.iter().collect::<Vec<_>>();
+    logs.sort();
+    let mut audit_log = LinkedList::new();
+    let mut prev_hash = GENESIS_HASH.to_string();
+    for (index, (time, message)) in logs.into_iter().enumerate() {
+        let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Move, EntityType::Ambulance, String::new());
+        event.id = index as u64 + 1;
+        event.timestamp = DateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f %:z").map_or(0, |time| time.timestamp());
+        event.after = Some(message.clone());
+        event.link(prev_hash);
+        prev_hash = event.hash.clone();
+        audit_log.push_front(event);
+    }
+
+    let new = DatabaseV1 {
+        users_data,
+        clinics_data: old.clinics_data,
+        doctors_data: old.doctors_data,
+        prescriptions_data: old.prescriptions_data,
+        drugs_data: old.drugs_data,
+        drug_gps: old.drug_gps,
+        map: old.map,
+        ambulances_data: old.ambulances_data,
+        next_audit_id: audit_log.len() as u64 + 1,
+        audit_log,
+        role_permissions: None,
+        field_key_salt: Vec::new(),
+        field_key_check: String::new(),
+        journal_generation: 0,
+    };
+    Ok(bincode::serialize(&new).map_err(io::Error::other)?)
+}
+
+fn v1_to_v2(payload: &[u8]) -> Result<Vec<u8>, DbError> {
+    let old: DatabaseV1 = decode(1, payload)?;
+    // the permissions role permissions were first stored with, the rest get their defaults merged in on load
+    let mut known_permissions = LinkedList::new();
+    for permission in [
+        Permission::MakeAppointment,
+        Permission::CancelAppointment,
+        Permission::VisitPatients,
+        Permission::AssignPatients,
+        Permission::DispenseMedications,
+        Permission::ViewDrugs,
+        Permission::ManageDrugs,
+        Permission::ManageDrugGroups,
+        Permission::ViewMap,
+        Permission::ManageMap,
+        Permission::ManageAmbulances,
+        Permission::ViewLogs,
+        Permission::ManageUsers,
+        Permission::ManagePermissions,
+    ] {
+        known_permissions.insert(permission);
+    }
+
+    let new = DatabaseV2 {
+        users_data: old.users_data,
+        clinics_data: old.clinics_data,
+        doctors_data: old.doctors_data,
+        prescriptions_data: old.prescriptions_data,
+        drugs_data: old.drugs_data,
+        drug_gps: old.drug_gps,
+        map: old.map,
+        ambulances_data: old.ambulances_data,
+        audit_log: old.audit_log,
+        next_audit_id: old.next_audit_id,
+        role_permissions: old.role_permissions,
+        known_permissions,
+        field_key_salt: old.field_key_salt,
+        field_key_check: old.field_key_check,
+        journal_generation: old.journal_generation,
+    };
+    Ok(bincode::serialize(&new).map_err(io::Error::other)?)
+}
+
+fn v2_to_v3(payload: &[u8]) -> Result<Vec<u8>, DbError> {
+    let old: DatabaseV2 = decode(2, payload)?;
+    let mut queues = old.doctors_data.map_or(Vec::new(), |lists| lists.iter().cloned().collect::<Vec<_>>());
+    // a doctor listed twice only ever had the front one found, the one that comes first
+    queues.sort_by(|a, b| a.doctor.cmp(&b.doctor));
+    queues.dedup_by(|later, earlier| later.doctor == earlier.doctor);
+
+    let new = DatabaseV3 {
+        users_data: old.users_data,
+        clinics_data: old.clinics_data,
+        doctors_data: Node::balanced(queues),
+        prescriptions_data: old.prescriptions_data,
+        drugs_data: old.drugs_data,
+        drug_gps: old.drug_gps,
+        map: old.map,
+        ambulances_data: old.ambulances_data,
+        audit_log: old.audit_log,
+        next_audit_id: old.next_audit_id,
+        role_permissions: old.role_permissions,
+        known_permissions: old.known_permissions,
+        field_key_salt: old.field_key_salt,
+        field_key_check: old.field_key_check,
+        journal_generation: old.journal_generation,
+    };
+    Ok(bincode::serialize(&new).map_err(io::Error::other)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::priority_queue::PriorityQueue;
+    use crate::db::db_handler::Database;
+    use crate::db::entities::Patient;
+    use crate::db::repository::Repository;
+
+    fn legacy_database() -> Vec<u8> {
+        let user = |username: &str, ssn: &str| UserV0 {
+            username: username.to_string(),
+            password: "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8".to_string(),
+            full_name: format!("{} name", username),
+            ssn: ssn.to_string(),
+            age: 40,
+            role: Role::Doctor,
+        };
+        let mut logs_data = HashMap::new();
+        logs_data.insert("2025-01-02 10:00:00.000000001 +00:00".to_string(), "Ambulance A sent from Home A to Hospital A".to_string());
+        logs_data.insert("2025-01-01 10:00:00.000000001 +00:00".to_string(), "Ambulance A moved from Hospital A to Home A".to_string());
+        let old = DatabaseV0 {
+            users_data: Some(Node {
+                value: user("doc1", "111"),
+                left: None,
+                right: Some(Box::new(Node { value: user("doc2", "222"), left: None, right: None })),
+            }),
+            clinics_data: None,
+            doctors_data: None,
+            prescriptions_data: None,
+            drugs_data: Some(Box::new(Node { value: Drug::new(0, "Aspirin".to_string(), 32.99, 50), left: None, right: None })),
+            drug_gps: None,
+            map: Graph::new(),
+            ambulances_data: None,
+            logs_data,
+        };
+        bincode::serialize(&old).unwrap()
+    }
+
+    #[test]
+    fn test_header() {
+        let bytes = encode(b"payload");
+        assert_eq!(split(&bytes), (FORMAT_VERSION, &b"payload"[..]));
+        assert_eq!(split(b"no header at all").0, 0);
+        assert!(pending_migrations(FORMAT_VERSION).unwrap().is_empty());
+        assert_eq!(pending_migrations(0).unwrap().len(), FORMAT_VERSION as usize);
+        assert!(pending_migrations(FORMAT_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_from_v0() {
+        let (version, payload) = upgrade(&legacy_database()).unwrap();
+        assert_eq!(version, 0);
+        let mut db: Database = bincode::deserialize(&payload).unwrap();
+
+        // no key yet, so the personal details are still readable until the first save seals them
+        db.unlock("passphrase").unwrap();
+        let doc2 = db.users().get("doc2").unwrap();
+        assert_eq!(doc2.ssn, "222");
+        assert_eq!(doc2.status, AccountStatus::Active);
+        assert!(doc2.verify_password("password".to_string()));
+        assert_eq!(db.drugs().get("0").unwrap().quantity, 50);
+
+        let head = db.verify_audit_chain().unwrap().unwrap();
+        assert_eq!(head.length, 2);
+        assert_eq!(db.next_audit_id, 3);
+        assert!(db.audit_log.iter().next().unwrap().after.as_ref().unwrap().starts_with("Ambulance A sent"));
+    }
+
+    #[test]
+    fn test_upgrade_drops_gaps_in_queues() {
+        let patient = |name: &str, priority: u32| Patient { name: name.to_string(), priority };
+        let mut old: DatabaseV0 = bincode::deserialize(&legacy_database()).unwrap();
+        let mut queue = PriorityQueue::new();
+        for (name, priority) in [("patient a", 1), ("patient b", 2), ("patient c", 3)] {
+            queue.insert(patient(name, priority));
+        }
+        let mut lists = LinkedList::new();
+        lists.insert(DoctorsList { doctor: "doc1".to_string(), patients: queue });
+        old.doctors_data = Some(lists);
+        // a cancelled appointment used to leave its slot empty without shrinking the heap
+        let mut bytes = bincode::serialize(&old).unwrap();
+        let cancelled = bincode::serialize(&Some(patient("patient b", 2))).unwrap();
+        let at = bytes.windows(cancelled.len()).position(|window| window == cancelled.as_slice()).unwrap();
+        bytes.splice(at..at + cancelled.len(), [0u8]);
+
+        let (_, payload) = upgrade(&bytes).unwrap();
+        let mut db: Database = bincode::deserialize(&payload).unwrap();
+        let queue = &mut db.doctors_lists_mut().get_mut("doc1").unwrap().patients;
+        assert_eq!(queue.len(), 2);
+        assert!(queue.remove_by_uniq_attr("patient c".to_string()));
+        assert_eq!(queue.pop().unwrap().name, "patient a");
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_upgrade_from_v1_grants_new_defaults() {
+        let mut v1: DatabaseV1 = bincode::deserialize(&v0_to_v1(&legacy_database()).unwrap()).unwrap();
+        let mut doctor = RolePermissions::default_for(Role::Doctor);
+        doctor.permissions.remove(&Permission::ViewPii);
+        // revoked by an admin, stays revoked
+        doctor.permissions.remove(&Permission::VisitPatients);
+        let mut stored = LinkedList::new();
+        stored.insert(doctor);
+        v1.role_permissions = Some(stored);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&v1).unwrap());
+
+        let (version, payload) = upgrade(&bytes).unwrap();
+        assert_eq!(version, 1);
+        assert!(journal_readable(1));
+        assert!(!journal_readable(0));
+        let mut db: Database = bincode::deserialize(&payload).unwrap();
+        assert!(!db.role_has_permission(&Role::Doctor, Permission::ViewPii));
+        db.merge_new_permissions();
+        assert!(db.role_has_permission(&Role::Doctor, Permission::ViewPii));
+        assert!(!db.role_has_permission(&Role::Doctor, Permission::VisitPatients));
+        assert!(!db.role_has_permission(&Role::Doctor, Permission::ManageUsers));
+
+        // once merged the admin's choices are left alone
+        db.get_role_permissions(Role::Doctor).permissions.remove(&Permission::ViewPii);
+        db.merge_new_permissions();
+        assert!(!db.role_has_permission(&Role::Doctor, Permission::ViewPii));
+    }
+
+    #[test]
+    fn test_upgrade_from_v2_keys_queues() {
+        let mut v2: DatabaseV2 = bincode::deserialize(&v1_to_v2(&v0_to_v1(&legacy_database()).unwrap()).unwrap()).unwrap();
+        let mut lists = LinkedList::new();
+        for (doctor, patients) in [("doc2", 0), ("doc1", 0), ("doc3", 0), ("doc1", 1)] {
+            let mut queue = PriorityQueue::new();
+            for _ in 0..patients {
+                queue.insert(Patient { name: "patient1".to_string(), priority: 1 });
+            }
+            lists.insert(DoctorsList { doctor: doctor.to_string(), patients: queue });
+        }
+        v2.doctors_data = Some(lists);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(&v2).unwrap());
+
+        let (version, payload) = upgrade(&bytes).unwrap();
+        assert_eq!(version, 2);
+        assert!(journal_readable(2));
+        let db: Database = bincode::deserialize(&payload).unwrap();
+        assert_eq!(db.doctors_lists().iterate().map(|list| list.doctor.as_str()).collect::<Vec<_>>(), vec!["doc1", "doc2", "doc3"]);
+        // the list found doc1's queue at the front, that one is kept
+        assert_eq!(db.doctors_lists().get("doc1").unwrap().patients.len(), 1);
+        assert_eq!(db.queues_with_patient("patient1").len(), 1);
+    }
+
+    #[test]
+    fn test_current_payload_is_untouched() {
+        let bytes = encode(b"current");
+        assert_eq!(upgrade(&bytes).unwrap(), (FORMAT_VERSION, b"current".to_vec()));
+        assert!(upgrade(&[0u8; 3]).is_err());
+    }
+}
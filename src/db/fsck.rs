@@ -0,0 +1,286 @@
+use std::fmt;
+
+use crate::data_structures::map::{LocationType, Object};
+use crate::data_structures::priority_queue::PriorityQueue;
+use crate::db::audit::EntityType;
+use crate::db::db_handler::Database;
+use crate::db::entities::{DoctorsList, Role};
+use crate::db::repository::Repository;
+
+
+// records refer to each other by username, drug id or location name, nothing keeps those in step but the menus
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    // a clinic lists someone who isn't a doctor
+    ClinicDoctor { clinic: String, doctor: String },
+    // a queue whose doctor no longer exists
+    OrphanQueue { doctor: String },
+    // a doctor without a queue can't be given appointments
+    MissingQueue { doctor: String },
+    QueuedPatient { doctor: String, patient: String },
+    PrescriptionPatient { patient: String },
+    GroupDrug { group: String, id: u32 },
+    // the home base isn't a hospital on the map
+    AmbulanceHospital { ambulance: String, hospital: String },
+    // the location of an ambulance doesn't hold an object of the same name
+    AmbulanceNotOnMap { ambulance: String, location: String },
+    // an object no ambulance accounts for, or a second copy of one away from its location
+    StrayObject { location: String, object: String },
+    Route { from: String, to: String },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::ClinicDoctor { clinic, doctor } => write!(f, "clinic {} lists {}, who is not a doctor", clinic, doctor),
+            Issue::OrphanQueue { doctor } => write!(f, "there is a queue for {}, who is not a doctor", doctor),
+            Issue::MissingQueue { doctor } => write!(f, "doctor {} has no queue", doctor),
+            Issue::QueuedPatient { doctor, patient } => write!(f, "the queue of {} holds {}, who is not a user", doctor, patient),
+            Issue::PrescriptionPatient { patient } => write!(f, "there is a prescription for {}, who is not a user", patient),
+            Issue::GroupDrug { group, id } => write!(f, "drug group {} holds drug #{}, which does not exist", group, id),
+            Issue::AmbulanceHospital { ambulance, hospital } => write!(f, "ambulance {} belongs to {}, which is not a hospital on the map", ambulance, hospital),
+            Issue::AmbulanceNotOnMap { ambulance, location } => write!(f, "ambulance {} is at {}, but the map doesn't show it there", ambulance, location),
+            Issue::StrayObject { location, object } => write!(f, "the map shows {} at {}, but no ambulance is there", object, location),
+            Issue::Route { from, to } => write!(f, "route from {} to {} leads to or from a location that does not exist", from, to),
+        }
+    }
+}
+
+impl Issue {
+    // what a repair changes, for the audit log
+    pub fn entity(&self) -> (EntityType, String) {
+        match self {
+            Issue::ClinicDoctor { doctor, .. } => (EntityType::User, doctor.clone()),
+            Issue::OrphanQueue { doctor } | Issue::MissingQueue { doctor } | Issue::QueuedPatient { doctor, .. } => (EntityType::Appointment, doctor.clone()),
+            Issue::PrescriptionPatient { patient } => (EntityType::Prescription, patient.clone()),
+            Issue::GroupDrug { group, .. } => (EntityType::DrugGroup, group.clone()),
+            Issue::AmbulanceHospital { ambulance, .. } | Issue::AmbulanceNotOnMap { ambulance, .. } => (EntityType::Ambulance, ambulance.clone()),
+            Issue::StrayObject { location, .. } => (EntityType::Location, location.clone()),
+            Issue::Route { from, to } => (EntityType::Route, format!("{} - {}", from, to)),
+        }
+    }
+}
+
+fn is_user(db: &Database, username: &str) -> bool {
+    db.users().contains(username)
+}
+
+fn is_doctor(db: &Database, username: &str) -> bool {
+    db.users().get(username).is_some_and(|user| user.role == Role::Doctor || user.role == Role::EmergencyDoctor)
+}
+
+fn is_hospital(db: &Database, location: &str) -> bool {
+    db.map().nodes.get(location).is_some_and(|node| matches!(node.location_type, LocationType::Hospital))
+}
+
+// every dangling or inconsistent reference, nothing is changed
+pub fn check(db: &Database) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    for clinic in db.clinics_data.iter().flat_map(|clinics| clinics.iter()) {
+        for doctor in clinic.doctors.iter().filter(|doctor| !is_doctor(db, doctor)) {
+            issues.push(Issue::ClinicDoctor { clinic: clinic.name.clone(), doctor: doctor.clone() });
+        }
+    }
+
+    let queues = db.doctors_data.iter().flat_map(|queues| queues.iter()).collect::<Vec<_>>();
+    for queue in queues.iter() {
+        if !is_doctor(db, &queue.doctor) {
+            issues.push(Issue::OrphanQueue { doctor: queue.doctor.clone() });
+            continue;
+        }
+        for patient in queue.patients.iter().filter(|patient| !is_user(db, &patient.name)) {
+            issues.push(Issue::QueuedPatient { doctor: queue.doctor.clone(), patient: patient.name.clone() });
+        }
+    }
+    for user in db.users_data.iter().flat_map(|users| users.iter()) {
+        if is_doctor(db, &user.username) && !queues.iter().any(|queue| queue.doctor == user.username) {
+            issues.push(Issue::MissingQueue { doctor: user.username.clone() });
+        }
+    }
+
+    for prescription in db.prescriptions_data.iter().flat_map(|prescriptions| prescriptions.iter()) {
+        if !is_user(db, &prescription.patient_name) {
+            issues.push(Issue::PrescriptionPatient { patient: prescription.patient_name.clone() });
+        }
+    }
+
+    let drugs = db.drugs_data.as_deref();
+    for group in db.drug_gps.iter().flat_map(|groups| groups.iter()) {
+        for id in group.drugs.iter().filter(|id| drugs.and_then(|drugs| drugs.get_drug_by_id(**id)).is_none()) {
+            issues.push(Issue::GroupDrug { group: group.name.clone(), id: *id });
+        }
+    }
+
+    let ambulances = db.ambulances_data.iter().flat_map(|ambulances| ambulances.iter()).collect::<Vec<_>>();
+    for ambulance in ambulances.iter() {
+        if !is_hospital(db, &ambulance.hospital) {
+            issues.push(Issue::AmbulanceHospital { ambulance: ambulance.name.clone(), hospital: ambulance.hospital.clone() });
+        }
+        let shown = db.map().nodes.get(ambulance.location.as_str()).is_some_and(|node| node.objects.iter().any(|object| object.name == ambulance.name));
+        if !shown {
+            issues.push(Issue::AmbulanceNotOnMap { ambulance: ambulance.name.clone(), location: ambulance.location.clone() });
+        }
+    }
+
+    let mut locations = db.map().nodes.iter().collect::<Vec<_>>();
+    locations.sort_by(|a, b| a.0.cmp(b.0));
+    for (location, node) in locations {
+        for object in node.objects.iter() {
+            if !ambulances.iter().any(|ambulance| ambulance.name == object.name && ambulance.location == *location) {
+                issues.push(Issue::StrayObject { location: location.clone(), object: object.name.clone() });
+            }
+        }
+    }
+
+    let mut routes = db.map().edges.iter().collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.0.cmp(b.0));
+    for (from, targets) in routes {
+        for to in targets.iter() {
+            if !db.map().nodes.contains_key(from) || !db.map().nodes.contains_key(to) {
+                issues.push(Issue::Route { from: from.clone(), to: to.clone() });
+            }
+        }
+    }
+    issues
+}
+
+// false when the fix needs a person to decide, e.g. which hospital an ambulance belongs to
+pub fn repair(db: &mut Database, issue: &Issue) -> bool {
+    match issue {
+        Issue::ClinicDoctor { clinic, doctor } => match db.clinics_mut().get_mut(clinic) {
+            Some(clinic) => {
+                while clinic.doctors.remove(doctor) {}
+                true
+            },
+            None => false,
+        },
+        Issue::OrphanQueue { doctor } => db.doctors_lists_mut().remove(doctor).is_ok(),
+        Issue::MissingQueue { doctor } => db.doctors_lists_mut().insert(DoctorsList { doctor: doctor.clone(), patients: PriorityQueue::new() }).is_ok(),
+        Issue::QueuedPatient { doctor, patient } => match db.doctors_lists_mut().get_mut(doctor) {
+            Some(queue) => {
+                while queue.patients.remove_by_uniq_attr(patient.clone()) {}
+                true
+            },
+            None => false,
+        },
+        Issue::PrescriptionPatient { patient } => {
+            while db.prescriptions_mut().remove(patient).is_ok() {}
+            true
+        },
+        Issue::GroupDrug { group, id } => match db.drug_groups_mut().get_mut(group) {
+            Some(group) => {
+                while group.drugs.remove(id) {}
+                true
+            },
+            None => false,
+        },
+        Issue::AmbulanceHospital { .. } => false,
+        // put it where its record says it is, or back at its hospital when that location is gone
+        Issue::AmbulanceNotOnMap { ambulance, location } => {
+            let location = if db.map().nodes.contains_key(location.as_str()) {
+                location.clone()
+            } else {
+                match db.ambulances().get(ambulance).map(|record| record.hospital.clone()) {
+                    Some(hospital) if is_hospital(db, &hospital) => hospital,
+                    _ => return false,
+                }
+            };
+            db.map_mut().add_object_to_node(&location, Object { name: ambulance.clone() });
+            db.ambulances_mut().get_mut(ambulance).unwrap().location = location;
+            true
+        },
+        Issue::StrayObject { location, object } => {
+            db.map_mut().remove_object_from_node(location, object);
+            true
+        },
+        Issue::Route { from, to } => {
+            if !db.map().nodes.contains_key(from.as_str()) {
+                db.map_mut().edges.remove(from);
+            } else if let Some(targets) = db.map_mut().edges.get_mut(from) {
+                while targets.remove(to) {}
+            }
+            true
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::linked_list::LinkedList;
+    use crate::db::entities::{Ambulance, Clinic, Drug, DrugGP, Patient, Prescription, User};
+    use crate::data_structures::stack::Stack;
+
+    fn user(username: &str, role: Role) -> User {
+        User::new(username.to_string(), "pw".to_string(), String::new(), String::new(), 30, role)
+    }
+
+    fn list(items: &[&str]) -> LinkedList<String> {
+        let mut list = LinkedList::new();
+        for item in items {
+            list.insert(item.to_string());
+        }
+        list
+    }
+
+    // one of every kind of broken reference
+    fn database() -> Database {
+        let mut db = Database::new();
+        db.users_mut().insert(user("doc1", Role::Doctor)).unwrap();
+        db.users_mut().insert(user("doc2", Role::EmergencyDoctor)).unwrap();
+        db.users_mut().insert(user("patient1", Role::Patient)).unwrap();
+        db.doctors_lists_mut().insert(DoctorsList { doctor: "doc1".to_string(), patients: PriorityQueue::new() }).unwrap();
+        db.doctors_lists_mut().insert(DoctorsList { doctor: "gone".to_string(), patients: PriorityQueue::new() }).unwrap();
+        db.doctors_lists_mut().get_mut("doc1").unwrap().patients.insert(Patient { name: "patient1".to_string(), priority: 1 });
+        db.doctors_lists_mut().get_mut("doc1").unwrap().patients.insert(Patient { name: "ghost".to_string(), priority: 2 });
+        db.clinics_mut().insert(Clinic { name: "Clinic A".to_string(), doctors: list(&["doc1", "patient1"]) }).unwrap();
+        db.prescriptions_mut().insert(Prescription { patient_name: "ghost".to_string(), medications: Stack::new() }).unwrap();
+        db.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        let mut drugs = LinkedList::new();
+        drugs.insert(0);
+        drugs.insert(4);
+        db.drug_groups_mut().insert(DrugGP { name: "Painkillers".to_string(), drugs }).unwrap();
+
+        db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+        db.map_mut().add_node("Home A".to_string(), LocationType::Home);
+        db.map_mut().add_edge("Hospital A".to_string(), "Home A".to_string());
+        db.map_mut().add_edge("Hospital A".to_string(), "Home B".to_string());
+        db.ambulances_mut().insert(Ambulance::new("Ambulance A".to_string(), "Hospital A".to_string(), "Home B".to_string())).unwrap();
+        db.ambulances_mut().insert(Ambulance::new("Ambulance B".to_string(), "Home A".to_string(), "Hospital A".to_string())).unwrap();
+        db.map_mut().add_object_to_node("Hospital A", Object { name: "Ambulance B".to_string() });
+        db.map_mut().add_object_to_node("Home A", Object { name: "Ambulance C".to_string() });
+        db
+    }
+
+    #[test]
+    fn test_check() {
+        let issues = check(&database());
+        assert_eq!(issues, vec![
+            Issue::ClinicDoctor { clinic: "Clinic A".to_string(), doctor: "patient1".to_string() },
+            Issue::QueuedPatient { doctor: "doc1".to_string(), patient: "ghost".to_string() },
+            Issue::OrphanQueue { doctor: "gone".to_string() },
+            Issue::MissingQueue { doctor: "doc2".to_string() },
+            Issue::PrescriptionPatient { patient: "ghost".to_string() },
+            Issue::GroupDrug { group: "Painkillers".to_string(), id: 4 },
+            Issue::AmbulanceHospital { ambulance: "Ambulance B".to_string(), hospital: "Home A".to_string() },
+            Issue::AmbulanceNotOnMap { ambulance: "Ambulance A".to_string(), location: "Home B".to_string() },
+            Issue::StrayObject { location: "Home A".to_string(), object: "Ambulance C".to_string() },
+            Issue::Route { from: "Hospital A".to_string(), to: "Home B".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_repair() {
+        let mut db = database();
+        let issues = check(&db);
+        let unrepaired = issues.iter().filter(|issue| !repair(&mut db, issue)).cloned().collect::<Vec<_>>();
+        assert_eq!(unrepaired, vec![Issue::AmbulanceHospital { ambulance: "Ambulance B".to_string(), hospital: "Home A".to_string() }]);
+        assert_eq!(check(&db), unrepaired);
+
+        assert_eq!(db.ambulances().get("Ambulance A").unwrap().location, "Hospital A");
+        assert_eq!(db.drug_groups().get("Painkillers").unwrap().drugs.iter().collect::<Vec<_>>(), vec![&0]);
+        assert_eq!(db.clinics().get("Clinic A").unwrap().doctors.iter().collect::<Vec<_>>(), vec!["doc1"]);
+        assert!(db.doctors_lists().contains("doc2"));
+    }
+}
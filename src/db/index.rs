@@ -0,0 +1,391 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::data_structures::hash_map::HashMap;
+use crate::data_structures::linked_list::LinkedList;
+use crate::db::entities::UniqueAttribute;
+use crate::db::repository::Repository;
+
+
+// a secondary index, the values a record can be found by besides its key
+pub struct IndexSpec<T> {
+    pub name: &'static str,
+    pub values: fn(&T) -> Vec<String>,
+    // a value can only belong to one record, inserts check it like the key
+    pub unique: bool,
+}
+
+// entities list their indexes here and Indexed keeps them up to date
+pub trait SecondaryIndexes: UniqueAttribute + Clone + Sized + 'static {
+    const INDEXES: &'static [IndexSpec<Self>];
+}
+
+// savepoints over a collection, lets Database open, undo and keep them on every collection at once
+pub trait Savepoints {
+    fn begin(&mut self);
+    // puts back every record changed since the matching begin()
+    fn rollback(&mut self);
+    // the changes become part of the savepoint outside this one, if there is one
+    fn release(&mut self);
+}
+
+// a repository together with its secondary indexes, each one maps a value to the keys of the records that have it.
+// it also remembers which records changed since the last commit so only those get journaled.
+// only the records are saved, the indexes are built again when loading
+#[derive(Debug, Clone)]
+pub struct Indexed<S, T: Clone> {
+    storage: S,
+    indexes: Vec<HashMap<String, LinkedList<String>>>,
+    // the record last handed out by get_mut, it is out of the indexes until the next change puts it back
+    changing: Option<String>,
+    // keys of the records added, handed out for changing or taken out, whether or not they exist now
+    changed: HashMap<String, ()>,
+    // one log per open savepoint, innermost last. for every record changed since, what it was before the first
+    // change and whether it already counted as changed then
+    undo: Vec<HashMap<String, (Option<T>, bool)>>,
+    entity: PhantomData<T>,
+}
+
+impl<S: Repository<T>, T: SecondaryIndexes> Indexed<S, T> {
+    pub fn new(storage: S) -> Self {
+        let mut indexed = Indexed { storage, indexes: Vec::new(), changing: None, changed: HashMap::new(), undo: Vec::new(), entity: PhantomData };
+        indexed.rebuild();
+        indexed
+    }
+
+    pub fn rebuild(&mut self) {
+        self.indexes = T::INDEXES.iter().map(|_| HashMap::new()).collect();
+        self.changing = None;
+        for item in self.storage.iterate() {
+            add_entries(&mut self.indexes, item);
+        }
+    }
+
+    // for changes that can touch any record, the indexes are built again once they are done
+    pub fn change_all(&mut self, change: impl FnOnce(&mut S)) {
+        self.touch_all();
+        change(&mut self.storage);
+        // whatever is there now and wasn't touched above didn't exist before
+        for key in self.storage.iterate().map(|item| item.uattr()).collect::<Vec<_>>() {
+            self.remember(&key, None);
+        }
+        self.rebuild();
+    }
+
+    // like change_all for changes to how the records are held rather than what they say, sealing personal details
+    // for one, so they don't count as changed
+    pub fn rewrite_all(&mut self, change: impl FnOnce(&mut S)) {
+        change(&mut self.storage);
+        self.rebuild();
+    }
+
+    // takes over the records of another copy, every record of either one counts as changed
+    pub fn replace_with(&mut self, other: Indexed<S, T>) {
+        self.change_all(|storage| *storage = other.storage);
+    }
+
+    pub fn changed_keys(&self) -> Vec<String> {
+        self.changed.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changed = HashMap::new();
+    }
+
+    // called before a record changes
+    fn touch(&mut self, key: &str) {
+        let before = match self.undo.last() {
+            Some(undo) if !undo.contains_key(key) => self.storage.get(key).cloned(),
+            _ => None,
+        };
+        self.remember(key, before);
+    }
+
+    fn remember(&mut self, key: &str, before: Option<T>) {
+        let was_changed = self.changed.contains_key(key);
+        if let Some(undo) = self.undo.last_mut() {
+            if !undo.contains_key(key) {
+                undo.insert(key.to_string(), (before, was_changed));
+            }
+        }
+        if !was_changed {
+            self.changed.insert(key.to_string(), ());
+        }
+    }
+
+    fn touch_all(&mut self) {
+        for key in self.storage.iterate().map(|item| item.uattr()).collect::<Vec<_>>() {
+            self.touch(&key);
+        }
+    }
+
+    // the records whose value in the named index is exactly this
+    pub fn find(&self, index: &str, value: &str) -> Vec<&T> {
+        let position = T::INDEXES.iter().position(|spec| spec.name == index).unwrap_or_else(|| panic!("no {} index on {}", index, T::ENTITY));
+        self.find_at(position, value)
+    }
+
+    fn find_at(&self, position: usize, value: &str) -> Vec<&T> {
+        let mut found = self.indexes[position].get(value).map_or(Vec::new(), |keys| keys.iter().filter_map(|key| self.storage.get(key)).collect());
+        if let Some(item) = self.changing.as_ref().and_then(|key| self.storage.get(key)) {
+            if (T::INDEXES[position].values)(item).iter().any(|candidate| candidate == value) {
+                found.push(item);
+            }
+        }
+        found
+    }
+
+    fn settle(&mut self) {
+        if let Some(item) = self.changing.take().and_then(|key| self.storage.get(&key)) {
+            add_entries(&mut self.indexes, item);
+        }
+    }
+}
+
+impl<S: Repository<T>, T: SecondaryIndexes> Savepoints for Indexed<S, T> {
+    fn begin(&mut self) {
+        self.undo.push(HashMap::new());
+    }
+
+    fn rollback(&mut self) {
+        let undo = match self.undo.pop() {
+            Some(undo) => undo,
+            None => return,
+        };
+        self.settle();
+        for (key, (before, was_changed)) in undo.iter() {
+            match before.clone() {
+                // put back in place so lists keep their order
+                Some(before) => match self.storage.get_mut(key) {
+                    Some(current) => {
+                        remove_entries(&mut self.indexes, current);
+                        add_entries(&mut self.indexes, &before);
+                        *current = before;
+                    },
+                    None => {
+                        add_entries(&mut self.indexes, &before);
+                        self.storage.store(before);
+                    },
+                },
+                None => if let Some(item) = self.storage.extract(key) {
+                    remove_entries(&mut self.indexes, &item);
+                },
+            }
+            if !was_changed {
+                self.changed.remove(key);
+            }
+        }
+    }
+
+    fn release(&mut self) {
+        let undo = match self.undo.pop() {
+            Some(undo) => undo,
+            None => return,
+        };
+        // the outer savepoint already knows what the records it saw first looked like
+        if let Some(outer) = self.undo.last_mut() {
+            for (key, entry) in undo.iter() {
+                if !outer.contains_key(key) {
+                    outer.insert(key.clone(), entry.clone());
+                }
+            }
+        }
+    }
+}
+
+fn add_entries<T: SecondaryIndexes>(indexes: &mut [HashMap<String, LinkedList<String>>], item: &T) {
+    let key = item.uattr();
+    for (spec, index) in T::INDEXES.iter().zip(indexes.iter_mut()) {
+        // details that are still sealed or were never filled in are left out
+        for value in (spec.values)(item).into_iter().filter(|value| !value.is_empty()) {
+            match index.get_mut(&value) {
+                Some(keys) if keys.contains(&key) => {},
+                Some(keys) => keys.insert(key.clone()),
+                None => {
+                    let mut keys = LinkedList::new();
+                    keys.insert(key.clone());
+                    index.insert(value, keys);
+                },
+            }
+        }
+    }
+}
+
+fn remove_entries<T: SecondaryIndexes>(indexes: &mut [HashMap<String, LinkedList<String>>], item: &T) {
+    let key = item.uattr();
+    for (spec, index) in T::INDEXES.iter().zip(indexes.iter_mut()) {
+        for value in (spec.values)(item) {
+            let now_empty = index.get_mut(&value).is_some_and(|keys| {
+                keys.remove(&key);
+                keys.is_empty()
+            });
+            if now_empty {
+                index.remove(&value);
+            }
+        }
+    }
+}
+
+impl<S: Repository<T>, T: SecondaryIndexes> Repository<T> for Indexed<S, T> {
+    fn get(&self, key: &str) -> Option<&T> {
+        self.storage.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        self.settle();
+        if let Some(item) = self.storage.get(key) {
+            remove_entries(&mut self.indexes, item);
+            self.changing = Some(key.to_string());
+            self.touch(key);
+        }
+        self.storage.get_mut(key)
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        self.storage.iterate()
+    }
+
+    fn store(&mut self, item: T) {
+        self.settle();
+        add_entries(&mut self.indexes, &item);
+        self.touch(&item.uattr());
+        self.storage.store(item);
+    }
+
+    fn extract(&mut self, key: &str) -> Option<T> {
+        self.settle();
+        self.storage.get(key)?;
+        self.touch(key);
+        let item = self.storage.extract(key)?;
+        remove_entries(&mut self.indexes, &item);
+        Some(item)
+    }
+
+    // the key and the values of unique indexes are looked up, the storage isn't searched
+    fn conflict(&self, item: &T) -> Option<String> {
+        let key = item.uattr();
+        if self.storage.get(&key).is_some() {
+            return Some(key);
+        }
+        T::INDEXES.iter().enumerate().filter(|(_, spec)| spec.unique).find_map(|(position, spec)| {
+            (spec.values)(item).into_iter().find(|value| !self.find_at(position, value).is_empty())
+        })
+    }
+
+    fn count(&self) -> usize {
+        self.storage.count()
+    }
+}
+
+// reading the records directly is fine, changing them has to go through the repository
+impl<S, T: Clone> Deref for Indexed<S, T> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.storage
+    }
+}
+
+// saved exactly like the bare collection, so the file format doesn't change
+impl<S: Serialize, T: Clone> Serialize for Indexed<S, T> {
+    fn serialize<Z: Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        self.storage.serialize(serializer)
+    }
+}
+
+impl<'de, S: Deserialize<'de> + Repository<T>, T: SecondaryIndexes> Deserialize<'de> for Indexed<S, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        S::deserialize(deserializer).map(Indexed::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::bst::TreeNode;
+    use crate::data_structures::priority_queue::PriorityQueue;
+    use crate::db::entities::{DoctorsList, Drug, Patient, Role, User};
+
+    fn keys<T: UniqueAttribute>(found: Vec<&T>) -> Vec<String> {
+        let mut keys = found.into_iter().map(|item| item.uattr()).collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_drug_names() {
+        let mut drugs: Indexed<Option<Box<TreeNode<Drug>>>, Drug> = Indexed::new(None);
+        drugs.insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        drugs.insert(Drug::new(1, "Ibuprofen".to_string(), 12.5, 10)).unwrap();
+        assert_eq!(keys(drugs.find("name", "Aspirin")), vec!["0"]);
+
+        // found under the new name both while the record is still being changed and afterwards
+        drugs.get_mut("0").unwrap().name = "Aspirin Forte".to_string();
+        assert!(drugs.find("name", "Aspirin").is_empty());
+        assert_eq!(keys(drugs.find("name", "Aspirin Forte")), vec!["0"]);
+        drugs.remove("1").unwrap();
+        assert_eq!(keys(drugs.find("name", "Aspirin Forte")), vec!["0"]);
+        assert!(drugs.find("name", "Ibuprofen").is_empty());
+
+        // names are unique, the old one is free again after the rename
+        let taken = drugs.insert(Drug::new(2, "Aspirin Forte".to_string(), 1.0, 5)).unwrap_err();
+        assert_eq!(taken.to_string(), "A drug named 'Aspirin Forte' already exists");
+        drugs.insert(Drug::new(2, "Aspirin".to_string(), 1.0, 5)).unwrap();
+        assert!(drugs.insert(Drug::new(2, "Other".to_string(), 1.0, 5)).is_err());
+
+        let reloaded: Indexed<Option<Box<TreeNode<Drug>>>, Drug> = bincode::deserialize(&bincode::serialize(&drugs).unwrap()).unwrap();
+        assert_eq!(bincode::serialize(&reloaded).unwrap(), bincode::serialize(&*drugs).unwrap());
+        assert_eq!(keys(reloaded.find("name", "Aspirin Forte")), vec!["0"]);
+    }
+
+    #[test]
+    fn test_users_and_queues() {
+        let mut users: Indexed<Option<TreeNode<User>>, User> = Indexed::new(None);
+        for (username, full_name, ssn) in [("ann", "Ann Lee", "111"), ("bob", "Bob Lee", "222"), ("ann2", "ann lee", "")] {
+            users.insert(User::new(username.to_string(), "pw".to_string(), full_name.to_string(), ssn.to_string(), 30, Role::Patient)).unwrap();
+        }
+        assert_eq!(keys(users.find("ssn", "222")), vec!["bob"]);
+        assert!(users.find("ssn", "").is_empty());
+        assert_eq!(keys(users.find("full name", "ann lee")), vec!["ann", "ann2"]);
+
+        let mut queues: Indexed<Option<LinkedList<DoctorsList>>, DoctorsList> = Indexed::new(None);
+        for doctor in ["doc1", "doc2"] {
+            let mut patients = PriorityQueue::new();
+            patients.insert(Patient { name: "ann".to_string(), priority: 1 });
+            queues.insert(DoctorsList { doctor: doctor.to_string(), patients }).unwrap();
+        }
+        assert_eq!(keys(queues.find("patient", "ann")), vec!["doc1", "doc2"]);
+        queues.get_mut("doc1").unwrap().patients.remove_by_uniq_attr("ann".to_string());
+        queues.change_all(|lists| {
+            lists.get_mut("doc2").unwrap().patients.insert(Patient { name: "bob".to_string(), priority: 2 });
+        });
+        assert_eq!(keys(queues.find("patient", "ann")), vec!["doc2"]);
+        assert_eq!(keys(queues.find("patient", "bob")), vec!["doc2"]);
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let mut drugs: Indexed<Option<Box<TreeNode<Drug>>>, Drug> = Indexed::new(None);
+        for (id, name) in [(0, "Aspirin"), (1, "Ibuprofen"), (2, "Paracetamol")] {
+            drugs.insert(Drug::new(id, name.to_string(), 1.0, 5)).unwrap();
+        }
+        let mut changed = drugs.changed_keys();
+        changed.sort();
+        assert_eq!(changed, vec!["0", "1", "2"]);
+
+        drugs.clear_changes();
+        drugs.get_mut("1").unwrap().quantity = 7;
+        drugs.remove("2").unwrap();
+        assert!(drugs.get_mut("9").is_none());
+        let mut changed = drugs.changed_keys();
+        changed.sort();
+        assert_eq!(changed, vec!["1", "2"]);
+
+        drugs.clear_changes();
+        drugs.rewrite_all(|_| {});
+        assert!(drugs.changed_keys().is_empty());
+        drugs.change_all(|_| {});
+        assert_eq!(drugs.changed_keys().len(), 2);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use serde::{Serialize, Deserialize};
+
+use crate::db::audit::AuditEvent;
+use crate::db::entities::{Drug, User};
+use crate::db::error::DbError;
+use crate::db::format::{self, FORMAT_VERSION};
+use crate::sha_hasher::Sha256;
+
+
+// file layout: MAGIC, generation (u64 LE), format version (u32 LE), then one frame per commit
+// frame: payload length (u32 LE) || sha256(payload) || bincode Vec<JournalOp>
+const MAGIC: &[u8; 8] = b"HOSPJRNL";
+pub const HEADER_LEN: u64 = 20;
+const FRAME_HEADER_LEN: usize = 4 + 32;
+// the journal is folded into a fresh snapshot once it grows past this
+pub const COMPACT_THRESHOLD: u64 = 1024 * 1024;
+
+// everything besides users, drugs and the audit log. the keyed ones are journaled a record at a time,
+// the map, the encryption key check and the known permissions whole
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Clinics,
+    DoctorsLists,
+    Prescriptions,
+    DrugGroups,
+    Ambulances,
+    RolePermissions,
+    Map,
+    FieldKey,
+    KnownPermissions,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum JournalOp {
+    PutUser(User),
+    DeleteUser(String),
+    PutDrug(Drug),
+    DeleteDrug(u32),
+    AppendAudit(AuditEvent),
+    SetSection(Section, Vec<u8>),
+    // one bincode encoded record of a keyed section, and the key of one taken out of it
+    PutRecord(Section, Vec<u8>),
+    DeleteRecord(Section, String),
+}
+
+// what survived in a journal file, valid_len is where the next frame goes
+#[derive(Debug)]
+pub struct Replay {
+    pub generation: u64,
+    pub format_version: u32,
+    pub batches: Vec<Vec<JournalOp>>,
+    pub valid_len: u64,
+}
+
+pub fn journal_path(filename: &str) -> String {
+    format!("{}.journal", filename)
+}
+
+fn checksum(payload: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+pub fn encode_header(generation: u64) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&generation.to_le_bytes());
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+pub fn encode_frame(ops: &[JournalOp]) -> Vec<u8> {
+    let payload = bincode::serialize(ops).unwrap();
+    let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// a torn or corrupt frame ends the journal, everything after it was never acknowledged
+pub fn decode(bytes: &[u8]) -> Result<Replay, DbError> {
+    if bytes.len() < HEADER_LEN as usize || &bytes[..8] != MAGIC {
+        return Err(DbError::Corrupt("journal header is missing or damaged".to_string()));
+    }
+    let mut generation = [0u8; 8];
+    generation.copy_from_slice(&bytes[8..16]);
+    let generation = u64::from_le_bytes(generation);
+    let mut format_version = [0u8; 4];
+    format_version.copy_from_slice(&bytes[16..20]);
+    let format_version = u32::from_le_bytes(format_version);
+    // frames of a format with another layout can't be decoded here, the caller decides what to do with them
+    if !format::journal_readable(format_version) {
+        return Ok(Replay { generation, format_version, batches: Vec::new(), valid_len: bytes.len() as u64 });
+    }
+
+    let mut batches = Vec::new();
+    let mut offset = HEADER_LEN as usize;
+    while bytes.len() - offset >= FRAME_HEADER_LEN {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&bytes[offset..offset + 4]);
+        let start = offset + FRAME_HEADER_LEN;
+        let end = start + u32::from_le_bytes(len) as usize;
+        if end > bytes.len() || checksum(&bytes[start..end])[..] != bytes[offset + 4..start] {
+            break;
+        }
+        match bincode::deserialize::<Vec<JournalOp>>(&bytes[start..end]) {
+            Ok(ops) => batches.push(ops),
+            Err(_) => break,
+        }
+        offset = end;
+    }
+    Ok(Replay { generation, format_version, batches, valid_len: offset as u64 })
+}
+
+// a missing journal is the same as an empty one
+pub fn read(path: &str) -> Result<Option<Replay>, DbError> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    decode(&bytes).map(Some)
+}
+
+// swaps in an empty journal for the given generation, written aside and renamed like the snapshot
+pub fn reset(path: &str, generation: u64) -> Result<u64, DbError> {
+    let temp_path = format!("{}.tmp", path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&encode_header(generation))?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, path)?;
+    Ok(HEADER_LEN)
+}
+
+// cuts off whatever torn tail a crash left behind before adding the new frame
+pub fn append(path: &str, valid_len: u64, ops: &[JournalOp]) -> Result<u64, DbError> {
+    let frame = encode_frame(ops);
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(valid_len)?;
+    file.sync_all()?;
+    drop(file);
+
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&frame)?;
+    file.sync_all()?;
+    Ok(valid_len + frame.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(batches: &[Vec<JournalOp>]) -> Vec<u8> {
+        let mut bytes = encode_header(7);
+        for ops in batches {
+            bytes.extend_from_slice(&encode_frame(ops));
+        }
+        bytes
+    }
+
+    fn batches() -> Vec<Vec<JournalOp>> {
+        vec![
+            vec![JournalOp::DeleteUser("patient1".to_string()), JournalOp::DeleteDrug(3)],
+            vec![JournalOp::SetSection(Section::Map, vec![1, 2, 3]), JournalOp::DeleteRecord(Section::Clinics, "Heart".to_string())],
+        ]
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let bytes = journal(&batches());
+        let replay = decode(&bytes).unwrap();
+        assert_eq!(replay.generation, 7);
+        assert_eq!(replay.format_version, FORMAT_VERSION);
+        assert_eq!(replay.batches.len(), 2);
+        assert!(matches!(replay.batches[0][1], JournalOp::DeleteDrug(3)));
+        assert!(matches!(replay.batches[1][0], JournalOp::SetSection(Section::Map, _)));
+        assert!(matches!(replay.batches[1][1], JournalOp::DeleteRecord(Section::Clinics, ref key) if key == "Heart"));
+        assert_eq!(replay.valid_len, bytes.len() as u64);
+
+        let replay = decode(&encode_header(1)).unwrap();
+        assert!(replay.batches.is_empty());
+        assert_eq!(replay.valid_len, HEADER_LEN);
+    }
+
+    #[test]
+    fn test_torn_tail_is_dropped() {
+        let bytes = journal(&batches());
+        let first_len = HEADER_LEN as usize + encode_frame(&batches()[0]).len();
+        for cut in first_len..bytes.len() {
+            let replay = decode(&bytes[..cut]).unwrap();
+            assert_eq!(replay.batches.len(), 1);
+            assert_eq!(replay.valid_len, first_len as u64);
+        }
+
+        // a flipped bit in the last frame drops that frame only
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        let replay = decode(&corrupt).unwrap();
+        assert_eq!(replay.batches.len(), 1);
+        assert_eq!(replay.valid_len, first_len as u64);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(decode(b"HOSP").is_err());
+        let mut bytes = journal(&batches());
+        bytes[0] = b'X';
+        assert!(decode(&bytes).is_err());
+    }
+}
@@ -1,2 +1,13 @@
+pub mod audit;
 pub mod db_handler;
-pub mod entities;
\ No newline at end of file
+pub mod drug_csv;
+pub mod entities;
+pub mod error;
+pub mod export;
+pub mod format;
+pub mod fsck;
+pub mod index;
+pub mod journal;
+pub mod relations;
+pub mod repository;
+pub mod snapshot;
@@ -0,0 +1,164 @@
+use crate::db::error::DbError;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnDelete {
+    // dependents are cleaned up together with the record
+    Cascade,
+    // the delete is refused while there are dependents
+    Restrict,
+}
+
+// records that point at another one by name or id
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relation {
+    // drug ids in drug groups
+    DrugInGroup,
+    // usernames in clinics
+    DoctorInClinic,
+    // patients waiting in a doctor's queue
+    DoctorsQueue,
+    // a patient waiting in someone's queue
+    PatientInQueue,
+    PatientPrescription,
+    // ambulances currently at a location
+    AmbulanceAtLocation,
+    // ambulances whose home hospital a location is
+    AmbulanceBase,
+    // routes from or to a location
+    RouteToLocation,
+}
+
+pub const DELETE_RULES: [(Relation, OnDelete); 8] = [
+    (Relation::DrugInGroup, OnDelete::Cascade),
+    (Relation::DoctorInClinic, OnDelete::Cascade),
+    (Relation::DoctorsQueue, OnDelete::Restrict),
+    (Relation::PatientInQueue, OnDelete::Cascade),
+    (Relation::PatientPrescription, OnDelete::Cascade),
+    (Relation::AmbulanceAtLocation, OnDelete::Restrict),
+    (Relation::AmbulanceBase, OnDelete::Restrict),
+    (Relation::RouteToLocation, OnDelete::Cascade),
+];
+
+impl Relation {
+    pub fn on_delete(self) -> OnDelete {
+        DELETE_RULES.iter().find(|(relation, _)| *relation == self).map(|(_, rule)| *rule).unwrap()
+    }
+
+    fn describe(self, dependent: &str) -> String {
+        match self {
+            Relation::DrugInGroup => format!("drug group {}", dependent),
+            Relation::DoctorInClinic => format!("clinic {}", dependent),
+            Relation::DoctorsQueue => format!("{} waiting in their queue", dependent),
+            Relation::PatientInQueue => format!("the queue of {}", dependent),
+            Relation::PatientPrescription => format!("the prescription of {}", dependent),
+            Relation::AmbulanceAtLocation => format!("ambulance {} parked there", dependent),
+            Relation::AmbulanceBase => format!("ambulance {} based there", dependent),
+            Relation::RouteToLocation => format!("route {}", dependent),
+        }
+    }
+}
+
+// called before anything is deleted, so a refused delete leaves everything as it was
+pub fn check_delete(entity: &'static str, key: &str, dependents: &[(Relation, Vec<String>)]) -> Result<(), DbError> {
+    let blocking = dependents.iter()
+        .filter(|(relation, _)| relation.on_delete() == OnDelete::Restrict)
+        .flat_map(|(relation, keys)| keys.iter().map(|key| relation.describe(key)))
+        .collect::<Vec<_>>();
+    if blocking.is_empty() {
+        return Ok(());
+    }
+    Err(DbError::InUse { entity, key: key.to_string(), dependents: blocking })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::linked_list::LinkedList;
+    use crate::data_structures::map::{LocationType, Object};
+    use crate::data_structures::priority_queue::PriorityQueue;
+    use crate::db::db_handler::Database;
+    use crate::db::entities::{Ambulance, Clinic, DoctorsList, Drug, DrugGP, Patient, Role, User};
+    use crate::db::repository::Repository;
+
+    fn user(username: &str, role: Role) -> User {
+        User::new(username.to_string(), "pw".to_string(), String::new(), String::new(), 30, role)
+    }
+
+    #[test]
+    fn test_every_relation_has_a_rule() {
+        let relations = [
+            Relation::DrugInGroup,
+            Relation::DoctorInClinic,
+            Relation::DoctorsQueue,
+            Relation::PatientInQueue,
+            Relation::PatientPrescription,
+            Relation::AmbulanceAtLocation,
+            Relation::AmbulanceBase,
+            Relation::RouteToLocation,
+        ];
+        for relation in relations {
+            assert!(DELETE_RULES.iter().any(|(ruled, _)| *ruled == relation));
+        }
+    }
+
+    #[test]
+    fn test_remove_drug_cascades_to_groups() {
+        let mut db = Database::new();
+        db.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        db.drugs_mut().insert(Drug::new(1, "Ibuprofen".to_string(), 12.5, 10)).unwrap();
+        let mut drugs = LinkedList::new();
+        drugs.insert(0);
+        drugs.insert(1);
+        db.drug_groups_mut().insert(DrugGP { name: "Painkillers".to_string(), drugs }).unwrap();
+
+        assert_eq!(db.remove_drug(0).unwrap().name, "Aspirin");
+        assert_eq!(db.drug_groups().get("Painkillers").unwrap().drugs.iter().collect::<Vec<_>>(), vec![&1]);
+        assert!(matches!(db.remove_drug(0), Err(DbError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_remove_doctor_with_waiting_patients_is_refused() {
+        let mut db = Database::new();
+        db.users_mut().insert(user("doc1", Role::Doctor)).unwrap();
+        db.users_mut().insert(user("patient1", Role::Patient)).unwrap();
+        let mut patients = PriorityQueue::new();
+        patients.insert(Patient { name: "patient1".to_string(), priority: 1 });
+        db.doctors_lists_mut().insert(DoctorsList { doctor: "doc1".to_string(), patients }).unwrap();
+        let mut doctors = LinkedList::new();
+        doctors.insert("doc1".to_string());
+        db.clinics_mut().insert(Clinic { name: "Clinic A".to_string(), doctors }).unwrap();
+
+        let refused = db.remove_user("doc1".to_string()).unwrap_err();
+        assert_eq!(refused.to_string(), "Can't delete user 'doc1', it is still referenced by: patient1 waiting in their queue");
+        assert!(db.users().contains("doc1"));
+
+        // the patient goes from the queue, after that the doctor can go and leaves the clinic
+        db.remove_user("patient1".to_string()).unwrap();
+        assert!(db.doctors_lists().get("doc1").unwrap().patients.is_empty());
+        db.remove_user("doc1".to_string()).unwrap();
+        assert!(!db.doctors_lists().contains("doc1"));
+        assert!(db.clinics().get("Clinic A").unwrap().doctors.is_empty());
+    }
+
+    #[test]
+    fn test_remove_location() {
+        let mut db = Database::new();
+        db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+        db.map_mut().add_node("Home A".to_string(), LocationType::Home);
+        db.map_mut().add_edge("Hospital A".to_string(), "Home A".to_string());
+        db.map_mut().add_edge("Home A".to_string(), "Hospital A".to_string());
+        db.ambulances_mut().insert(Ambulance::new("Ambulance A".to_string(), "Hospital A".to_string(), "Home A".to_string())).unwrap();
+        db.map_mut().add_object_to_node("Home A", Object { name: "Ambulance A".to_string() });
+
+        let refused = db.remove_location("Home A").unwrap_err();
+        assert!(matches!(&refused, DbError::InUse { dependents, .. } if *dependents == vec!["ambulance Ambulance A parked there".to_string()]));
+        assert!(matches!(db.remove_location("Hospital A"), Err(DbError::InUse { .. })));
+        assert!(matches!(db.remove_location("Home B"), Err(DbError::NotFound { .. })));
+
+        assert!(db.remove_ambulance("Ambulance A".to_string()));
+        assert!(db.map().nodes.get("Home A").unwrap().objects.is_empty());
+        db.remove_location("Home A").unwrap();
+        assert!(db.map().edges.get("Hospital A").unwrap().is_empty());
+    }
+}
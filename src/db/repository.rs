@@ -0,0 +1,215 @@
+use std::fmt::Debug;
+
+use crate::data_structures::bst::TreeNode;
+use crate::data_structures::linked_list::LinkedList;
+use crate::db::entities::{Drug, UniqueAttribute};
+use crate::db::error::DbError;
+
+
+// one collection of records keyed by their unique attribute, a storage only says how to find,
+// add and take out a record and the checks on top of that are shared by all of them.
+// changes aren't audited here, an audit event names who made the change and that is only known to Auth,
+// so the callers write them through Auth::audit. what the journal needs is tracked by Indexed
+pub trait Repository<T: UniqueAttribute> {
+    fn get(&self, key: &str) -> Option<&T>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut T>;
+    fn iterate(&self) -> Box<dyn Iterator<Item = &T> + '_>;
+    // adds the record as is, insert() is the checked way in
+    fn store(&mut self, item: T);
+    // takes the record out as is, remove() is the checked way out
+    fn extract(&mut self, key: &str) -> Option<T>;
+
+    // the key of whatever the record would clash with
+    fn conflict(&self, item: &T) -> Option<String> {
+        let key = item.uattr();
+        self.get(&key).map(|_| key)
+    }
+
+    fn insert(&mut self, item: T) -> Result<(), DbError> {
+        if let Some(key) = self.conflict(&item) {
+            return Err(DbError::Duplicate { entity: T::ENTITY, key });
+        }
+        self.store(item);
+        Ok(())
+    }
+
+    // swaps in the new version of a record and hands back the old one
+    fn update(&mut self, item: T) -> Result<T, DbError> {
+        let key = item.uattr();
+        match self.get_mut(&key) {
+            Some(stored) => Ok(std::mem::replace(stored, item)),
+            None => Err(DbError::NotFound { entity: T::ENTITY, key }),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Result<T, DbError> {
+        self.extract(key).ok_or_else(|| DbError::NotFound { entity: T::ENTITY, key: key.to_string() })
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn count(&self) -> usize {
+        self.iterate().count()
+    }
+}
+
+// the collections are stored as they always were so the file format stays the same,
+// None and an empty list both mean no records
+impl<T: UniqueAttribute + Debug + Clone> Repository<T> for Option<LinkedList<T>> {
+    fn get(&self, key: &str) -> Option<&T> {
+        self.as_ref()?.iter().find(|item| item.uattr() == key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        self.as_mut()?.get_by_uniq_attr(key.to_string())
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter().flat_map(|list| list.iter()))
+    }
+
+    fn store(&mut self, item: T) {
+        self.get_or_insert_with(LinkedList::new).insert(item);
+    }
+
+    fn extract(&mut self, key: &str) -> Option<T> {
+        let item = self.get(key)?.clone();
+        self.as_mut()?.remove_by_uniq_attr(key.to_string());
+        Some(item)
+    }
+
+    fn count(&self) -> usize {
+        self.as_ref().map_or(0, |list| list.len())
+    }
+}
+
+impl<T: UniqueAttribute + Ord + Debug + Clone> Repository<T> for Option<TreeNode<T>> {
+    fn get(&self, key: &str) -> Option<&T> {
+        self.as_ref()?.get_by_uniq_attr(key.to_string())
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        self.as_mut()?.get_by_uniq_attr_mut(key.to_string())
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.iter().flat_map(|root| root.iter()))
+    }
+
+    fn store(&mut self, item: T) {
+        match self {
+            Some(root) => root.insert(item),
+            None => *self = Some(TreeNode::new(item)),
+        }
+    }
+
+    fn extract(&mut self, key: &str) -> Option<T> {
+        let item = self.get(key)?.clone();
+        *self = TreeNode::remove_by_uniq_attr(Option::take(self).map(Box::new), key.to_string()).map(|node| *node);
+        Some(item)
+    }
+}
+
+// drugs are ordered by id and their names have to be unique too, held bare the name is checked by walking
+// the tree, Database's Indexed looks it up in the name index instead
+impl Repository<Drug> for Option<Box<TreeNode<Drug>>> {
+    fn get(&self, key: &str) -> Option<&Drug> {
+        self.as_ref()?.get_drug_by_id(key.parse().ok()?)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Drug> {
+        self.as_mut()?.get_drug_by_id_mut(key.parse().ok()?)
+    }
+
+    fn iterate(&self) -> Box<dyn Iterator<Item = &Drug> + '_> {
+        Box::new(self.iter().flat_map(|root| root.iter()))
+    }
+
+    fn store(&mut self, drug: Drug) {
+        match self {
+            Some(root) => {
+                root.insert(drug);
+                root.balance();
+            },
+            None => *self = Some(Box::new(TreeNode::new(drug))),
+        }
+    }
+
+    fn extract(&mut self, key: &str) -> Option<Drug> {
+        let drug = self.get(key)?.clone();
+        *self = TreeNode::remove_drug_by_id(Option::take(self), drug.id);
+        Some(drug)
+    }
+
+    fn conflict(&self, drug: &Drug) -> Option<String> {
+        let name_taken = self.as_ref().is_some_and(|root| root.get_drug_by_name(drug.name.clone()).is_some());
+        (self.contains(&drug.uattr()) || name_taken).then(|| format!("{} (id {})", drug.name, drug.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::{Clinic, Role, User};
+
+    fn user(username: &str) -> User {
+        User::new(username.to_string(), "pw".to_string(), String::new(), String::new(), 30, Role::Patient)
+    }
+
+    #[test]
+    fn test_list_repository() {
+        let mut clinics: Option<LinkedList<Clinic>> = None;
+        assert_eq!(Repository::count(&clinics), 0);
+        Repository::insert(&mut clinics, Clinic { name: "Heart".to_string(), doctors: LinkedList::new() }).unwrap();
+        Repository::insert(&mut clinics, Clinic { name: "Eyes".to_string(), doctors: LinkedList::new() }).unwrap();
+        let duplicate = Repository::insert(&mut clinics, Clinic { name: "Heart".to_string(), doctors: LinkedList::new() }).unwrap_err();
+        assert_eq!(duplicate.to_string(), "A clinic named 'Heart' already exists");
+
+        let mut doctors = LinkedList::new();
+        doctors.insert("doc1".to_string());
+        let old = clinics.update(Clinic { name: "Heart".to_string(), doctors }).unwrap();
+        assert!(old.doctors.is_empty());
+        assert_eq!(clinics.get("Heart").unwrap().doctors.len(), 1);
+        assert!(matches!(clinics.update(Clinic { name: "Lungs".to_string(), doctors: LinkedList::new() }), Err(DbError::NotFound { .. })));
+
+        assert_eq!(clinics.remove("Eyes").unwrap().name, "Eyes");
+        assert!(matches!(clinics.remove("Eyes"), Err(DbError::NotFound { entity: "clinic", .. })));
+        assert_eq!(clinics.iterate().map(|clinic| clinic.name.as_str()).collect::<Vec<_>>(), vec!["Heart"]);
+    }
+
+    #[test]
+    fn test_tree_repository() {
+        let mut users: Option<TreeNode<User>> = None;
+        for username in ["bob", "alice", "carol"] {
+            Repository::insert(&mut users, user(username)).unwrap();
+        }
+        assert!(matches!(Repository::insert(&mut users, user("alice")), Err(DbError::Duplicate { entity: "user", .. })));
+        users.get_mut("carol").unwrap().age = 40;
+        assert_eq!(users.get("carol").unwrap().age, 40);
+
+        assert_eq!(users.remove("bob").unwrap().username, "bob");
+        assert_eq!(users.iterate().map(|user| user.username.as_str()).collect::<Vec<_>>(), vec!["alice", "carol"]);
+        assert_eq!(users.count(), 2);
+        users.remove("alice").unwrap();
+        users.remove("carol").unwrap();
+        assert!(users.is_none());
+    }
+
+    #[test]
+    fn test_drug_repository() {
+        let mut drugs: Option<Box<TreeNode<Drug>>> = None;
+        for (id, name) in [(10, "Aspirin"), (9, "Ibuprofen"), (2, "Paracetamol")] {
+            Repository::insert(&mut drugs, Drug::new(id, name.to_string(), 1.0, 5)).unwrap();
+        }
+        let same_name = Repository::insert(&mut drugs, Drug::new(3, "Aspirin".to_string(), 1.0, 5)).unwrap_err();
+        assert_eq!(same_name.to_string(), "A drug named 'Aspirin (id 3)' already exists");
+        assert!(Repository::insert(&mut drugs, Drug::new(9, "Other".to_string(), 1.0, 5)).is_err());
+
+        assert_eq!(drugs.get("10").unwrap().name, "Aspirin");
+        assert!(drugs.get("ten").is_none());
+        assert_eq!(drugs.remove("9").unwrap().name, "Ibuprofen");
+        assert_eq!(drugs.iterate().map(|drug| drug.id).collect::<Vec<_>>(), vec![2, 10]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::data_structures::hash_map::HashMap;
+use crate::db::db_handler::Database;
+use crate::db::error::DbError;
+use crate::db::export::Export;
+
+
+pub const SNAPSHOT_DIR: &str = "snapshots";
+const EXTENSION: &str = ".snapshot";
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+const STAMP_LEN: usize = 15;
+const MAX_NAME_LEN: usize = 64;
+
+// a snapshot is the file <dir>/<taken at>-<name>.snapshot, listing them needs nothing but the directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub taken_at: NaiveDateTime,
+    pub path: String,
+}
+
+impl SnapshotInfo {
+    pub fn new(dir: &str, name: &str, taken_at: NaiveDateTime) -> Self {
+        let file_name = format!("{}-{}{}", taken_at.format(STAMP_FORMAT), name, EXTENSION);
+        SnapshotInfo {
+            name: name.to_string(),
+            taken_at,
+            path: Path::new(dir).join(file_name).to_string_lossy().to_string(),
+        }
+    }
+
+    fn from_file_name(dir: &str, file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(EXTENSION)?;
+        let name = stem.get(STAMP_LEN..)?.strip_prefix('-')?;
+        let taken_at = NaiveDateTime::parse_from_str(stem.get(..STAMP_LEN)?, STAMP_FORMAT).ok()?;
+        if !valid_name(name) {
+            return None;
+        }
+        Some(SnapshotInfo::new(dir, name, taken_at))
+    }
+
+    // unique even when the same name is used twice
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.taken_at.format(STAMP_FORMAT), self.name)
+    }
+}
+
+impl fmt::Display for SnapshotInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (taken {})", self.name, self.taken_at.format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+// names end up in a file name, so no separators, dots or spaces
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// oldest first, a directory that doesn't exist yet just has no snapshots
+pub fn list(dir: &str) -> Result<Vec<SnapshotInfo>, DbError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        if let Some(snapshot) = SnapshotInfo::from_file_name(dir, &file_name.to_string_lossy()) {
+            snapshots.push(snapshot);
+        }
+    }
+    snapshots.sort_by(|a, b| a.taken_at.cmp(&b.taken_at).then_with(|| a.name.cmp(&b.name)));
+    Ok(snapshots)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangedRecord {
+    pub kind: &'static str,
+    pub key: String,
+    pub change: RecordChange,
+}
+
+impl fmt::Display for ChangedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = match self.change {
+            RecordChange::Added => '+',
+            RecordChange::Removed => '-',
+            RecordChange::Changed => '~',
+        };
+        write!(f, "{} {} {}", sign, self.kind, self.key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityCount {
+    pub kind: &'static str,
+    pub before: usize,
+    pub after: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub counts: Vec<EntityCount>,
+    pub records: Vec<ChangedRecord>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+fn keyed<T: Serialize>(items: &[T], key: impl Fn(&T) -> String) -> Vec<(String, String)> {
+    items.iter().map(|item| (key(item), serde_json::to_string(item).unwrap())).collect()
+}
+
+// every record as (key, contents) per kind, compared through the export so both sides are in the same plain shape
+fn records(export: &Export) -> Vec<(&'static str, Vec<(String, String)>)> {
+    vec![
+        ("users", keyed(&export.users, |user| user.username.clone())),
+        ("clinics", keyed(&export.clinics, |clinic| clinic.name.clone())),
+        ("queues", keyed(&export.queues, |queue| queue.doctor.clone())),
+        ("prescriptions", keyed(&export.prescriptions, |prescription| prescription.patient_name.clone())),
+        ("drugs", keyed(&export.drugs, |drug| format!("#{} {}", drug.id, drug.name))),
+        ("drug groups", keyed(&export.drug_groups, |group| group.name.clone())),
+        ("locations", keyed(&export.locations, |location| location.name.clone())),
+        ("ambulances", keyed(&export.ambulances, |ambulance| ambulance.name.clone())),
+        ("role permissions", keyed(&export.role_permissions, |entry| format!("{:?}", entry.role))),
+    ]
+}
+
+// what changed going from one state to the other, the audit log only ever grows so it is counted but not listed
+pub fn diff(before: &Database, after: &Database) -> Diff {
+    let (before, after) = (Export::from_database(before), Export::from_database(after));
+    let mut diff = Diff::default();
+    for ((kind, old), (_, new)) in records(&before).into_iter().zip(records(&after)) {
+        diff.counts.push(EntityCount { kind, before: old.len(), after: new.len() });
+        let mut remaining = HashMap::new();
+        for (key, contents) in new.iter() {
+            remaining.insert(key.clone(), contents.clone());
+        }
+        for (key, contents) in old {
+            let change = match remaining.remove(&key) {
+                Some(current) if current == contents => continue,
+                Some(_) => RecordChange::Changed,
+                None => RecordChange::Removed,
+            };
+            diff.records.push(ChangedRecord { kind, key, change });
+        }
+        for (key, _) in new.into_iter().filter(|(key, _)| remaining.contains_key(key)) {
+            diff.records.push(ChangedRecord { kind, key, change: RecordChange::Added });
+        }
+    }
+    diff.counts.push(EntityCount { kind: "audit events", before: before.audit_log.len(), after: after.audit_log.len() });
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::data_structures::map::LocationType;
+    use crate::db::entities::{Drug, DrugGP};
+    use crate::db::repository::Repository;
+    use crate::data_structures::linked_list::LinkedList;
+
+    fn time(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, 18).unwrap().and_hms_opt(hour, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn test_file_names() {
+        let snapshot = SnapshotInfo::new("snapshots", "before-cleanup", time(9));
+        assert_eq!(snapshot.id(), "20261018-093000-before-cleanup");
+        assert_eq!(snapshot.path, Path::new("snapshots").join("20261018-093000-before-cleanup.snapshot").to_string_lossy());
+        assert_eq!(SnapshotInfo::from_file_name("snapshots", "20261018-093000-before-cleanup.snapshot"), Some(snapshot));
+
+        assert_eq!(SnapshotInfo::from_file_name("snapshots", "20261018-093000-before-cleanup.snapshot.tmp"), None);
+        assert_eq!(SnapshotInfo::from_file_name("snapshots", "notes.snapshot"), None);
+        assert_eq!(SnapshotInfo::from_file_name("snapshots", "20261018-093000-.snapshot"), None);
+        assert!(!valid_name("../database"));
+        assert!(!valid_name("night shift"));
+        assert!(valid_name("nightly_2"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut before = Database::new();
+        before.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+        before.drugs_mut().insert(Drug::new(1, "Ibuprofen".to_string(), 12.5, 10)).unwrap();
+        before.drug_groups_mut().insert(DrugGP { name: "Painkillers".to_string(), drugs: LinkedList::new() }).unwrap();
+        before.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+
+        let mut after = before.clone();
+        after.drugs_mut().get_mut("0").unwrap().quantity = 45;
+        after.drug_groups_mut().remove("Painkillers").unwrap();
+        after.map_mut().add_node("Home A".to_string(), LocationType::Home);
+
+        let diff = diff(&before, &after);
+        assert_eq!(diff.records, vec![
+            ChangedRecord { kind: "drugs", key: "#0 Aspirin".to_string(), change: RecordChange::Changed },
+            ChangedRecord { kind: "drug groups", key: "Painkillers".to_string(), change: RecordChange::Removed },
+            ChangedRecord { kind: "locations", key: "Home A".to_string(), change: RecordChange::Added },
+        ]);
+        assert!(diff.counts.contains(&EntityCount { kind: "drugs", before: 2, after: 2 }));
+        assert!(diff.counts.contains(&EntityCount { kind: "locations", before: 1, after: 2 }));
+        assert_eq!(diff.records[2].to_string(), "+ locations Home A");
+        assert!(super::diff(&before, &before.clone()).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use rand::RngCore;
+
+use crate::password_hasher::{constant_time_eq, pbkdf2_sha256};
+use crate::sha_hasher::HmacSha256;
+
+// sealed format: nonce || ciphertext || tag
+// the keystream is HMAC-SHA256(enc_key, nonce || block counter), the tag is HMAC-SHA256(mac_key, nonce || ciphertext)
+pub const KEY_ITERATIONS: u32 = 100_000;
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct FieldKey {
+    enc_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl std::fmt::Debug for FieldKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "FieldKey {{ .. }}")
+    }
+}
+
+pub fn generate_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+impl FieldKey {
+    pub fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Self {
+        let material = pbkdf2_sha256(passphrase.as_bytes(), salt, iterations, 64);
+        let mut enc_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        enc_key.copy_from_slice(&material[..32]);
+        mac_key.copy_from_slice(&material[32..]);
+        FieldKey { enc_key, mac_key }
+    }
+
+    // stored next to the data so a wrong passphrase is caught before anything gets decrypted
+    pub fn check_value(&self) -> String {
+        let mut mac = HmacSha256::new(&self.mac_key);
+        mac.update(b"field key check");
+        hex::encode(mac.finalize())
+    }
+
+    fn apply_keystream(&self, nonce: &[u8], data: &mut [u8]) {
+        for (counter, chunk) in data.chunks_mut(32).enumerate() {
+            let mut mac = HmacSha256::new(&self.enc_key);
+            mac.update(nonce);
+            mac.update(&(counter as u64).to_be_bytes());
+            for (byte, key) in chunk.iter_mut().zip(mac.finalize().iter()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    fn tag(&self, nonce: &[u8], ciphertext: &[u8]) -> [u8; 32] {
+        let mut mac = HmacSha256::new(&self.mac_key);
+        mac.update(nonce);
+        mac.update(ciphertext);
+        mac.finalize()
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut ciphertext = plaintext.to_vec();
+        self.apply_keystream(&nonce, &mut ciphertext);
+        let tag = self.tag(&nonce, &ciphertext);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    // None when the data was tampered with or sealed under another key
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        if !constant_time_eq(&self.tag(nonce, ciphertext), tag) {
+            return None;
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        self.apply_keystream(nonce, &mut plaintext);
+        Some(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(passphrase: &str) -> FieldKey {
+        FieldKey::derive(passphrase, b"0123456789abcdef", 10)
+    }
+
+    #[test]
+    fn test_seal_open_round_trip() {
+        let key = key("correct horse");
+        let plaintext = b"123-45-6789 and a value longer than a single keystream block";
+        let sealed = key.seal(plaintext);
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + plaintext.len()], plaintext);
+        assert_eq!(key.open(&sealed).unwrap(), plaintext);
+
+        // fresh nonce every time
+        assert_ne!(key.seal(plaintext), sealed);
+    }
+
+    #[test]
+    fn test_wrong_key_and_tampering() {
+        let sealed = key("correct horse").seal(b"123-45-6789");
+        assert!(key("battery staple").open(&sealed).is_none());
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(key("correct horse").open(&tampered).is_none());
+        assert!(key("correct horse").open(&sealed[..10]).is_none());
+    }
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(key("correct horse").check_value(), key("correct horse").check_value());
+        assert_ne!(key("correct horse").check_value(), key("battery staple").check_value());
+    }
+}
@@ -4,39 +4,42 @@ mod db;
 mod data_structures;
 mod menus_logic;
 mod sha_hasher;
+mod password_hasher;
+mod permissions;
+mod sha1_hasher;
+mod totp;
+mod session;
+mod field_cipher;
+mod config;
 
 use cli_handler::{admin_menu, doctor_menu, emergency_doctor_menu, patient_menu, pharmacist_menu, triage_supervisor_menu};
-use data_structures::{linked_list::LinkedList, map::{LocationType, Object}};
-use db::{db_handler::Database, entities::{Ambulance, Clinic, Drug, DrugGP, Role}};
+use data_structures::{linked_list::LinkedList, map::{LocationType, Object}, max_heap::set_max_heap_size};
+use config::Config;
+use db::{audit::{AuditAction, AuditEvent, EntityType}, db_handler::{backup_path, Database}, error::DbError, export::{Export, ImportMode, ImportSummary}, format, fsck, journal, repository::Repository, entities::{AccountStatus, Ambulance, Clinic, Drug, DrugGP, Role, User}};
 use auth::Auth;
 
 
 fn test_data(auth: &mut Auth) {
     // Insert two patients for testing
-    auth.signup("patient1".to_string(), "password1".to_string(), "John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Patient).unwrap();
+    auth.signup("patient1".to_string(), "password1".to_string(), "John Doe".to_string(), "123-45-6789".to_string(), 30).unwrap();
     auth.logout();
     // auth.register("patient2".to_string(), "password2".to_string(), "Jane Smith".to_string(), "987-65-4321".to_string(), 25, Role::Patient).unwrap();
 
     // Insert two doctors for testing
-    auth.signup("doc1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Doctor).unwrap();
-    auth.logout();
+    auth.register("doc1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Doctor).unwrap();
     // auth.register("doc2".to_string(), "password2".to_string(), "Dr. Jane Smith".to_string(), "987-65-4321".to_string(), 25, Role::Doctor).unwrap();
 
     // insert a pharmacist for testing
-    auth.signup("pharmacist1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Pharmacist).unwrap();
-    auth.logout();
+    auth.register("pharmacist1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Pharmacist).unwrap();
 
     // insert a triage supervisor for testing
-    auth.signup("tir1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::TriageSupervisor).unwrap();
-    auth.logout();
+    auth.register("tir1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::TriageSupervisor).unwrap();
 
     // insert an emergency doctor for testing
-    auth.signup("emdoc1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::EmergencyDoctor).unwrap();
-    auth.logout();
+    auth.register("emdoc1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::EmergencyDoctor).unwrap();
 
     // insert an admin for testing
-    auth.signup("admin1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Admin).unwrap();
-    auth.logout();
+    auth.register("admin1".to_string(), "password1".to_string(), "Dr. John Doe".to_string(), "123-45-6789".to_string(), 30, Role::Admin).unwrap();
 
     // Insert two clinics for testing
     let mut doctors1 = LinkedList::new();
@@ -44,79 +47,359 @@ fn test_data(auth: &mut Auth) {
     doctors1.insert("emdoc1".to_string());
     // let mut doctors2 = LinkedList::new();
     // doctors2.insert("doc2".to_string());
-    auth.db.insert_clinic(Clinic { name: "Clinic A".to_string(), doctors: doctors1}).unwrap();
-    // auth.db.insert_clinic(Clinic { name: "Clinic B".to_string(), doctors: doctors2 }).unwrap();
+    auth.db.clinics_mut().insert(Clinic { name: "Clinic A".to_string(), doctors: doctors1}).unwrap();
+    // auth.db.clinics_mut().insert(Clinic { name: "Clinic B".to_string(), doctors: doctors2 }).unwrap();
 
     // Insert some drugs for testing
-    auth.db.insert_drug(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
-    auth.db.insert_drug(Drug::new(1, "Ibuprofen".to_string(), 12.99, 100)).unwrap();
-    auth.db.insert_drug(Drug::new(2, "Paracetamol".to_string(), 9.99, 200)).unwrap();
-    auth.db.insert_drug(Drug::new(3, "Amoxicillin".to_string(), 19.99, 30)).unwrap();
-    auth.db.insert_drug(Drug::new(4, "Azithromycin".to_string(), 29.99, 20)).unwrap();
-    auth.db.insert_drug(Drug::new(5, "Ciprofloxacin".to_string(), 39.99, 10)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(0, "Aspirin".to_string(), 32.99, 50)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(1, "Ibuprofen".to_string(), 12.99, 100)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(2, "Paracetamol".to_string(), 9.99, 200)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(3, "Amoxicillin".to_string(), 19.99, 30)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(4, "Azithromycin".to_string(), 29.99, 20)).unwrap();
+    auth.db.drugs_mut().insert(Drug::new(5, "Ciprofloxacin".to_string(), 39.99, 10)).unwrap();
 
     // Insert some drug groups for testing
     let mut drugs1 = LinkedList::new();
     drugs1.insert(0);
     drugs1.insert(1);
     drugs1.insert(2);
-    auth.db.insert_drug_gp(DrugGP { name: "Painkiller".to_string(), drugs: drugs1 }).unwrap();
+    auth.db.drug_groups_mut().insert(DrugGP { name: "Painkiller".to_string(), drugs: drugs1 }).unwrap();
 
     let mut drugs2 = LinkedList::new();
     drugs2.insert(3);
     drugs2.insert(4);
-    auth.db.insert_drug_gp(DrugGP { name: "Antibiotics".to_string(), drugs: drugs2 }).unwrap();
+    auth.db.drug_groups_mut().insert(DrugGP { name: "Antibiotics".to_string(), drugs: drugs2 }).unwrap();
 
     // insert some locations for testing
-    auth.db.map.add_node("Hospital A".to_string(), LocationType::Hospital);
-    auth.db.map.add_node("Hospital B".to_string(), LocationType::Hospital);
-    auth.db.map.add_node("Home A".to_string(), LocationType::Home);
-    auth.db.map.add_node("Home B".to_string(), LocationType::Home);
-    auth.db.map.add_node("Other A".to_string(), LocationType::Other);
-    auth.db.map.add_node("Other B".to_string(), LocationType::Other);
+    auth.db.map_mut().add_node("Hospital A".to_string(), LocationType::Hospital);
+    auth.db.map_mut().add_node("Hospital B".to_string(), LocationType::Hospital);
+    auth.db.map_mut().add_node("Home A".to_string(), LocationType::Home);
+    auth.db.map_mut().add_node("Home B".to_string(), LocationType::Home);
+    auth.db.map_mut().add_node("Other A".to_string(), LocationType::Other);
+    auth.db.map_mut().add_node("Other B".to_string(), LocationType::Other);
 
     // insert some edges for testing
-    auth.db.map.add_edge("Hospital A".to_string(), "Hospital B".to_string());
-    auth.db.map.add_edge("Hospital A".to_string(), "Home A".to_string());
-    auth.db.map.add_edge("Hospital A".to_string(), "Other A".to_string());
-    auth.db.map.add_edge("Hospital B".to_string(), "Home B".to_string());
-    auth.db.map.add_edge("Hospital B".to_string(), "Other B".to_string());
-    auth.db.map.add_edge("Home A".to_string(), "Home B".to_string());
-    auth.db.map.add_edge("Home A".to_string(), "Other A".to_string());
-    auth.db.map.add_edge("Home B".to_string(), "Other B".to_string());
-    auth.db.map.add_edge("Other B".to_string(), "Home B".to_string());
+    auth.db.map_mut().add_edge("Hospital A".to_string(), "Hospital B".to_string());
+    auth.db.map_mut().add_edge("Hospital A".to_string(), "Home A".to_string());
+    auth.db.map_mut().add_edge("Hospital A".to_string(), "Other A".to_string());
+    auth.db.map_mut().add_edge("Hospital B".to_string(), "Home B".to_string());
+    auth.db.map_mut().add_edge("Hospital B".to_string(), "Other B".to_string());
+    auth.db.map_mut().add_edge("Home A".to_string(), "Home B".to_string());
+    auth.db.map_mut().add_edge("Home A".to_string(), "Other A".to_string());
+    auth.db.map_mut().add_edge("Home B".to_string(), "Other B".to_string());
+    auth.db.map_mut().add_edge("Other B".to_string(), "Home B".to_string());
 
     // insert some ambulances for testing
-    auth.db.insert_ambulance(Ambulance { name: "Ambulance A".to_string(), hospital: "Hospital A".to_string(), location: "Hospital A".to_string() }).unwrap();
-    auth.db.map.add_object_to_node("Hospital A", Object { name: "Ambulance A".to_string() });
-    auth.db.insert_ambulance(Ambulance { name: "Ambulance B".to_string(), hospital: "Hospital B".to_string(), location: "Hospital B".to_string() }).unwrap();
-    auth.db.map.add_object_to_node("Hospital B", Object { name: "Ambulance B".to_string() });
-    auth.db.insert_ambulance(Ambulance { name: "Ambulance C".to_string(), hospital: "Hospital A".to_string(), location: "Other B".to_string() }).unwrap();
-    auth.db.map.add_object_to_node("Other B", Object { name: "Ambulance C".to_string() });
+    auth.db.ambulances_mut().insert(Ambulance { name: "Ambulance A".to_string(), hospital: "Hospital A".to_string(), location: "Hospital A".to_string() }).unwrap();
+    auth.db.map_mut().add_object_to_node("Hospital A", Object { name: "Ambulance A".to_string() });
+    auth.db.ambulances_mut().insert(Ambulance { name: "Ambulance B".to_string(), hospital: "Hospital B".to_string(), location: "Hospital B".to_string() }).unwrap();
+    auth.db.map_mut().add_object_to_node("Hospital B", Object { name: "Ambulance B".to_string() });
+    auth.db.ambulances_mut().insert(Ambulance { name: "Ambulance C".to_string(), hospital: "Hospital A".to_string(), location: "Other B".to_string() }).unwrap();
+    auth.db.map_mut().add_object_to_node("Other B", Object { name: "Ambulance C".to_string() });
     
     auth.db.commit().unwrap();
 }
 
+// --check-format: reports the file's format version and what loading it would migrate, without touching it
+fn check_format(filename: &str) -> Result<(), DbError> {
+    let bytes = std::fs::read(filename)?;
+    let (version, _) = format::split(&bytes);
+    println!("{}: format version {}, this build writes format {}", filename, version, format::FORMAT_VERSION);
+    let migrations = format::pending_migrations(version)?;
+    if migrations.is_empty() {
+        println!("Up to date, no migration would run");
+    }
+    for migration in migrations {
+        println!("Would migrate from format {} to {}: {}", migration.from, migration.from + 1, migration.description);
+    }
+    if let Some(replay) = journal::read(&journal::journal_path(filename))? {
+        println!("Journal: generation {}, format {}, {} commits not yet compacted", replay.generation, replay.format_version, replay.batches.len());
+    }
+    Ok(())
+}
+
+// --salvage: keeps whatever still decodes from a damaged database, the damaged file is copied aside first
+fn salvage_database(filename: &str) -> Database {
+    let (db, report) = match Database::salvage_from_file(filename) {
+        Ok(salvaged) => salvaged,
+        Err(e) => {
+            println!("Could not salvage {}: {}", filename, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Recovered: {}", if report.recovered.is_empty() { "nothing".to_string() } else { report.recovered.join(", ") });
+    if report.lost.is_empty() {
+        println!("Nothing was lost");
+    } else {
+        println!("Lost: {}", report.lost.join(", "));
+    }
+    println!("Replayed {} journaled commits", report.replayed_commits);
+    if db.field_key_salt.is_empty() && db.users_data.is_some() {
+        println!("The encryption key parameters were lost, personal details of every user are gone and a new passphrase will be set");
+    }
+    if cli_handler::get_input_string("Continue with the salvaged data? (y/n)".to_string()) != "y" {
+        std::process::exit(0);
+    }
+
+    let damaged_copy = format!("{}.corrupt", filename);
+    if let Err(e) = std::fs::copy(filename, &damaged_copy) {
+        println!("Could not keep a copy of the damaged file: {}", e);
+        std::process::exit(1);
+    }
+    println!("The damaged file was copied to {}", damaged_copy);
+    db
+}
+
+// --export FILE: writes the whole database as JSON, personal details included
+fn export_database(db: &mut Database, path: &str) -> Result<(), DbError> {
+    let export = Export::from_database(db);
+    std::fs::write(path, export.to_json())?;
+    let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Export, EntityType::Database, path.to_string());
+    event.after = Some(format!("{} users, {} drugs, {} audit events", export.users.len(), export.drugs.len(), export.audit_log.len()));
+    db.record_event(event);
+    db.commit()
+}
+
+// --import FILE [--merge]: loads a JSON export into an empty database, or next to the existing data with --merge
+fn import_database(db: &mut Database, path: &str, mode: ImportMode) -> Result<(), DbError> {
+    let export = Export::from_json(&std::fs::read_to_string(path)?)?;
+    let problems = export.validate(db, mode);
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            println!("  {}", problem);
+        }
+        return Err(DbError::Integrity(format!("{} problems in {}, nothing was imported", problems.len(), path)));
+    }
+    // a clash the validation didn't foresee leaves the database as it was
+    let summary = db.transaction(|db| {
+        let summary = export.apply(db, mode)?;
+        let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Import, EntityType::Database, path.to_string());
+        event.after = Some(format!("{:?}, {} records", mode, summary.records));
+        db.record_event(event);
+        Ok::<ImportSummary, DbError>(summary)
+    })?;
+    db.compact()?;
+    println!("Imported {} records from {}", summary.records, path);
+    if summary.skipped_audit_events > 0 {
+        println!("{} audit events of the export were left out, merged history can't join this database's chain", summary.skipped_audit_events);
+    }
+    Ok(())
+}
+
+// --create-admin USERNAME: sign up never hands out admin accounts, so the first one is made here by whoever runs the server
+fn create_admin(db: &mut Database, username: String, hash_iterations: u32) -> Result<(), DbError> {
+    let password = cli_handler::get_input_string("Enter a password".to_string());
+    if password.is_empty() || password != cli_handler::get_input_string("Repeat the password".to_string()) {
+        println!("Passwords don't match");
+        std::process::exit(1);
+    }
+    let full_name = cli_handler::get_input_string("Enter the admin's full name".to_string());
+    let ssn = cli_handler::get_input_string("Enter the admin's ssn".to_string());
+    let age = match cli_handler::get_input_string("Enter the admin's age".to_string()).parse::<u32>() {
+        Ok(age) => age,
+        Err(_) => {
+            println!("Invalid age");
+            std::process::exit(1);
+        }
+    };
+    let password = password_hasher::hash_password(&password, hash_iterations);
+    let user = User::new(username.clone(), password, full_name, ssn, age, Role::Admin);
+    db.transaction(|db| {
+        db.users_mut().insert(user)?;
+        let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Create, EntityType::User, username);
+        event.after = Some(format!("role: {:?}, status: {:?}", Role::Admin, AccountStatus::Active));
+        db.record_event(event);
+        Ok(())
+    })
+}
+
+// --unlock USERNAME: the way back in for a locked out admin, anyone can lock an account by guessing wrong
+fn unlock_account(db: &mut Database, username: String) -> Result<(), DbError> {
+    let user = db.users_mut().get_mut(&username).ok_or(DbError::NotFound { entity: "user", key: username.clone() })?;
+    let before = format!("status: {:?}, failed logins: {}", user.status, user.failed_logins);
+    // accounts waiting for approval only get their failed attempts cleared
+    if user.status == AccountStatus::Locked {
+        user.unlock();
+    } else {
+        user.failed_logins = 0;
+        user.last_failed_login = None;
+    }
+    let after = format!("status: {:?}", user.status);
+    let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Unlock, EntityType::User, username);
+    event.before = Some(before);
+    event.after = Some(after);
+    db.record_event(event);
+    db.commit()
+}
+
+// --fsck [--repair]: lists references that point nowhere, --repair fixes the ones with an obvious fix
+// returns how many problems are left
+fn check_references(db: &mut Database, repair: bool) -> Result<usize, DbError> {
+    let issues = fsck::check(db);
+    for issue in issues.iter() {
+        println!("  {}", issue);
+    }
+    if !repair || issues.is_empty() {
+        println!("{} problems found", issues.len());
+        return Ok(issues.len());
+    }
+    let repaired = db.transaction(|db| {
+        let mut repaired = 0;
+        for issue in issues.iter() {
+            if !fsck::repair(db, issue) {
+                continue;
+            }
+            let (entity_type, key) = issue.entity();
+            let mut event = AuditEvent::new("system".to_string(), None, AuditAction::Update, entity_type, key);
+            event.after = Some(format!("repaired: {}", issue));
+            db.record_event(event);
+            repaired += 1;
+        }
+        Ok::<usize, DbError>(repaired)
+    })?;
+    let left = fsck::check(db);
+    println!("{} problems found, {} repaired", issues.len(), repaired);
+    for issue in left.iter() {
+        println!("  needs a manual fix: {}", issue);
+    }
+    Ok(left.len())
+}
+
 fn main() {
-    let mut db = Database::load_from_file("database.bin").unwrap_or(Database::new());
+    let args = std::env::args().collect::<Vec<String>>();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.iter().any(|arg| arg == "--print-config") {
+        print!("{}", config);
+        return;
+    }
+    set_max_heap_size(config.max_heap_size);
+    let data_path = config.data_path.as_str();
+
+    if args.iter().any(|arg| arg == "--check-format") {
+        if let Err(e) = check_format(data_path) {
+            println!("Could not check {}: {}", data_path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let salvage = args.iter().any(|arg| arg == "--salvage");
+    let (mut db, salvaged) = match Database::load_from_file(data_path) {
+        Ok(db) => (db, false),
+        Err(e) if e.is_missing_file() => (Database::new(), false),
+        Err(DbError::Corrupt(_)) if salvage => (salvage_database(data_path), true),
+        Err(e) => {
+            // starting empty would overwrite the file on the first commit
+            println!("Could not load {}: {}", data_path, e);
+            println!("Refusing to start so the file isn't overwritten. Restore a backup ({} is the newest), move the file away or run with --salvage to keep what still decodes.", backup_path(data_path, 1));
+            std::process::exit(1);
+        }
+    };
+    db.set_storage(config.storage());
+    let passphrase = cli_handler::get_input_string("Enter the database passphrase".to_string());
+    if db.field_key_salt.is_empty() && passphrase != cli_handler::get_input_string("New database, repeat the passphrase".to_string()) {
+        println!("Passphrases don't match");
+        std::process::exit(1);
+    }
+    let unlocked = if salvaged { db.unlock_salvaged(&passphrase) } else { db.unlock(&passphrase).map(|_| Vec::new()) };
+    match unlocked {
+        Ok(unreadable) if !unreadable.is_empty() => println!("Personal details of {} could not be decrypted and were cleared", unreadable.join(", ")),
+        Ok(_) => {}
+        Err(e) => {
+            println!("Could not open the database: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if salvaged {
+        if let Err(e) = db.compact() {
+            println!("Could not save the salvaged database: {}", e);
+            std::process::exit(1);
+        }
+        println!("Salvaged database saved");
+    }
+    if let Some(version) = db.migrated_from() {
+        // the old file stays behind as the newest backup
+        if let Err(e) = db.compact() {
+            println!("Could not save the migrated database: {}", e);
+            std::process::exit(1);
+        }
+        println!("Database migrated from format {} to {}, the previous file was kept as {}", version, format::FORMAT_VERSION, backup_path(data_path, 1));
+    }
+    if let Ok(Some(path)) = config::flag_value(&args, "--export") {
+        match export_database(&mut db, &path) {
+            Ok(()) => println!("Database exported to {}, it holds personal details in plain text so keep it safe", path),
+            Err(e) => println!("Export failed: {}", e),
+        }
+        return;
+    }
+    if let Ok(Some(path)) = config::flag_value(&args, "--import") {
+        let mode = if args.iter().any(|arg| arg == "--merge") { ImportMode::Merge } else { ImportMode::Empty };
+        if let Err(e) = import_database(&mut db, &path, mode) {
+            println!("Import failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Ok(Some(username)) = config::flag_value(&args, "--create-admin") {
+        match create_admin(&mut db, username.clone(), config.hash_iterations) {
+            Ok(()) => println!("Admin {} created, log in to set up two-factor authentication", username),
+            Err(e) => {
+                println!("Could not create the admin: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Ok(Some(username)) = config::flag_value(&args, "--unlock") {
+        match unlock_account(&mut db, username.clone()) {
+            Ok(()) => println!("Account {} unlocked", username),
+            Err(e) => {
+                println!("Could not unlock {}: {}", username, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--fsck") {
+        match check_references(&mut db, args.iter().any(|arg| arg == "--repair")) {
+            Ok(0) => {}
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                println!("Check failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     let mut auth = Auth::new(&mut db);
+    auth.hash_iterations = config.hash_iterations;
+    auth.max_failed_logins = config.max_failed_logins;
+    auth.backoff_base_secs = config.backoff_base_secs;
+    auth.max_backoff_secs = config.max_backoff_secs;
+    auth.out_of_hospital_penalty = config.out_of_hospital_penalty;
+    auth.default_appointment_priority = config.default_appointment_priority;
 
     // test_data(&mut auth); // for testing
     // println!("{:?}", auth.db); // for debugging
 
     loop {
-        if let Some(ref user) = auth.user {
-            match user.role {
-                Role::Patient => patient_menu(&mut auth),
-                Role::Doctor => doctor_menu(&mut auth),
-                Role::Pharmacist => pharmacist_menu(&mut auth),
-                Role::TriageSupervisor => triage_supervisor_menu(&mut auth),
-                Role::EmergencyDoctor => emergency_doctor_menu(&mut auth),
-                Role::Admin => admin_menu(&mut auth),
+        match auth.user().map(|user| user.role.clone()) {
+            Some(Role::Patient) => patient_menu(&mut auth),
+            Some(Role::Doctor) => doctor_menu(&mut auth),
+            Some(Role::Pharmacist) => pharmacist_menu(&mut auth),
+            Some(Role::TriageSupervisor) => triage_supervisor_menu(&mut auth),
+            Some(Role::EmergencyDoctor) => emergency_doctor_menu(&mut auth),
+            Some(Role::Admin) => admin_menu(&mut auth),
+            None => {
+                let selected = cli_handler::main_menu();
+                auth.authenticate(selected);
             }
-        } else {
-            let selected = cli_handler::main_menu();
-            auth.authenticate(selected);
         }
     }
 }
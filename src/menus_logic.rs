@@ -1,69 +1,125 @@
-use crate::auth::Auth;
-use crate::cli_handler::{doctor_menu, get_input_string, MenuHandler};
+use std::fmt;
+use std::fs;
+use chrono::{Local, TimeZone, Utc};
+
+use crate::auth::{totp_mandatory, Auth};
+use crate::cli_handler::{get_input_string, select_role, MenuHandler};
 use crate::data_structures::linked_list::LinkedList;
 use crate::data_structures::map::{LocationType, Object};
 use crate::data_structures::stack::Stack;
-use crate::db::entities::{Ambulance, Drug, DrugGP, Patient, Prescription, Role};
+use crate::db::db_handler::Database;
+use crate::db::drug_csv::{self, Change};
+use crate::db::error::DbError;
+use crate::db::repository::Repository;
+use crate::db::snapshot::{self, Diff, SnapshotInfo};
+use crate::db::entities::{AccountStatus, Ambulance, Drug, DrugGP, Patient, Prescription, Role, User};
 use crate::data_structures::trie::Trie;
+use crate::db::audit::{self, AuditAction, AuditFilter, ChainHead, EntityType, ALL_ACTIONS, ALL_ENTITY_TYPES};
+use crate::permissions::{Permission, PermissionError, ALL_PERMISSIONS};
+use crate::totp;
+
 
+// what a menu operation can fail with, the menu reports it and carries on
+#[derive(Debug)]
+pub enum MenuError {
+    Permission(PermissionError),
+    Storage(DbError),
+}
 
-pub fn make_appointment(auth: &mut Auth) {
-    let options = auth.db.clinics_data.as_ref().unwrap().iter().map(|clinic| clinic.name.as_str()).collect::<Vec<&str>>().into_iter();
+impl fmt::Display for MenuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MenuError::Permission(e) => write!(f, "{}", e),
+            MenuError::Storage(e) => write!(f, "Could not save: {}", e),
+        }
+    }
+}
+
+impl From<PermissionError> for MenuError {
+    fn from(e: PermissionError) -> Self {
+        MenuError::Permission(e)
+    }
+}
+
+impl From<DbError> for MenuError {
+    fn from(e: DbError) -> Self {
+        MenuError::Storage(e)
+    }
+}
+
+pub fn make_appointment(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::MakeAppointment)?;
+    let username = auth.current_user()?.username.clone();
+    let options = auth.db.clinics().iterate().map(|clinic| clinic.name.as_str()).collect::<Vec<&str>>().into_iter();
     let clinic_menu = MenuHandler::new("Choose a clinic".to_string(), options);
     let selected_clinic = clinic_menu.run();
-    let selected_clinic = auth.db.clinics_data.as_mut().unwrap().get_by_uniq_attr(selected_clinic).unwrap();
+    let selected_clinic = auth.db.clinics().get(&selected_clinic).unwrap();
     let options = selected_clinic.doctors.iter().map(|doctor| doctor.as_str()).collect::<Vec<&str>>().into_iter();
     let doctor_menu = MenuHandler::new("Choose a doctor".to_string(), options);
     let selected_doctor = doctor_menu.run();
 
-    auth.db.doctors_data.as_mut().unwrap().get_by_uniq_attr(selected_doctor).unwrap().patients.insert(Patient {
-        name: auth.user.as_ref().unwrap().username.clone(),
-        priority: 5 // least priority
+    let priority = auth.default_appointment_priority;
+    let patients = &mut auth.db.doctors_lists_mut().get_mut(&selected_doctor).unwrap().patients;
+    if patients.is_full() {
+        println!("{}'s queue is full, please choose another doctor", selected_doctor);
+        return Ok(());
+    }
+    patients.insert(Patient {
+        name: username.clone(),
+        priority
     });
 
-    auth.db.commit().unwrap();
+    auth.audit(AuditAction::Create, EntityType::Appointment, format!("{} with {}", username, selected_doctor), None, Some(format!("priority: {}", priority)));
+    auth.db.commit()?;
+    Ok(())
 }
 
-pub fn cancel_appointment(auth: &mut Auth) {
-    let options = auth.db.doctors_data.as_ref().unwrap().iter().filter_map(|doctor| {
-        doctor.patients.clone().get_by_uniq_attr(auth.user.as_ref().unwrap().username.clone()).map(|_| doctor.doctor.as_str())
-    }).collect::<Vec<&str>>().into_iter();
+pub fn cancel_appointment(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::CancelAppointment)?;
+    let username = auth.current_user()?.username.clone();
+    let options = auth.db.queues_with_patient(&username).into_iter().map(|doctors_list| doctors_list.doctor.as_str()).collect::<Vec<&str>>().into_iter();
     let doctor_menu = MenuHandler::new("Choose a doctor".to_string(), options);
     let selected_doctor = doctor_menu.run();
 
-    let selected_doctor = auth.db.doctors_data.as_mut().unwrap().get_by_uniq_attr(selected_doctor).unwrap();
-    let extracted = selected_doctor.patients.remove_by_uniq_attr(auth.user.as_ref().unwrap().username.clone());
+    let doctors_list = auth.db.doctors_lists_mut().get_mut(&selected_doctor).unwrap();
+    let extracted = doctors_list.patients.remove_by_uniq_attr(username.clone());
     
     if extracted {
+        auth.audit(AuditAction::Delete, EntityType::Appointment, format!("{} with {}", username, selected_doctor), None, None);
         println!("Appointment cancelled");
     }
 
-    auth.db.commit().unwrap();
+    auth.db.commit()?;
+    Ok(())
 }
 
 
-pub fn visit_patients_wrapper(auth: &mut Auth) {
+pub fn visit_patients_wrapper(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::VisitPatients)?;
+    let username = auth.current_user()?.username.clone();
     loop {
         let inp = get_input_string("Enter 'done' to stop".to_string());
-        let list_is_empty = auth.db.doctors_data.as_mut().unwrap().get_by_uniq_attr(auth.user.as_ref().unwrap().username.clone()).unwrap().patients.is_empty();
+        auth.require_session()?;
+        let list_is_empty = auth.db.doctors_lists().get(&username).unwrap().patients.is_empty();
         if list_is_empty && inp == "done" {
             break;
         }
-        visit_patients(auth);
-        auth.db.commit().unwrap();
+        visit_patients(auth)?;
+        auth.db.commit()?;
     }
-    doctor_menu(auth);
+    Ok(())
 }
 
-pub fn visit_patients(auth: &mut Auth) {
-    let selected_doctor = auth.db.doctors_data.as_mut().unwrap().get_by_uniq_attr(auth.user.as_ref().unwrap().username.clone()).unwrap();
-    if let Some(patient) = selected_doctor.patients.pop() {
+pub fn visit_patients(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::VisitPatients)?;
+    let username = auth.current_user()?.username.clone();
+    // the patient only leaves the queue once the visit is written down
+    let next_patient = auth.db.doctors_lists().get(&username).unwrap().patients.peek().cloned();
+    if let Some(patient) = next_patient {
         {
             println!("Patient: {}", patient.name);
-            let patient = auth.db.users_data.as_mut().unwrap().get_by_uniq_attr(patient.name.clone()).unwrap();
-            println!("Patient: {}", patient.full_name);
-            println!("ssn: {}", patient.ssn);
-            println!("age: {}", patient.age);
+            let patient = auth.db.users().get(&patient.name).unwrap();
+            print_pii(patient, auth.can_view_pii_of(patient));
         }
 
         let mut prescription = Stack::new();
@@ -74,64 +130,107 @@ pub fn visit_patients(auth: &mut Auth) {
             }
             prescription.push(inp);
         }
+        auth.require_session()?;
+        auth.db.doctors_lists_mut().get_mut(&username).unwrap().patients.pop();
 
-        auth.db.insert_prescription(Prescription {
-            patient_name: patient.name,
-            medications: prescription
-        }).unwrap();
+        auth.audit(AuditAction::Delete, EntityType::Appointment, format!("{} with {}", patient.name, username), Some(format!("priority: {}", patient.priority)), None);
+        match auth.db.prescriptions_mut().get_mut(&patient.name) {
+            // a visit before the last prescription was dispensed adds to it, bottom of the new stack first
+            Some(existing) => {
+                let before = format!("{:?}", existing.medications);
+                let mut added = prescription.iter().cloned().collect::<Vec<_>>();
+                added.reverse();
+                for medication in added {
+                    existing.medications.push(medication);
+                }
+                let after = format!("{:?}", existing.medications);
+                auth.audit(AuditAction::Update, EntityType::Prescription, patient.name.clone(), Some(before), Some(after));
+            },
+            None => {
+                auth.audit(AuditAction::Create, EntityType::Prescription, patient.name.clone(), None, Some(format!("{:?}", prescription)));
+                auth.db.prescriptions_mut().insert(Prescription {
+                    patient_name: patient.name,
+                    medications: prescription
+                })?;
+            },
+        }
     }
+    Ok(())
 }
 
-pub fn dispense_medications(auth: &mut Auth) {
+pub fn dispense_medications(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::DispenseMedications)?;
     println!("Dispense medications");
     let patient_name = get_input_string("Enter patient name".to_string());
-    if let Some(prescription) = auth.db.get_prescription(patient_name.clone()) {
+    if let Some(prescription) = auth.db.prescriptions_mut().get_mut(&patient_name) {
         println!("Patient: {}", prescription.patient_name);
         println!("Medications: {:?}", prescription.medications);
+        let before = format!("{:?}", prescription.medications);
         while let Some(medication) = prescription.medications.pop() {
             println!("Dispensing medication: {}", medication);
             get_input_string("".to_string());
         }
         println!("Medications dispensed");
-        auth.db.remove_prescription(patient_name);
-        auth.db.commit().unwrap();
+        auth.db.prescriptions_mut().remove(&patient_name).unwrap();
+        auth.audit(AuditAction::Dispense, EntityType::Prescription, patient_name, Some(before), None);
+        auth.db.commit()?;
     } else {
         println!("Patient not found");
     }
+    Ok(())
 }
 
-pub fn assign_patients(auth: &mut Auth) {
+pub fn assign_patients(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::AssignPatients)?;
     let patient_username = get_input_string("Enter patient username".to_string());
-    if auth.db.get_user(patient_username.clone()).is_none() {
+    let new_patient = if !auth.db.users().contains(&patient_username) {
         let patient_password = get_input_string("Enter patient password".to_string());
         let patient_full_name = get_input_string("Enter patient full name".to_string());
         let patient_ssn = get_input_string("Enter patient ssn".to_string());
         let patient_age = get_input_string("Enter patient age".to_string()).parse::<u32>().unwrap();
-        auth.register(patient_username.clone(), patient_password, patient_full_name, patient_ssn, patient_age, Role::Patient).unwrap();
-        auth.db.commit().unwrap();
-    }
+        Some((patient_password, patient_full_name, patient_ssn, patient_age))
+    } else {
+        None
+    };
 
-    let options = auth.db.clinics_data.as_ref().unwrap().iter().map(|clinic| clinic.name.as_str()).collect::<Vec<&str>>().into_iter();
+    let options = auth.db.clinics().iterate().map(|clinic| clinic.name.as_str()).collect::<Vec<&str>>().into_iter();
     let clinic_menu = MenuHandler::new("Choose a clinic".to_string(), options);
     let selected_clinic = clinic_menu.run();
-    let selected_clinic = auth.db.clinics_data.as_mut().unwrap().get_by_uniq_attr(selected_clinic).unwrap();
+    let selected_clinic = auth.db.clinics().get(&selected_clinic).unwrap();
     let options = selected_clinic.doctors.iter().map(|doctor| doctor.as_str()).collect::<Vec<&str>>().into_iter();
     let doctor_menu = MenuHandler::new("Choose a doctor".to_string(), options);
     let selected_doctor = doctor_menu.run();
     let priority = get_input_string("Enter patient priority".to_string()).parse::<u32>().unwrap();
 
-    auth.db.doctors_data.as_mut().unwrap().get_by_uniq_attr(selected_doctor).unwrap().patients.insert(Patient {
-        name: patient_username,
-        priority
-    });
+    if auth.db.doctors_lists().get(&selected_doctor).is_some_and(|list| list.patients.is_full()) {
+        println!("{}'s queue is full, please choose another doctor", selected_doctor);
+        return Ok(());
+    }
 
-    auth.db.commit().unwrap();
+    // a new patient is only registered together with their appointment
+    let result = auth.transaction(|auth| {
+        if let Some((password, full_name, ssn, age)) = new_patient {
+            auth.register(patient_username.clone(), password, full_name, ssn, age, Role::Patient)?;
+        }
+        let doctors_list = auth.db.doctors_lists_mut().get_mut(&selected_doctor).ok_or(DbError::NotFound { entity: "doctor", key: selected_doctor.clone() })?;
+        doctors_list.patients.insert(Patient {
+            name: patient_username.clone(),
+            priority
+        });
+        auth.audit(AuditAction::Create, EntityType::Appointment, format!("{} with {}", patient_username, selected_doctor), None, Some(format!("priority: {}", priority)));
+        Ok::<(), DbError>(())
+    });
+    if let Err(e) = result {
+        println!("Could not assign {}, nothing was changed: {}", patient_username, e);
+    }
+    Ok(())
 }
 
 
-pub fn add_drug(auth: &mut Auth) {
+pub fn add_drug(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugs)?;
     let name = get_input_string("Enter drug name".to_string());
-    if auth.db.get_drug_by_name(name.clone()).is_none() {
+    if auth.db.find_drug_by_name(&name).is_none() {
         let price = get_input_string("Enter drug price".to_string()).parse::<f32>().unwrap();
         let drug = Drug {
             id: match auth.db.drugs_data.as_ref() {
@@ -143,25 +242,114 @@ pub fn add_drug(auth: &mut Auth) {
             price,
             quantity: 0
         };
-        auth.db.insert_drug(drug).unwrap();
+        auth.audit(AuditAction::Create, EntityType::Drug, name.clone(), None, Some(format!("id: {}, price: {}", drug.id, drug.price)));
+        auth.db.drugs_mut().insert(drug).unwrap();
     }
     let quantity = get_input_string("Enter drug quantity".to_string()).parse::<u32>().unwrap();
-    auth.db.get_drug_by_name(name.clone()).unwrap().quantity += quantity;
-    auth.db.commit().unwrap();
+    let drug = auth.db.get_drug_by_name(name.clone()).unwrap();
+    let before = drug.quantity;
+    drug.quantity += quantity;
+    let after = drug.quantity;
+    auth.audit(AuditAction::Update, EntityType::Drug, name, Some(format!("quantity: {}", before)), Some(format!("quantity: {}", after)));
+    auth.db.commit()?;
     println!("Drug added");
+    Ok(())
+}
+
+// previews every change, nothing is written unless the pharmacist confirms and there are no conflicts
+pub fn import_drugs_csv(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugs)?;
+    let path = get_input_string(format!("Enter the CSV file to import ({})", drug_csv::INVENTORY_HEADER));
+    let rows = match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|csv| drug_csv::parse(&csv).map_err(|e| e.to_string())) {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("Could not read {}: {}", path, e);
+            return Ok(());
+        }
+    };
+
+    let changes = drug_csv::plan(auth.db, &rows);
+    for change in changes.iter() {
+        println!("{}", change);
+    }
+    let creates = changes.iter().filter(|change| matches!(change, Change::Create(_))).count();
+    let updates = changes.iter().filter(|change| matches!(change, Change::Update { .. })).count();
+    let conflicts = changes.iter().filter(|change| matches!(change, Change::Conflict { .. })).count();
+    println!("{} to create, {} to update, {} conflicts", creates, updates, conflicts);
+    if drug_csv::has_conflicts(&changes) {
+        println!("Nothing was imported, fix the conflicts and try again");
+        return Ok(());
+    }
+    if creates + updates == 0 {
+        println!("The inventory already matches the file");
+        return Ok(());
+    }
+    if get_input_string("Apply these changes? (y/n)".to_string()) != "y" {
+        println!("Dry run only, nothing was changed");
+        return Ok(());
+    }
+
+    // the whole file goes in or none of it does
+    let result = auth.transaction(|auth| {
+        for change in changes {
+            match change {
+                Change::Create(drug) => {
+                    auth.audit(AuditAction::Create, EntityType::Drug, drug.name.clone(), None, Some(format!("id: {}, price: {}, quantity: {}", drug.id, drug.price, drug.quantity)));
+                    auth.db.drugs_mut().insert(drug)?;
+                },
+                Change::Update { before, after } => {
+                    let drug = auth.db.drugs_mut().get_mut(&after.id.to_string()).ok_or(DbError::NotFound { entity: "drug", key: after.id.to_string() })?;
+                    drug.price = after.price;
+                    drug.quantity = after.quantity;
+                    auth.audit(AuditAction::Update, EntityType::Drug, after.name, Some(format!("price: {}, quantity: {}", before.price, before.quantity)), Some(format!("price: {}, quantity: {}", after.price, after.quantity)));
+                },
+                Change::Unchanged(_) | Change::Conflict { .. } => {},
+            }
+        }
+        Ok::<(), DbError>(())
+    });
+    match result {
+        Ok(()) => println!("Imported {} new and {} updated drugs", creates, updates),
+        Err(e) => println!("Import failed, nothing was changed: {}", e),
+    }
+    Ok(())
 }
 
-pub fn remove_drug(auth: &mut Auth) {
+pub fn export_drugs_csv(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewDrugs)?;
+    let path = get_input_string("Enter the file to export the inventory to".to_string());
+    match fs::write(&path, drug_csv::inventory_csv(auth.db)) {
+        Ok(()) => println!("Inventory exported to {}", path),
+        Err(e) => println!("Export failed: {}", e),
+    }
+    let path = get_input_string("Enter the file to export drug group membership to, or leave empty".to_string());
+    if !path.is_empty() {
+        match fs::write(&path, drug_csv::groups_csv(auth.db)) {
+            Ok(()) => println!("Drug groups exported to {}", path),
+            Err(e) => println!("Export failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+pub fn remove_drug(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugs)?;
     let id = get_input_string("Enter drug id".to_string()).parse::<u32>().unwrap();
-    if let Some(drug) = auth.db.get_drug_by_id(id.clone()) {
+    if let Some(drug) = auth.db.drugs_mut().get_mut(&id.to_string()) {
         let quantity = get_input_string("Enter quantity to remove".to_string()).parse::<u32>().unwrap();
         if drug.quantity >= quantity {
+            let before = drug.quantity;
             drug.quantity -= quantity;
             let remaining_quantity = drug.quantity;
+            let name = drug.name.clone();
+            auth.audit(AuditAction::Update, EntityType::Drug, name.clone(), Some(format!("quantity: {}", before)), Some(format!("quantity: {}", remaining_quantity)));
             if remaining_quantity == 0 {
-                auth.db.remove_drug(id);
+                match auth.db.remove_drug(id) {
+                    Ok(_) => auth.audit(AuditAction::Delete, EntityType::Drug, name, Some(format!("id: {}", id)), None),
+                    Err(e) => println!("{}, it stays listed with no stock", e),
+                }
             }
-            auth.db.commit().unwrap();
+            auth.db.commit()?;
             println!("Remained quantity: {}", remaining_quantity);
         } else {
             println!("Not enough quantity");
@@ -169,22 +357,24 @@ pub fn remove_drug(auth: &mut Auth) {
     } else {
         println!("Drug not found");
     }
+    Ok(())
 }
 
-pub fn search_drugs(auth: &mut Auth) {
+pub fn search_drugs(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewDrugs)?;
     let options = ["name", "id", "price"];
     let menu = MenuHandler::new("Search by".to_string(), options.into_iter());
     let search_type = menu.run();
     match search_type.as_str() {
         "name" => {
             let name = get_input_string("Enter drug name: ".to_string());
-            if let Some(drug) = auth.db.get_drug_by_name(name.clone()) {
+            if let Some(drug) = auth.db.find_drug_by_name(&name) {
                 println!("Drug found: {:?}", drug);
             } else {
                 println!("Drug not found");
 
                 let mut trie = Trie::new();
-                for drug in auth.db.drugs_data.as_ref().unwrap().iter() {
+                for drug in auth.db.drugs().iterate() {
                     trie.insert(&drug.name.to_lowercase());
                 }
                 let suggestions = trie.auto_complete(&name.to_lowercase());
@@ -197,7 +387,7 @@ pub fn search_drugs(auth: &mut Auth) {
         }
         "id" => {
             let id = get_input_string("Enter drug id: ".to_string()).parse::<u32>().unwrap();
-            if let Some(drug) = auth.db.get_drug_by_id(id) {
+            if let Some(drug) = auth.db.drugs().get(&id.to_string()) {
                 println!("Drug found: {:?}", drug);
             } else {
                 println!("Drug not found");
@@ -206,7 +396,7 @@ pub fn search_drugs(auth: &mut Auth) {
         "price" => {
             let min_price = get_input_string("Enter minimum price: ".to_string()).parse::<f32>().unwrap();
             let max_price = get_input_string("Enter maximum price: ".to_string()).parse::<f32>().unwrap();
-            let drugs = auth.db.drugs_data.as_ref().unwrap().iter().filter(|drug| drug.price >= min_price && drug.price <= max_price).collect::<Vec<_>>();
+            let drugs = auth.db.drugs().iterate().filter(|drug| drug.price >= min_price && drug.price <= max_price).collect::<Vec<_>>();
             if drugs.is_empty() {
                 println!("No drugs found in the given price range");
             } else {
@@ -217,13 +407,15 @@ pub fn search_drugs(auth: &mut Auth) {
             println!("Invalid search type");
         }
     }
+    Ok(())
 }
 
-pub fn display_all_drugs(auth: &mut Auth) {
+pub fn display_all_drugs(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewDrugs)?;
     let drugs = auth.db.drugs_data.as_ref();
     if drugs.is_none() {
         println!("No drugs available");
-        return;
+        return Ok(());
     }
     let drugs = drugs.unwrap();
 
@@ -245,38 +437,43 @@ pub fn display_all_drugs(auth: &mut Auth) {
     println!("Total quantity of all drugs: {}", total_quantity);
     println!("Cheapest drug: {:?}", cheapest_drug);
     println!("Most expensive drug: {:?}", most_expensive_drug);
+    Ok(())
 }
 
-pub fn create_drug_gp(auth: &mut Auth) {
+pub fn create_drug_gp(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugGroups)?;
     let name = get_input_string("Enter drug group name".to_string());
-    if auth.db.get_drug_gp(name.clone()).is_none() {
+    if !auth.db.drug_groups().contains(&name) {
         let mut drugs = LinkedList::new();
         loop {
             let drug_name = get_input_string("Enter drug name or type 'done'".to_string());
             if drug_name == "done" {
                 break;
             }
-            if let Some(drug) = auth.db.get_drug_by_name(drug_name.clone()) {
+            if let Some(drug) = auth.db.find_drug_by_name(&drug_name) {
                 drugs.push_front(drug.id);
             } else {
                 println!("Drug not found");
             }
         }
-        auth.db.insert_drug_gp(DrugGP { name: name.clone(), drugs }).unwrap();
+        auth.audit(AuditAction::Create, EntityType::DrugGroup, name.clone(), None, Some(format!("drugs: {:?}", drugs.iter().collect::<Vec<_>>())));
+        auth.db.drug_groups_mut().insert(DrugGP { name: name.clone(), drugs }).unwrap();
     }
-    auth.db.commit().unwrap();
+    auth.db.commit()?;
+    Ok(())
 }
 
-pub fn add_drug_to_gp(auth: &mut Auth) {
+pub fn add_drug_to_gp(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugGroups)?;
     let name = get_input_string("Enter drug group name".to_string());
-    if let Some(drug_gp) = auth.db.get_drug_gp(name.clone()) {
+    if let Some(drug_gp) = auth.db.drug_groups().get(&name) {
         let mut drugs = drug_gp.drugs.clone();
         loop {
             let drug_name = get_input_string("Enter drug name or type 'done'".to_string());
             if drug_name == "done" {
                 break;
             }
-            if let Some(drug) = auth.db.get_drug_by_name(drug_name.clone()) {
+            if let Some(drug) = auth.db.find_drug_by_name(&drug_name) {
                 if !drugs.contains(&drug.id) {
                     drugs.push_front(drug.id);
                 } else {
@@ -286,38 +483,49 @@ pub fn add_drug_to_gp(auth: &mut Auth) {
                 println!("Drug not found");
             }
         }
-        auth.db.get_drug_gp(name.clone()).unwrap().drugs = drugs;
-        auth.db.commit().unwrap();
+        let before = format!("drugs: {:?}", drug_gp_ids(auth, &name));
+        auth.db.drug_groups_mut().get_mut(&name).unwrap().drugs = drugs;
+        let after = format!("drugs: {:?}", drug_gp_ids(auth, &name));
+        auth.audit(AuditAction::Update, EntityType::DrugGroup, name, Some(before), Some(after));
+        auth.db.commit()?;
     } else {
         println!("Drug group not found");
     }
+    Ok(())
+}
+
+fn drug_gp_ids(auth: &mut Auth, name: &str) -> Vec<u32> {
+    auth.db.drug_groups().get(name).map_or(Vec::new(), |drug_gp| drug_gp.drugs.iter().copied().collect())
 }
 
-pub fn remove_drug_gp(auth: &mut Auth) {
+pub fn remove_drug_gp(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageDrugGroups)?;
     let name = get_input_string("Enter drug group name".to_string());
-    if let Some(_drug_gp) = auth.db.get_drug_gp(name.clone()) {
-        auth.db.remove_drug_gp(name);
-        auth.db.commit().unwrap();
+    if let Some(drug_gp) = auth.db.drug_groups().get(&name) {
+        let before = format!("drugs: {:?}", drug_gp.drugs.iter().collect::<Vec<_>>());
+        auth.db.drug_groups_mut().remove(&name).unwrap();
+        auth.audit(AuditAction::Delete, EntityType::DrugGroup, name, Some(before), None);
+        auth.db.commit()?;
         println!("Drug group removed");
     } else {
         println!("Drug group not found");
     }
+    Ok(())
 }
 
-pub fn display_all_drug_gps(auth: &mut Auth) {
-    let drug_gps = auth.db.drug_gps.as_ref();
-    if drug_gps.is_none() {
+pub fn display_all_drug_gps(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewDrugs)?;
+    if auth.db.drug_groups().count() == 0 {
         println!("No drug groups available");
-        return;
+        return Ok(());
     }
-    let drug_gps = drug_gps.unwrap().clone();
 
-    for drug_gp in drug_gps.iter() {
+    for drug_gp in auth.db.drug_groups().iterate() {
         println!("Drug Group: {}", drug_gp.name);
         let mut drugs = LinkedList::new();
         for id in drug_gp.drugs.iter() {
-            if let Some(drug) = auth.db.get_drug_by_id(*id) {
-                drugs.push_front(drug.clone());
+            if let Some(drug) = auth.db.drugs().get(&id.to_string()) {
+                drugs.push_front(drug);
             }
         }
         if drugs.is_empty() {
@@ -328,9 +536,11 @@ pub fn display_all_drug_gps(auth: &mut Auth) {
             }
         }
     }
+    Ok(())
 }
 
-pub fn show_search_complexity(auth: &mut Auth) {
+pub fn show_search_complexity(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewDrugs)?;
     let height = auth.db.drugs_data.as_ref().unwrap().height();
     let mut result = LinkedList::new();
     auth.db.drugs_data.as_ref().unwrap().in_order_traversal_collect(&mut result);
@@ -338,11 +548,13 @@ pub fn show_search_complexity(auth: &mut Auth) {
     println!("Total nodes in the tree: {}", total_nodes);
     println!("Height of the tree: {}", height);
     println!("Complexity of search: O(log {})", height);
+    Ok(())
 }
 
-pub fn add_location(auth: &mut Auth) {
+pub fn add_location(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageMap)?;
     let name = get_input_string("Enter location name".to_string());
-    if auth.db.map.nodes.get(name.as_str()).is_some() {
+    if auth.db.map().nodes.get(name.as_str()).is_some() {
         println!("Location already exists, adding edges instead");
     } else {
         let options = ["Hospital", "Home", "Other"];
@@ -354,10 +566,11 @@ pub fn add_location(auth: &mut Auth) {
             "Other" => LocationType::Other,
             _ => {
                 println!("Invalid location type");
-                return;
+                return Ok(());
             }
         };
-        auth.db.map.add_node(name.clone(), location_type);
+        auth.audit(AuditAction::Create, EntityType::Location, name.clone(), None, Some(format!("{:?}", location_type)));
+        auth.db.map_mut().add_node(name.clone(), location_type);
     }
 
     loop {
@@ -365,113 +578,139 @@ pub fn add_location(auth: &mut Auth) {
         if neighbor == "done" {
             break;
         }
-        if let Some(_node) = auth.db.map.nodes.get(neighbor.as_str()) {
-            auth.db.map.add_edge(name.clone(), neighbor.clone());
+        if let Some(_node) = auth.db.map().nodes.get(neighbor.as_str()) {
+            auth.db.map_mut().add_edge(name.clone(), neighbor.clone());
+            auth.audit(AuditAction::Create, EntityType::Route, format!("{} - {}", name, neighbor), None, None);
         } else {
             println!("Neighbor not found");
         }
     }
-    auth.db.commit().unwrap();
+    auth.db.commit()?;
     println!("Location added");
+    Ok(())
 }
 
 
-pub fn remove_location(auth: &mut Auth) {
+pub fn remove_location(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageMap)?;
     let name: String = get_input_string("Enter location name".to_string());
-    auth.db.map.remove_node(name);
-    auth.db.commit().unwrap();
+    if let Err(e) = auth.db.remove_location(&name) {
+        println!("{}", e);
+        return Ok(());
+    }
+    auth.audit(AuditAction::Delete, EntityType::Location, name, None, None);
+    auth.db.commit()?;
     println!("Location removed");
+    Ok(())
 }
 
-pub fn print_map(auth: &mut Auth) {
-    auth.db.map.print_graph();
+pub fn print_map(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewMap)?;
+    auth.db.map().print_graph();
+    Ok(())
 }
 
-pub fn add_ambulance(auth: &mut Auth) {
+pub fn add_ambulance(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageAmbulances)?;
     let name = get_input_string("Enter ambulance name".to_string());
-    if auth.db.get_ambulance(name.clone()).is_some() {
+    if auth.db.ambulances().contains(&name) {
         println!("Ambulance already exists");
-        return;
+        return Ok(());
     }
     let hospital = get_input_string("Enter hospital name".to_string());
-    if auth.db.map.nodes.get(hospital.as_str()).is_none() {
+    if auth.db.map().nodes.get(hospital.as_str()).is_none() {
         println!("Hospital not found");
-        return;
+        return Ok(());
     }
     let location = get_input_string("Enter the ambulance current location name".to_string());
-    if auth.db.map.nodes.get(location.as_str()).is_none() {
+    if auth.db.map().nodes.get(location.as_str()).is_none() {
         println!("Location not found");
-        return;
+        return Ok(());
     }
     
-    auth.db.insert_ambulance(Ambulance::new(name.clone(), hospital.clone(), location.clone())).unwrap();
-    auth.db.map.add_object_to_node(location.as_str(), Object { name });
-    auth.db.commit().unwrap();
+    auth.db.ambulances_mut().insert(Ambulance::new(name.clone(), hospital.clone(), location.clone())).unwrap();
+    auth.db.map_mut().add_object_to_node(location.as_str(), Object { name: name.clone() });
+    auth.audit(AuditAction::Create, EntityType::Ambulance, name, None, Some(format!("hospital: {}, location: {}", hospital, location)));
+    auth.db.commit()?;
     println!("Ambulance added");
+    Ok(())
 }
 
-pub fn remove_ambulance(auth: &mut Auth) {
+pub fn remove_ambulance(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageAmbulances)?;
     let name = get_input_string("Enter ambulance name".to_string());
-    let ambulance = auth.db.get_ambulance(name.clone());
+    let ambulance = auth.db.ambulances().get(&name);
     if ambulance.is_none() {
         println!("Ambulance not found");
-        return;
+        return Ok(());
     }
     let ambulance = ambulance.unwrap().clone();
-    auth.db.map.remove_object_from_node(ambulance.location.as_str(), &name);
     auth.db.remove_ambulance(name.clone());
-    auth.db.commit().unwrap();
+    auth.audit(AuditAction::Delete, EntityType::Ambulance, name, Some(format!("hospital: {}, location: {}", ambulance.hospital, ambulance.location)), None);
+    auth.db.commit()?;
     println!("Ambulance removed");
+    Ok(())
 }
 
-pub fn move_ambulance(auth: &mut Auth) {
+pub fn move_ambulance(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageAmbulances)?;
     let name = get_input_string("Enter ambulance name".to_string());
-    let ambulance = auth.db.get_ambulance(name.clone());
+    let ambulance = auth.db.ambulances().get(&name);
     if ambulance.is_none() {
         println!("Ambulance not found");
-        return;
+        return Ok(());
     }
     let ambulance = ambulance.unwrap().clone();
     let location = get_input_string("Enter new location name".to_string());
-    if auth.db.map.nodes.get(location.as_str()).is_none() {
+    if auth.db.map().nodes.get(location.as_str()).is_none() {
         println!("Location not found");
-        return;
+        return Ok(());
+    }
+    let result = auth.transaction(|auth| {
+        auth.db.ambulances_mut().get_mut(&name).ok_or(DbError::NotFound { entity: "ambulance", key: name.clone() })?.location = location.clone();
+        auth.db.map_mut().move_object(&ambulance.location, &location, &name).map_err(DbError::Integrity)?;
+        auth.audit(AuditAction::Move, EntityType::Ambulance, name.clone(), Some(ambulance.location.clone()), Some(location.clone()));
+        Ok::<(), DbError>(())
+    });
+    match result {
+        Ok(()) => println!("Ambulance moved"),
+        Err(e) => println!("Could not move {}, nothing was changed: {}", name, e),
     }
-    auth.db.ambulances_data.as_mut().unwrap().get_by_uniq_attr(name.clone()).unwrap().location = location.clone();
-    auth.db.map.move_object(&ambulance.location, &location, &name).unwrap();
-    auth.db.insert_log(format!("Ambulance {} moved from {} to {}", name, ambulance.location, location));
-    auth.db.commit().unwrap();
-    println!("Ambulance moved");
+    Ok(())
 }
 
-pub fn list_ambulances(auth: &mut Auth) {
+pub fn list_ambulances(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewMap)?;
     let ambulances = auth.db.ambulances_data.as_ref();
     if ambulances.is_none() {
         println!("No ambulances available");
-        return;
+        return Ok(());
     }
     let ambulances = ambulances.unwrap().clone();
 
     for ambulance in ambulances.iter() {
         println!("{:?}", ambulance);
     }
+    Ok(())
 }
 
-pub fn send_ambulance_to_patient(auth: &mut Auth) {
+pub fn send_ambulance_to_patient(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageAmbulances)?;
     let patient_loc = get_input_string("Enter patient location".to_string());
     let dst_hosp = get_input_string("Enter destination hospital".to_string());
 
+    let penalty = auth.out_of_hospital_penalty;
     let mut shortest_path = None;
     let mut min_distance = std::f32::MAX;
 
-    for ambulance in auth.db.ambulances_data.as_mut().unwrap().iter_mut() {
-        let path = auth.db.map.shortest_path(&ambulance.location, &patient_loc);
+    for ambulance in auth.db.ambulances().iterate() {
+        let path = auth.db.map().shortest_path(&ambulance.location, &patient_loc);
         let mut distance = std::f32::MAX;
         if let Some(path) = path {
             distance = path.len() as f32;
         }
         if ambulance.hospital != dst_hosp {
-            distance *= 1.2; // Penalty for ambulances from other hospitals
+            distance *= penalty; // Penalty for ambulances from other hospitals
         }
         if distance < min_distance {
             min_distance = distance;
@@ -479,27 +718,794 @@ pub fn send_ambulance_to_patient(auth: &mut Auth) {
         }
     }
 
-    if let Some((ambulance, _)) = shortest_path {
-        println!("Sending ambulance: {}", ambulance.name);
-        auth.db.map.move_object(&ambulance.location, &patient_loc, &ambulance.name).unwrap();
-        let log_message1 = format!("Ambulance {} sent from {} to patient at {}", ambulance.name, ambulance.location, patient_loc);
+    let (name, start) = match shortest_path {
+        Some((ambulance, _)) => (ambulance.name.clone(), ambulance.location.clone()),
+        None => {
+            println!("No available ambulance found");
+            return Ok(());
+        }
+    };
+
+    println!("Sending ambulance: {}", name);
+    // both legs and the new location are saved together, otherwise the ambulance stays where it was
+    let result = auth.transaction(|auth| {
+        auth.db.map_mut().move_object(&start, &patient_loc, &name).map_err(DbError::Integrity)?;
+        auth.db.map_mut().move_object(&patient_loc, &dst_hosp, &name).map_err(DbError::Integrity)?;
+        auth.db.ambulances_mut().get_mut(&name).ok_or(DbError::NotFound { entity: "ambulance", key: name.clone() })?.location = dst_hosp.clone();
+        auth.audit(AuditAction::Move, EntityType::Ambulance, name.clone(), Some(start.clone()), Some(format!("patient at {}", patient_loc)));
+        auth.audit(AuditAction::Move, EntityType::Ambulance, name.clone(), Some(patient_loc.clone()), Some(dst_hosp.clone()));
+        Ok::<(), DbError>(())
+    });
+    match result {
+        Ok(()) => println!("Ambulance sent from {} to {} via {}", start, dst_hosp, patient_loc),
+        Err(e) => println!("Could not send {}, nothing was changed: {}", name, e),
+    }
+    Ok(())
+}
+
+pub fn print_logs(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewLogs)?;
+    let events = auth.db.audit_events(&AuditFilter::EntityType(EntityType::Ambulance));
+    if events.is_empty() {
+        println!("No ambulance history");
+    }
+    for event in events {
+        println!("{}", event);
+    }
+    Ok(())
+}
+
+const AUDIT_PAGE_SIZE: usize = 20;
+
+fn select_audit_filter() -> AuditFilter {
+    let options = ["All events", "By actor", "By action", "By entity type", "By entity key"];
+    let menu = MenuHandler::new("Filter the audit log".to_string(), options.into_iter());
+    match menu.run().as_str() {
+        "By actor" => AuditFilter::Actor(get_input_string("Enter the actor's username".to_string())),
+        "By action" => {
+            let labels = ALL_ACTIONS.iter().map(|action| format!("{:?}", action)).collect::<Vec<_>>();
+            let menu = MenuHandler::new("Select an action".to_string(), labels.iter().map(|label| label.as_str()));
+            let selected = menu.run();
+            AuditFilter::Action(ALL_ACTIONS[labels.iter().position(|label| *label == selected).unwrap()])
+        }
+        "By entity type" => {
+            let labels = ALL_ENTITY_TYPES.iter().map(|entity_type| format!("{:?}", entity_type)).collect::<Vec<_>>();
+            let menu = MenuHandler::new("Select an entity type".to_string(), labels.iter().map(|label| label.as_str()));
+            let selected = menu.run();
+            AuditFilter::EntityType(ALL_ENTITY_TYPES[labels.iter().position(|label| *label == selected).unwrap()])
+        }
+        "By entity key" => AuditFilter::EntityKey(get_input_string("Enter the entity key".to_string())),
+        _ => AuditFilter::All,
+    }
+}
+
+pub fn verify_audit_chain(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewLogs)?;
+    match auth.db.verify_audit_chain() {
+        Ok(Some(head)) => println!("Audit chain intact: {} events, head #{} {}", head.length, head.id, head.hash),
+        Ok(None) => println!("The audit log is empty"),
+        Err(e) => {
+            println!("Audit chain broken: {}", e);
+            return Ok(());
+        }
+    }
+
+    let path = get_input_string("Enter the path of an exported chain head to compare against, or leave empty".to_string());
+    if path.is_empty() {
+        return Ok(());
+    }
+    let exported = match fs::read_to_string(&path).ok().and_then(|content| ChainHead::parse(content.trim())) {
+        Some(head) => head,
+        None => {
+            println!("Could not read a chain head from {}", path);
+            return Ok(());
+        }
+    };
+    match audit::check_head(auth.db.audit_chain_oldest_first().into_iter(), &exported) {
+        Ok(()) => println!("History up to event #{} is unchanged since the head was exported", exported.id),
+        Err(e) => println!("Audit chain broken: {}", e),
+    }
+    Ok(())
+}
+
+pub fn export_audit_chain_head(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewLogs)?;
+    let head = match auth.db.verify_audit_chain() {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            println!("The audit log is empty");
+            return Ok(());
+        }
+        Err(e) => {
+            println!("Refusing to export the head of a broken chain: {}", e);
+            return Ok(());
+        }
+    };
+    let path = get_input_string("Enter the file to export the chain head to".to_string());
+    match fs::write(&path, format!("{}\n", head)) {
+        Ok(()) => println!("Chain head #{} exported to {}, keep a copy outside this machine", head.id, path),
+        Err(e) => println!("Export failed: {}", e),
+    }
+    Ok(())
+}
+
+pub fn view_audit_log(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewLogs)?;
+    let filter = select_audit_filter();
+    let events = auth.db.audit_events(&filter).into_iter().cloned().collect::<Vec<_>>();
+    if events.is_empty() {
+        println!("No matching events");
+        return Ok(());
+    }
+
+    let pages = events.len().div_ceil(AUDIT_PAGE_SIZE);
+    let mut page = 0;
+    loop {
+        for event in events.iter().skip(page * AUDIT_PAGE_SIZE).take(AUDIT_PAGE_SIZE) {
+            println!("{}", event);
+        }
+        println!("Page {} of {} ({} events, newest first)", page + 1, pages, events.len());
+
+        let options = ["Next page", "Previous page", "back"];
+        let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
+        let selected = menu.run();
+        auth.require_session()?;
+        match selected.as_str() {
+            "Next page" if page + 1 < pages => page += 1,
+            "Previous page" if page > 0 => page -= 1,
+            "back" => break,
+            _ => println!("No more pages"),
+        }
+    }
+    Ok(())
+}
+
+// short enough for a busy shift, long enough that "emergency" alone won't do
+const MIN_JUSTIFICATION_LEN: usize = 20;
+
+fn print_patient_record(auth: &Auth, patient: &User) {
+    print_user(auth, patient);
+    print_account_summary(auth, patient);
+    match auth.db.prescriptions().get(&patient.username) {
+        Some(prescription) => println!("Pending prescription: {:?}", prescription.medications),
+        None => println!("No pending prescriptions"),
+    }
+}
+
+// break-the-glass: read any patient's record for a limited time, every access is flagged in the audit log
+pub fn emergency_access(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::EmergencyAccess)?;
+    let username = get_input_string("Enter the patient's username".to_string());
+    let patient = match auth.db.users().get(&username) {
+        Some(user) if user.role == Role::Patient => user.clone(),
+        _ => {
+            println!("Patient not found");
+            return Ok(());
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    let existing = auth.session.as_ref().and_then(|session| session.emergency_grant(&username, now)).cloned();
+    let after = match existing {
+        Some(grant) => format!("viewed under the grant until {}", format_time(grant.expires_at)),
+        None => {
+            println!("Emergency access is logged and reviewed by an administrator");
+            let justification = get_input_string("Describe the emergency that requires this access".to_string());
+            if justification.trim().len() < MIN_JUSTIFICATION_LEN {
+                println!("A written justification of at least {} characters is required", MIN_JUSTIFICATION_LEN);
+                return Ok(());
+            }
+            let expires_at = auth.session.as_mut().unwrap().grant_emergency_access(username.clone(), now);
+            format!("granted until {}: {}", format_time(expires_at), justification.trim())
+        }
+    };
+    auth.audit(AuditAction::EmergencyAccess, EntityType::User, username, None, Some(after));
+    auth.db.commit()?;
+
+    print_patient_record(auth, &patient);
+    Ok(())
+}
+
+fn format_time(timestamp: i64) -> String {
+    Local.timestamp_opt(timestamp, 0).single().map_or(timestamp.to_string(), |time| time.format("%H:%M:%S").to_string())
+}
+
+pub fn review_emergency_accesses(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ViewLogs)?;
+    let events = auth.db.audit_events(&AuditFilter::Action(AuditAction::EmergencyAccess));
+    if events.is_empty() {
+        println!("No emergency accesses recorded");
+    }
+    for event in events {
+        println!("{}", event);
+    }
+    Ok(())
+}
+
+const USERS_PAGE_SIZE: usize = 10;
+
+const RESTRICTED: &str = "[restricted]";
+
+fn display_name<'a>(auth: &Auth, user: &'a User) -> &'a str {
+    if auth.can_view_pii_of(user) { &user.full_name } else { RESTRICTED }
+}
+
+fn print_pii(user: &User, visible: bool) {
+    if visible {
+        println!("  Full name: {}", user.full_name);
+        println!("  ssn: {}", user.ssn);
+        println!("  age: {}", user.age);
+        println!("  contact: {}", user.contact);
+    } else {
+        println!("  Full name: {}", RESTRICTED);
+        println!("  ssn: {}", RESTRICTED);
+        println!("  age: {}", RESTRICTED);
+        println!("  contact: {}", RESTRICTED);
+    }
+}
+
+fn print_user(auth: &Auth, user: &User) {
+    println!("Username: {}", user.username);
+    print_pii(user, auth.can_view_pii_of(user));
+    println!("  role: {:?}", user.role);
+}
+
+pub fn register_user(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    let username = get_input_string("Enter username".to_string());
+    if auth.db.users().contains(&username) {
+        println!("Username already exists");
+        return Ok(());
+    }
+    let password = get_input_string("Enter password".to_string());
+    let full_name = get_input_string("Enter full name".to_string());
+    let ssn = get_input_string("Enter ssn".to_string());
+    let age = match get_input_string("Enter age".to_string()).parse::<u32>() {
+        Ok(age) => age,
+        Err(_) => {
+            println!("Invalid age");
+            return Ok(());
+        }
+    };
+    let role = select_role("Select the user's role".to_string(), false);
+
+    match auth.register(username, password, full_name, ssn, age, role) {
+        Ok(user) => println!("User {} registered as {:?}", user.username, user.role),
+        Err(e) => println!("Registration failed: {}", e),
+    }
+    Ok(())
+}
+
+pub fn delete_user(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    let username = get_input_string("Enter username".to_string());
+    if auth.user().unwrap().username == username {
+        println!("You cannot delete your own account");
+        return Ok(());
+    }
+    match auth.db.remove_user(username) {
+        Ok(user) => {
+            auth.audit(AuditAction::Delete, EntityType::User, user.username.clone(), Some(format!("role: {:?}, status: {:?}", user.role, user.status)), None);
+            auth.db.commit()?;
+            println!("User {} deleted", user.username);
+        }
+        Err(e) => println!("{}", e),
+    }
+    Ok(())
+}
+
+pub fn search_users(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    if auth.db.users().count() == 0 {
+        println!("No users available");
+        return Ok(());
+    }
+
+    let options = if auth.can(Permission::ViewPii) { &["username", "full name", "ssn"][..] } else { &["username"][..] };
+    let menu = MenuHandler::new("Search by".to_string(), options.iter().copied());
+    let search_type = menu.run();
+    let query = get_input_string(format!("Enter {}", search_type));
+
+    let found = match search_type.as_str() {
+        "username" => auth.db.users().get(&query).into_iter().collect::<Vec<_>>(),
+        // exact matches come straight from the index, anything else falls back to a partial match
+        "full name" => match auth.db.find_users_by_full_name(&query) {
+            exact if !exact.is_empty() => exact,
+            _ => {
+                let query = query.to_lowercase();
+                auth.db.users().iterate().filter(|user| user.full_name.to_lowercase().contains(&query)).collect()
+            }
+        },
+        "ssn" => auth.db.find_users_by_ssn(&query),
+        _ => Vec::new(),
+    };
+
+    if found.is_empty() {
+        println!("No users found");
+    }
+    for user in found {
+        print_user(auth, user);
+    }
+    Ok(())
+}
+
+pub fn list_users(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    let options = ["All", "Patient", "Doctor", "Pharmacist", "TriageSupervisor", "EmergencyDoctor", "Admin"];
+    let menu = MenuHandler::new("Filter by role".to_string(), options.into_iter());
+    let filter = menu.run();
+
+    let users = match auth.db.users_data.as_ref() {
+        Some(users) => users.iter().filter(|user| filter == "All" || format!("{:?}", user.role) == filter).cloned().collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+    if users.is_empty() {
+        println!("No users found");
+        return Ok(());
+    }
+
+    let pages = users.len().div_ceil(USERS_PAGE_SIZE);
+    let mut page = 0;
+    loop {
+        for user in users.iter().skip(page * USERS_PAGE_SIZE).take(USERS_PAGE_SIZE) {
+            println!("{} ({:?}) - {}", user.username, user.role, display_name(auth, user));
+        }
+        println!("Page {} of {} ({} users)", page + 1, pages, users.len());
+
+        let options = ["Next page", "Previous page", "back"];
+        let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
+        let selected = menu.run();
+        auth.require_session()?;
+        match selected.as_str() {
+            "Next page" if page + 1 < pages => page += 1,
+            "Previous page" if page > 0 => page -= 1,
+            "back" => break,
+            _ => println!("No more pages"),
+        }
+    }
+    Ok(())
+}
+
+pub fn pending_approvals(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    loop {
+        let pending = match auth.db.users_data.as_ref() {
+            Some(users) => users.iter().filter(|user| user.status == AccountStatus::PendingApproval).cloned().collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        if pending.is_empty() {
+            println!("No accounts waiting for approval");
+            break;
+        }
+
+        let labels = pending.iter().map(|user| format!("{} ({:?}) - {}", user.username, user.role, display_name(auth, user))).collect::<Vec<_>>();
+        let options = labels.iter().map(|label| label.as_str()).chain(["back"]);
+        let menu = MenuHandler::new("Choose an account to review".to_string(), options);
+        let selected = menu.run();
+        auth.require_session()?;
+        if selected == "back" {
+            break;
+        }
+        let user = &pending[labels.iter().position(|label| *label == selected).unwrap()];
+
+        let options = ["Approve", "Reject", "back"];
+        let menu = MenuHandler::new(format!("Approve {} as {:?}?", user.username, user.role), options.into_iter());
+        let decision = menu.run();
+        auth.require_session()?;
+        match decision.as_str() {
+            "Approve" => {
+                auth.db.users_mut().get_mut(&user.username).unwrap().status = AccountStatus::Active;
+                auth.audit(AuditAction::Approve, EntityType::User, user.username.clone(), Some("status: PendingApproval".to_string()), Some("status: Active".to_string()));
+                auth.db.commit()?;
+                println!("Account {} approved", user.username);
+            }
+            "Reject" => {
+                if let Err(e) = auth.db.remove_user(user.username.clone()) {
+                    println!("{}", e);
+                    return Ok(());
+                }
+                auth.audit(AuditAction::Reject, EntityType::User, user.username.clone(), Some(format!("role: {:?}", user.role)), None);
+                auth.db.commit()?;
+                println!("Account {} rejected", user.username);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn unlock_users(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageUsers)?;
+    loop {
+        let locked = match auth.db.users_data.as_ref() {
+            Some(users) => users.iter().filter(|user| user.status == AccountStatus::Locked).map(|user| user.username.clone()).collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        if locked.is_empty() {
+            println!("No locked accounts");
+            break;
+        }
+
+        let options = locked.iter().map(|username| username.as_str()).chain(["back"]);
+        let menu = MenuHandler::new("Choose an account to unlock".to_string(), options);
+        let selected = menu.run();
+        auth.require_session()?;
+        if selected == "back" {
+            break;
+        }
+        auth.db.users_mut().get_mut(&selected).unwrap().unlock();
+        auth.audit(AuditAction::Unlock, EntityType::User, selected.clone(), Some("status: Locked".to_string()), Some("status: Active".to_string()));
+        auth.db.commit()?;
+        println!("Account {} unlocked", selected);
+    }
+    Ok(())
+}
+
+pub fn edit_role_permissions(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManagePermissions)?;
+    let role = select_role("Select a role to edit".to_string(), false);
+    loop {
+        let role_permissions = auth.db.get_role_permissions(role.clone()).clone();
+        let labels = ALL_PERMISSIONS.iter().map(|permission| {
+            let mark = if role_permissions.has(*permission) { "x" } else { " " };
+            format!("[{}] {:?}", mark, permission)
+        }).collect::<Vec<_>>();
+        let options = labels.iter().map(|label| label.as_str()).chain(["back"]);
+        let menu = MenuHandler::new(format!("Toggle permissions for {:?}", role), options);
+        let selected = menu.run();
+        auth.require_session()?;
+        if selected == "back" {
+            break;
+        }
+
+        let permission = ALL_PERMISSIONS[labels.iter().position(|label| *label == selected).unwrap()];
+        if role == Role::Admin && permission == Permission::ManagePermissions {
+            println!("Admins can't lose the permission to manage permissions");
+            continue;
+        }
+        auth.db.get_role_permissions(role.clone()).toggle(permission);
+        let after = auth.db.get_role_permissions(role.clone()).permissions.iter().copied().collect::<Vec<_>>();
+        let before = role_permissions.permissions.iter().copied().collect::<Vec<_>>();
+        auth.audit(AuditAction::Update, EntityType::RolePermissions, format!("{:?}", role), Some(format!("{:?}", before)), Some(format!("{:?}", after)));
+        auth.db.commit()?;
+    }
+    Ok(())
+}
+
+fn print_account_summary(auth: &Auth, user: &User) {
+    match user.role {
+        Role::Patient => {
+            let appointments = auth.db.queues_with_patient(&user.username).into_iter().filter_map(|doctors_list| {
+                doctors_list.patients.iter().find(|patient| patient.name == user.username).map(|patient| (doctors_list.doctor.clone(), patient.priority))
+            }).collect::<Vec<_>>();
+            if appointments.is_empty() {
+                println!("No upcoming appointments");
+            }
+            for (doctor, priority) in appointments {
+                println!("Upcoming appointment with {} (priority {})", doctor, priority);
+            }
+        }
+        Role::Doctor | Role::EmergencyDoctor => {
+            let queue_length = auth.db.doctors_lists().get(&user.username).map_or(0, |doctors_list| doctors_list.patients.len());
+            println!("Patients waiting in your queue: {}", queue_length);
+        }
+        Role::Pharmacist => {
+            let pending = auth.db.prescriptions().count();
+            println!("Prescriptions waiting to be dispensed: {}", pending);
+        }
+        _ => {}
+    }
+}
 
-        auth.db.map.move_object(&patient_loc, &dst_hosp, &ambulance.name).unwrap();
-        let log_message2 = format!("Ambulance {} sent from {} to {}", ambulance.name, patient_loc, dst_hosp);
-        println!("Ambulance sent from {} to {} via {}", ambulance.location, dst_hosp, patient_loc);
+pub fn my_account(auth: &mut Auth) -> Result<(), MenuError> {
+    let username = auth.current_user()?.username.clone();
+    loop {
+        let user = auth.db.users().get(&username).unwrap().clone();
+        print_user(auth, &user);
+        print_account_summary(auth, &user);
 
-        ambulance.location = dst_hosp.clone();
-        auth.db.insert_log(log_message1);
-        auth.db.insert_log(log_message2);
-        auth.db.commit().unwrap();
+        let options = ["Change password", "Update full name", "Update contact details", "Two-factor authentication", "back"];
+        let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
+        let selected = menu.run();
+        auth.require_session()?;
+        match selected.as_str() {
+            "Change password" => {
+                let old_password = get_input_string("Enter your current password".to_string());
+                if !user.verify_password(old_password) {
+                    println!("Wrong password");
+                    continue;
+                }
+                let new_password = get_input_string("Enter a new password".to_string());
+                if new_password != get_input_string("Repeat the new password".to_string()) {
+                    println!("Passwords don't match");
+                    continue;
+                }
+                let iterations = auth.hash_iterations;
+                auth.db.users_mut().get_mut(&username).unwrap().set_password(new_password, iterations);
+                auth.audit(AuditAction::Update, EntityType::User, username.clone(), None, Some("password changed".to_string()));
+                println!("Password changed");
+            }
+            "Update full name" => {
+                let full_name = get_input_string("Enter your full name".to_string());
+                auth.db.users_mut().get_mut(&username).unwrap().full_name = full_name;
+                auth.audit(AuditAction::Update, EntityType::User, username.clone(), None, Some("full name changed".to_string()));
+                println!("Full name updated");
+            }
+            "Update contact details" => {
+                let contact = get_input_string("Enter your phone number or email".to_string());
+                auth.db.users_mut().get_mut(&username).unwrap().contact = contact;
+                auth.audit(AuditAction::Update, EntityType::User, username.clone(), None, Some("contact details changed".to_string()));
+                println!("Contact details updated");
+            }
+            "Two-factor authentication" => two_factor_settings(auth)?,
+            _ => break,
+        }
+        auth.db.commit()?;
+        auth.refresh_user();
+    }
+    Ok(())
+}
+
+fn print_recovery_codes(codes: &[String]) {
+    println!("Recovery codes, each one works once. Store them somewhere safe, they won't be shown again:");
+    for code in codes {
+        println!("  {}", code);
+    }
+}
+
+// walks the logged in user through setting up an authenticator app, returns whether it got enabled
+pub fn enroll_totp(auth: &mut Auth) -> bool {
+    let username = match auth.user() {
+        Some(user) => user.username.clone(),
+        None => return false,
+    };
+    let secret = totp::generate_secret();
+    println!("Add this account to your authenticator app");
+    println!("Secret: {}", secret);
+    println!("URI: {}", totp::provisioning_uri("Hospital", &username, &secret));
+
+    let code = get_input_string("Enter the code shown by the app to confirm".to_string());
+    let step = match totp::verify(&secret, &code, Utc::now().timestamp() as u64) {
+        Some(step) => step,
+        None => {
+            println!("Invalid code, two-factor authentication was not enabled");
+            return false;
+        }
+    };
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let saved = auth.transaction(|auth| {
+        let user = auth.db.users_mut().get_mut(&username).ok_or(DbError::NotFound { entity: "user", key: username.clone() })?;
+        user.enable_totp(secret, &recovery_codes);
+        user.totp_last_step = step;
+        auth.audit(AuditAction::Update, EntityType::User, username.clone(), None, Some("two-factor authentication enabled".to_string()));
+        Ok::<(), DbError>(())
+    });
+    if let Err(e) = saved {
+        println!("{}, two-factor authentication was not enabled", MenuError::from(e));
+        return false;
+    }
+    auth.refresh_user();
+
+    println!("Two-factor authentication enabled");
+    print_recovery_codes(&recovery_codes);
+    true
+}
+
+fn two_factor_settings(auth: &mut Auth) -> Result<(), MenuError> {
+    let user = auth.user().unwrap().clone();
+    if !user.totp_enabled() {
+        enroll_totp(auth);
+        return Ok(());
+    }
+
+    println!("Two-factor authentication is enabled, {} recovery codes left", user.recovery_codes.len());
+    let options = ["Regenerate recovery codes", "Disable", "back"];
+    let menu = MenuHandler::new("What would you like to do?".to_string(), options.into_iter());
+    let choice = menu.run();
+    if choice == "back" {
+        return Ok(());
+    }
+    if choice == "Disable" && totp_mandatory(&user.role) {
+        println!("Two-factor authentication is mandatory for {:?}", user.role);
+        return Ok(());
+    }
+
+    let code = get_input_string("Enter a code from your authenticator app or a recovery code".to_string());
+    let stored = auth.db.users_mut().get_mut(&user.username).unwrap();
+    if !stored.verify_second_factor(&code, Utc::now().timestamp() as u64) {
+        println!("Invalid code");
+        return Ok(());
+    }
+    if choice == "Disable" {
+        stored.disable_totp();
+        auth.audit(AuditAction::Update, EntityType::User, user.username.clone(), None, Some("two-factor authentication disabled".to_string()));
+        println!("Two-factor authentication disabled");
     } else {
-        println!("No available ambulance found");
+        let recovery_codes = totp::generate_recovery_codes();
+        stored.set_recovery_codes(&recovery_codes);
+        auth.audit(AuditAction::Update, EntityType::User, user.username.clone(), None, Some("recovery codes regenerated".to_string()));
+        print_recovery_codes(&recovery_codes);
+    }
+    auth.db.commit()?;
+    Ok(())
+}
+
+pub fn rotate_encryption_key(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageEncryption)?;
+    let current = get_input_string("Enter the current database passphrase".to_string());
+    if !auth.db.verify_passphrase(&current) {
+        println!("Wrong passphrase");
+        return Ok(());
+    }
+    let new_passphrase = get_input_string("Enter the new database passphrase".to_string());
+    if new_passphrase.is_empty() || new_passphrase != get_input_string("Repeat the new passphrase".to_string()) {
+        println!("Passphrases don't match");
+        return Ok(());
+    }
+
+    match auth.db.rotate_field_key(&new_passphrase) {
+        Ok(()) => {
+            auth.audit(AuditAction::KeyRotation, EntityType::EncryptionKey, "field key".to_string(), None, None);
+            auth.db.commit()?;
+            println!("Encryption key rotated, every record was re-encrypted");
+        }
+        Err(e) => println!("Key rotation failed: {}", e),
+    }
+    Ok(())
+}
+
+pub fn take_snapshot(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageSnapshots)?;
+    let name = get_input_string("Enter a snapshot name (letters, digits, '-' and '_')".to_string());
+    if !snapshot::valid_name(&name) {
+        println!("Invalid snapshot name");
+        return Ok(());
+    }
+    match auth.db.save_snapshot(&name) {
+        Ok(info) => {
+            auth.audit(AuditAction::Create, EntityType::Snapshot, info.id(), None, None);
+            auth.db.commit()?;
+            println!("Snapshot {} saved to {}", info, info.path);
+        }
+        Err(e) => println!("Could not take the snapshot: {}", e),
+    }
+    Ok(())
+}
+
+fn available_snapshots(auth: &Auth) -> Option<Vec<SnapshotInfo>> {
+    match snapshot::list(auth.db.snapshot_dir()) {
+        Ok(snapshots) if snapshots.is_empty() => println!("No snapshots in {}", auth.db.snapshot_dir()),
+        Ok(snapshots) => return Some(snapshots),
+        Err(e) => println!("Could not list the snapshots: {}", e),
     }
+    None
 }
 
-pub fn print_logs(auth: &mut Auth) {
-    let logs = &auth.db.logs_data;
-    for (datetime, log) in logs.iter() {
-        println!("{}: {}", datetime, log);
+pub fn list_snapshots(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageSnapshots)?;
+    if let Some(snapshots) = available_snapshots(auth) {
+        for snapshot in snapshots.iter() {
+            println!("{}  {}", snapshot, snapshot.path);
+        }
+    }
+    Ok(())
+}
+
+const CURRENT_STATE: &str = "Current database";
+
+// None is the database as it is now, only offered when with_current is set
+fn select_snapshot(query: &str, snapshots: &[SnapshotInfo], with_current: bool) -> Option<SnapshotInfo> {
+    let mut labels = snapshots.iter().map(|snapshot| snapshot.to_string()).collect::<Vec<_>>();
+    if with_current {
+        labels.push(CURRENT_STATE.to_string());
+    }
+    let menu = MenuHandler::new(query.to_string(), labels.iter().map(|label| label.as_str()));
+    let selected = menu.run();
+    labels.iter().position(|label| *label == selected).and_then(|index| snapshots.get(index).cloned())
+}
+
+fn open_snapshot(auth: &Auth, info: &SnapshotInfo) -> Result<Database, DbError> {
+    match auth.db.load_snapshot(info, None) {
+        Err(DbError::WrongPassphrase) => {
+            let passphrase = get_input_string(format!("{} was taken under an earlier encryption key, enter the passphrase of that time", info));
+            auth.db.load_snapshot(info, Some(&passphrase))
+        }
+        result => result,
+    }
+}
+
+fn print_diff(diff: &Diff) {
+    for count in diff.counts.iter().filter(|count| count.before != 0 || count.after != 0) {
+        println!("{}: {} -> {}", count.kind, count.before, count.after);
+    }
+    if diff.is_empty() {
+        println!("No records differ");
+    }
+    for record in diff.records.iter() {
+        println!("{}", record);
+    }
+}
+
+pub fn diff_snapshots(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageSnapshots)?;
+    let snapshots = match available_snapshots(auth) {
+        Some(snapshots) => snapshots,
+        None => return Ok(()),
+    };
+    let mut states = Vec::new();
+    for query in ["Compare from", "Compare to"] {
+        let state = match select_snapshot(query, &snapshots, true) {
+            Some(info) => open_snapshot(auth, &info),
+            None => Ok(auth.db.clone()),
+        };
+        match state {
+            Ok(state) => states.push(state),
+            Err(e) => {
+                println!("Could not open the snapshot: {}", e);
+                return Ok(());
+            }
+        }
+    }
+    print_diff(&snapshot::diff(&states[0], &states[1]));
+    Ok(())
+}
+
+pub fn restore_snapshot(auth: &mut Auth) -> Result<(), MenuError> {
+    auth.require(Permission::ManageSnapshots)?;
+    let snapshots = match available_snapshots(auth) {
+        Some(snapshots) => snapshots,
+        None => return Ok(()),
+    };
+    let info = match select_snapshot("Restore which snapshot?", &snapshots, false) {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+    let restored = match open_snapshot(auth, &info) {
+        Ok(restored) => restored,
+        Err(e) => {
+            println!("Could not open the snapshot: {}", e);
+            return Ok(());
+        }
+    };
+
+    println!("Restoring {} makes these changes:", info);
+    let mut diff = snapshot::diff(auth.db, &restored);
+    // the audit log is the one part that isn't restored
+    diff.counts.retain(|count| count.kind != "audit events");
+    print_diff(&diff);
+    if get_input_string("Restore this snapshot? (y/n)".to_string()) != "y" {
+        println!("Nothing was restored");
+        return Ok(());
+    }
+
+    // the state being replaced is kept too, so a restore can be undone the same way
+    let saved = match auth.db.save_snapshot("before-restore") {
+        Ok(saved) => saved,
+        Err(e) => {
+            println!("Could not save the current state, nothing was restored: {}", e);
+            return Ok(());
+        }
+    };
+    let result = auth.transaction(|auth| {
+        auth.db.restore_snapshot(restored);
+        auth.audit(AuditAction::Restore, EntityType::Snapshot, info.id(), Some(format!("saved as {}", saved.id())), None);
+        Ok::<(), DbError>(())
+    });
+    match result {
+        Ok(()) => println!("Restored {}, the previous state was saved as {}", info, saved),
+        Err(e) => {
+            println!("Restore failed, nothing was changed: {}", e);
+            return Ok(());
+        }
+    }
+
+    let username = auth.current_user()?.username.clone();
+    if !auth.db.users().contains(&username) {
+        println!("Your account is not in the restored snapshot, logging out");
+        auth.logout();
+    } else {
+        auth.refresh_user();
     }
+    Ok(())
 }
@@ -0,0 +1,166 @@
+use rand::RngCore;
+
+use crate::sha_hasher::{HmacSha256, Sha256};
+
+// stored format: pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+const SCHEME: &str = "pbkdf2-sha256";
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, key_len: usize) -> Vec<u8> {
+    let prf = HmacSha256::new(password);
+    let mut key = Vec::with_capacity(key_len);
+    let mut block_index: u32 = 1;
+
+    while key.len() < key_len {
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&block_index.to_be_bytes());
+        let mut u = mac.finalize();
+        let mut block = u;
+
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize();
+            for (b, x) in block.iter_mut().zip(u.iter()) {
+                *b ^= x;
+            }
+        }
+
+        let take = (key_len - key.len()).min(block.len());
+        key.extend_from_slice(&block[..take]);
+        block_index += 1;
+    }
+    key
+}
+
+pub fn hash_password(password: &str, iterations: u32) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    hash_with_salt(password, &salt, iterations)
+}
+
+fn hash_with_salt(password: &str, salt: &[u8], iterations: u32) -> String {
+    let hash = pbkdf2_sha256(password.as_bytes(), salt, iterations, KEY_LEN);
+    format!("{}${}${}${}", SCHEME, iterations, hex::encode(salt), hex::encode(hash))
+}
+
+// unsalted hex(sha256(password)) hashes written before the pbkdf2 scheme
+fn legacy_hash(password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(password.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// the old sha256 padded some lengths wrong (56 to 63 bytes past the last full block),
+// legacy hashes of those passwords were stored with that digest
+fn legacy_hash_old_padding(password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(password.as_bytes());
+    hex::encode(hasher.finalize_legacy())
+}
+
+fn parse(stored: &str) -> Option<(u32, Vec<u8>, Vec<u8>)> {
+    let mut parts = stored.split('$');
+    if parts.next()? != SCHEME {
+        return None;
+    }
+    let iterations = parts.next()?.parse::<u32>().ok()?;
+    let salt = hex::decode(parts.next()?).ok()?;
+    let hash = hex::decode(parts.next()?).ok()?;
+    if parts.next().is_some() || iterations == 0 {
+        return None;
+    }
+    Some((iterations, salt, hash))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    match parse(stored) {
+        Some((iterations, salt, hash)) => {
+            let candidate = pbkdf2_sha256(password.as_bytes(), &salt, iterations, hash.len());
+            constant_time_eq(&candidate, &hash)
+        }
+        None => {
+            constant_time_eq(legacy_hash(password).as_bytes(), stored.as_bytes())
+                || constant_time_eq(legacy_hash_old_padding(password).as_bytes(), stored.as_bytes())
+        }
+    }
+}
+
+// true for legacy hashes and for hashes made with fewer iterations than the current setting
+pub fn needs_rehash(stored: &str, iterations: u32) -> bool {
+    match parse(stored) {
+        Some((stored_iterations, salt, _)) => stored_iterations < iterations || salt.len() < SALT_LEN,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pbkdf2_vectors() {
+        // RFC 7914 section 11 / widely published PBKDF2-HMAC-SHA256 vectors
+        assert_eq!(
+            hex::encode(pbkdf2_sha256(b"password", b"salt", 1, 32)),
+            "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"
+        );
+        assert_eq!(
+            hex::encode(pbkdf2_sha256(b"password", b"salt", 2, 32)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+        assert_eq!(
+            hex::encode(pbkdf2_sha256(b"passwd", b"salt", 1, 64)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let stored = hash_password("password1", 10);
+        assert!(stored.starts_with("pbkdf2-sha256$10$"));
+        assert!(verify_password("password1", &stored));
+        assert!(!verify_password("password2", &stored));
+    }
+
+    #[test]
+    fn test_salts_differ() {
+        assert_ne!(hash_password("password1", 10), hash_password("password1", 10));
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let stored = legacy_hash("password1");
+        assert!(verify_password("password1", &stored));
+        assert!(!verify_password("password2", &stored));
+        assert!(needs_rehash(&stored, DEFAULT_ITERATIONS));
+    }
+
+    #[test]
+    fn test_legacy_hash_old_padding() {
+        // written by the old sha256 before its padding was fixed
+        let password = "p".repeat(60);
+        let stored = "614298325c52e790f3b819c9b5e21e7b7a7124cc0f41860d762c3ac3811e20c9";
+        assert_ne!(legacy_hash(&password), stored);
+        assert!(verify_password(&password, stored));
+        assert!(!verify_password(&"p".repeat(59), stored));
+        assert!(needs_rehash(stored, DEFAULT_ITERATIONS));
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let stored = hash_password("password1", 10);
+        assert!(!needs_rehash(&stored, 10));
+        assert!(needs_rehash(&stored, 20));
+    }
+}
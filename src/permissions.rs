@@ -0,0 +1,150 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+use crate::data_structures::linked_list::LinkedList;
+use crate::db::entities::{Role, UniqueAttribute};
+use crate::db::index::{IndexSpec, SecondaryIndexes};
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    MakeAppointment,
+    CancelAppointment,
+    VisitPatients,
+    AssignPatients,
+    DispenseMedications,
+    ViewDrugs,
+    ManageDrugs,
+    ManageDrugGroups,
+    ViewMap,
+    ManageMap,
+    ManageAmbulances,
+    ViewLogs,
+    ManageUsers,
+    ManagePermissions,
+    ViewPii,
+    ManageEncryption,
+    EmergencyAccess,
+    ManageSnapshots,
+}
+
+pub const ALL_PERMISSIONS: [Permission; 18] = [
+    Permission::MakeAppointment,
+    Permission::CancelAppointment,
+    Permission::VisitPatients,
+    Permission::AssignPatients,
+    Permission::DispenseMedications,
+    Permission::ViewDrugs,
+    Permission::ManageDrugs,
+    Permission::ManageDrugGroups,
+    Permission::ViewMap,
+    Permission::ManageMap,
+    Permission::ManageAmbulances,
+    Permission::ViewLogs,
+    Permission::ManageUsers,
+    Permission::ManagePermissions,
+    Permission::ViewPii,
+    Permission::ManageEncryption,
+    Permission::EmergencyAccess,
+    Permission::ManageSnapshots,
+];
+
+pub fn all_permissions() -> LinkedList<Permission> {
+    let mut permissions = LinkedList::new();
+    for permission in ALL_PERMISSIONS {
+        permissions.insert(permission);
+    }
+    permissions
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PermissionError {
+    NotLoggedIn,
+    // the session ran out in the middle of a flow, Auth::check_session already told the user
+    SessionExpired,
+    Denied { role: Role, permission: Permission },
+}
+
+impl fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PermissionError::NotLoggedIn => write!(f, "You need to log in first"),
+            PermissionError::SessionExpired => write!(f, "Your session has expired"),
+            PermissionError::Denied { role, permission } => write!(f, "Permission denied: {:?} does not have {:?}", role, permission),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RolePermissions {
+    pub role: Role,
+    pub permissions: LinkedList<Permission>,
+}
+
+impl UniqueAttribute for RolePermissions {
+    const ENTITY: &'static str = "role permissions";
+
+    fn uattr(&self) -> String {
+        format!("{:?}", self.role)
+    }
+}
+
+impl SecondaryIndexes for RolePermissions {
+    const INDEXES: &'static [IndexSpec<Self>] = &[];
+}
+
+impl RolePermissions {
+    pub fn default_for(role: Role) -> Self {
+        let granted: &[Permission] = match role {
+            Role::Patient => &[Permission::MakeAppointment, Permission::CancelAppointment],
+            Role::Doctor => &[Permission::VisitPatients, Permission::ViewPii],
+            // sees a patient's details only through a logged emergency grant
+            Role::EmergencyDoctor => &[Permission::VisitPatients, Permission::EmergencyAccess],
+            Role::Pharmacist => &[
+                Permission::DispenseMedications,
+                Permission::ViewDrugs,
+                Permission::ManageDrugs,
+                Permission::ManageDrugGroups,
+            ],
+            Role::TriageSupervisor => &[Permission::AssignPatients],
+            Role::Admin => &[
+                Permission::ViewMap,
+                Permission::ManageMap,
+                Permission::ManageAmbulances,
+                Permission::ViewLogs,
+                Permission::ManageUsers,
+                Permission::ManagePermissions,
+                Permission::ViewPii,
+                Permission::ManageEncryption,
+                Permission::ManageSnapshots,
+            ],
+        };
+
+        let mut permissions = LinkedList::new();
+        for permission in granted {
+            permissions.insert(*permission);
+        }
+        RolePermissions { role, permissions }
+    }
+
+    // hands out the role's defaults among the permissions that didn't exist yet when this entry was stored,
+    // anything the entry already knew about stays as the admin left it
+    pub fn merge_defaults(&mut self, known: &LinkedList<Permission>) {
+        let defaults = RolePermissions::default_for(self.role.clone());
+        for permission in ALL_PERMISSIONS {
+            if !known.contains(&permission) && defaults.has(permission) && !self.has(permission) {
+                self.permissions.insert(permission);
+            }
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn toggle(&mut self, permission: Permission) {
+        if !self.permissions.remove(&permission) {
+            self.permissions.insert(permission);
+        }
+    }
+}
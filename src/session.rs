@@ -0,0 +1,144 @@
+use std::fmt;
+
+use crate::db::entities::{Role, User};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionPolicy {
+    pub idle_timeout_secs: i64,
+    pub max_lifetime_secs: i64,
+}
+
+impl SessionPolicy {
+    // roles that can see more data get logged out of shared terminals sooner
+    pub fn for_role(role: &Role) -> Self {
+        let (idle_minutes, lifetime_hours) = match role {
+            Role::Admin => (5, 1),
+            Role::Pharmacist => (10, 8),
+            Role::Doctor | Role::TriageSupervisor => (15, 12),
+            // emergency shifts can't afford to re-authenticate mid treatment
+            Role::EmergencyDoctor => (30, 12),
+            Role::Patient => (10, 1),
+        };
+        SessionPolicy {
+            idle_timeout_secs: idle_minutes * 60,
+            max_lifetime_secs: lifetime_hours * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SessionExpiry {
+    Idle,
+    Lifetime,
+}
+
+impl fmt::Display for SessionExpiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionExpiry::Idle => write!(f, "Your session expired due to inactivity"),
+            SessionExpiry::Lifetime => write!(f, "Your session reached its maximum length"),
+        }
+    }
+}
+
+// how long a break-the-glass grant lets an emergency doctor read a patient's record
+pub const EMERGENCY_ACCESS_SECS: i64 = 30 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmergencyGrant {
+    pub patient: String,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user: User,
+    pub login_time: i64,
+    pub last_activity: i64,
+    pub policy: SessionPolicy,
+    pub emergency_grants: Vec<EmergencyGrant>,
+}
+
+impl Session {
+    pub fn new(user: User, now: i64) -> Self {
+        let policy = SessionPolicy::for_role(&user.role);
+        Session { user, login_time: now, last_activity: now, policy, emergency_grants: Vec::new() }
+    }
+
+    pub fn expiry(&self, now: i64) -> Option<SessionExpiry> {
+        if now - self.login_time >= self.policy.max_lifetime_secs {
+            Some(SessionExpiry::Lifetime)
+        } else if now - self.last_activity >= self.policy.idle_timeout_secs {
+            Some(SessionExpiry::Idle)
+        } else {
+            None
+        }
+    }
+
+    pub fn touch(&mut self, now: i64) {
+        self.last_activity = now;
+    }
+
+    // grants only live as long as the session, logging out revokes them
+    pub fn grant_emergency_access(&mut self, patient: String, now: i64) -> i64 {
+        let expires_at = now + EMERGENCY_ACCESS_SECS;
+        self.emergency_grants.retain(|grant| grant.patient != patient && grant.expires_at > now);
+        self.emergency_grants.push(EmergencyGrant { patient, expires_at });
+        expires_at
+    }
+
+    pub fn emergency_grant(&self, patient: &str, now: i64) -> Option<&EmergencyGrant> {
+        self.emergency_grants.iter().find(|grant| grant.patient == patient && grant.expires_at > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(role: Role) -> Session {
+        let user = User::new("user".to_string(), String::new(), "User".to_string(), "000".to_string(), 30, role);
+        Session::new(user, 1_000)
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut session = session(Role::Doctor);
+        let idle = session.policy.idle_timeout_secs;
+        assert_eq!(session.expiry(1_000 + idle - 1), None);
+        assert_eq!(session.expiry(1_000 + idle), Some(SessionExpiry::Idle));
+
+        session.touch(1_000 + idle - 1);
+        assert_eq!(session.expiry(1_000 + idle), None);
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let mut session = session(Role::Admin);
+        let lifetime = session.policy.max_lifetime_secs;
+        session.touch(1_000 + lifetime - 1);
+        assert_eq!(session.expiry(1_000 + lifetime), Some(SessionExpiry::Lifetime));
+    }
+
+    #[test]
+    fn test_emergency_grant_expires() {
+        let mut session = session(Role::EmergencyDoctor);
+        assert!(session.emergency_grant("patient1", 1_000).is_none());
+
+        let expires_at = session.grant_emergency_access("patient1".to_string(), 1_000);
+        assert_eq!(expires_at, 1_000 + EMERGENCY_ACCESS_SECS);
+        assert!(session.emergency_grant("patient1", expires_at - 1).is_some());
+        assert!(session.emergency_grant("patient2", 1_000).is_none());
+        assert!(session.emergency_grant("patient1", expires_at).is_none());
+
+        // granting again replaces the old grant instead of piling up
+        session.grant_emergency_access("patient1".to_string(), 2_000);
+        assert_eq!(session.emergency_grants.len(), 1);
+    }
+
+    #[test]
+    fn test_policy_per_role() {
+        assert!(SessionPolicy::for_role(&Role::Admin).idle_timeout_secs < SessionPolicy::for_role(&Role::EmergencyDoctor).idle_timeout_secs);
+    }
+}
@@ -0,0 +1,158 @@
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    data: Vec<u8>,
+    bit_len: u64,
+}
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            data: Vec::new(),
+            bit_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.data.push(byte);
+            if self.data.len() == 64 {
+                self.transform();
+                self.bit_len += 512;
+            }
+        }
+    }
+
+    fn transform(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in self.data.chunks(4).take(16).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+        self.state[4] = self.state[4].wrapping_add(e);
+
+        self.data.clear();
+    }
+
+    pub fn finalize(&mut self) -> [u8; 20] {
+        self.bit_len += (self.data.len() * 8) as u64;
+        self.data.push(0x80);
+
+        // the length doesn't fit in this block, pad it out and start a new one
+        if self.data.len() > 56 {
+            while self.data.len() < 64 {
+                self.data.push(0x00);
+            }
+            self.transform();
+        }
+        while self.data.len() < 56 {
+            self.data.push(0x00);
+        }
+        self.data.extend_from_slice(&self.bit_len.to_be_bytes());
+        self.transform();
+
+        let mut hash = [0; 20];
+        for (i, word) in self.state.iter().enumerate() {
+            hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+}
+
+const BLOCK_SIZE: usize = 64;
+
+pub fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        let mut hasher = Sha1::new();
+        hasher.update(key);
+        block[..20].copy_from_slice(&hasher.finalize());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(&block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let inner_hash = inner.finalize();
+
+    let mut outer = Sha1::new();
+    outer.update(&block.map(|b| b ^ 0x5c));
+    outer.update(&inner_hash);
+    outer.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_hex(data: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hex::encode(hasher.finalize())
+    }
+
+    #[test]
+    fn test_empty_string() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    }
+
+    #[test]
+    fn test_abc() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn test_multi_block() {
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202 test cases 1, 2 and 6
+        assert_eq!(
+            hex::encode(hmac_sha1(&[0x0b; 20], b"Hi There")),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+}
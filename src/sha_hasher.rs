@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct Sha256 {
     state: [u32; 8],
     data: Vec<u8>,
@@ -122,7 +123,37 @@ impl Sha256 {
 
     pub fn finalize(&mut self) -> [u8; 32] {
         let mut i = self.data_len;
-        let mut len = self.data_len;
+        self.data.push(0x80);
+        i += 1;
+
+        // the length doesn't fit in this block, pad it out and start a new one
+        if self.data_len >= 56 {
+            while i < 64 {
+                self.data.push(0x00);
+                i += 1;
+            }
+            self.transform();
+            i = 0;
+        }
+
+        while i < 56 {
+            self.data.push(0x00);
+            i += 1;
+        }
+
+        self.bit_len += (self.data_len * 8) as u64;
+        for i in 0..8 {
+            self.data.push(((self.bit_len >> (56 - (i * 8))) & 0xff) as u8);
+        }
+
+        self.transform();
+        self.digest()
+    }
+
+    // the padding used before finalize was fixed, it skips the 0x80 byte and cuts the length short
+    // once 56 or more bytes are left over. only for checking hashes that were written back then
+    pub fn finalize_legacy(&mut self) -> [u8; 32] {
+        let mut i = self.data_len;
         if self.data_len < 56 {
             self.data.push(0x80);
             i += 1;
@@ -133,14 +164,16 @@ impl Sha256 {
             i += 1;
         }
 
-        self.bit_len += (len * 8) as u64;
+        self.bit_len += (self.data_len * 8) as u64;
         for i in 0..8 {
             self.data.push(((self.bit_len >> (56 - (i * 8))) & 0xff) as u8);
-            len += 1;
         }
 
         self.transform();
+        self.digest()
+    }
 
+    fn digest(&self) -> [u8; 32] {
         let mut hash = [0; 32];
         for i in 0..8 {
             hash[i * 4] = (self.state[i] >> 24) as u8;
@@ -152,6 +185,43 @@ impl Sha256 {
     }
 }
 
+const BLOCK_SIZE: usize = 64;
+
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            let mut hasher = Sha256::new();
+            hasher.update(key);
+            block[..32].copy_from_slice(&hasher.finalize());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|b| b ^ 0x5c));
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        let inner_hash = self.inner.finalize();
+        self.outer.update(&inner_hash);
+        self.outer.finalize()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,4 +247,60 @@ mod tests {
             "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_multi_block_padding() {
+        // 56 bytes forces the length into a second block
+        let mut hasher = Sha256::new();
+        hasher.update(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq");
+        let result = hasher.finalize();
+        assert_eq!(
+            hex::encode(result),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_legacy_padding() {
+        // short inputs were always padded correctly
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        assert_eq!(
+            hex::encode(hasher.finalize_legacy()),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        // what the old finalize returned for 60 bytes
+        let mut hasher = Sha256::new();
+        hasher.update(&[b'p'; 60]);
+        assert_eq!(
+            hex::encode(hasher.finalize_legacy()),
+            "614298325c52e790f3b819c9b5e21e7b7a7124cc0f41860d762c3ac3811e20c9"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test case 2
+        let mut mac = HmacSha256::new(b"Jefe");
+        mac.update(b"what do ya want for nothing?");
+        let result = mac.finalize();
+        assert_eq!(
+            hex::encode(result),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256_long_key() {
+        // RFC 4231 test case 6
+        let key = [0xaa; 131];
+        let mut mac = HmacSha256::new(&key);
+        mac.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        let result = mac.finalize();
+        assert_eq!(
+            hex::encode(result),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
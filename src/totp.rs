@@ -0,0 +1,155 @@
+use rand::RngCore;
+
+use crate::sha1_hasher::hmac_sha1;
+use crate::sha_hasher::Sha256;
+
+// RFC 6238 defaults, which is also what authenticator apps assume
+pub const TIME_STEP: u64 = 30;
+pub const DIGITS: u32 = 6;
+pub const SKEW_STEPS: u64 = 1;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 8;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+// accepts lowercase, spaces and padding since users tend to type secrets by hand
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in encoded.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET.iter().position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base32_encode(&secret)
+}
+
+pub fn hotp(key: &[u8], counter: u64, digits: u32) -> String {
+    let hash = hmac_sha1(key, &counter.to_be_bytes());
+    let offset = (hash[19] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+pub fn totp(key: &[u8], unix_time: u64, digits: u32) -> String {
+    hotp(key, unix_time / TIME_STEP, digits)
+}
+
+// returns the matching time step so callers can refuse to accept it twice
+pub fn verify(secret: &str, code: &str, unix_time: u64) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let current = unix_time / TIME_STEP;
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS).find(|step| totp(&key, step * TIME_STEP, DIGITS) == code.trim())
+}
+
+fn url_encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        url_encode(issuer), url_encode(account), secret, url_encode(issuer), DIGITS, TIME_STEP
+    )
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES).map(|_| {
+        let mut bytes = [0u8; 5];
+        rng.fill_bytes(&mut bytes);
+        let code = hex::encode(bytes);
+        format!("{}-{}", &code[..5], &code[5..])
+    }).collect()
+}
+
+// recovery codes are random enough that a plain sha256 is sufficient
+pub fn hash_recovery_code(code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(code.trim().to_lowercase().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("mzxw 6ytb oi").unwrap(), b"foobar");
+        assert!(base32_decode("MZXW1").is_none());
+
+        let secret = generate_secret();
+        assert_eq!(base32_decode(&secret).unwrap().len(), SECRET_LEN);
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let key = b"12345678901234567890";
+        assert_eq!(totp(key, 59, 8), "94287082");
+        assert_eq!(totp(key, 1111111109, 8), "07081804");
+        assert_eq!(totp(key, 1111111111, 8), "14050471");
+        assert_eq!(totp(key, 1234567890, 8), "89005924");
+        assert_eq!(totp(key, 2000000000, 8), "69279037");
+        assert_eq!(totp(key, 20000000000, 8), "65353130");
+    }
+
+    #[test]
+    fn test_verify_with_skew() {
+        let secret = base32_encode(b"12345678901234567890");
+        let code = totp(b"12345678901234567890", 1111111111, DIGITS);
+        assert_eq!(verify(&secret, &code, 1111111111), Some(1111111111 / TIME_STEP));
+        assert!(verify(&secret, &code, 1111111111 + TIME_STEP).is_some());
+        assert!(verify(&secret, &code, 1111111111 - TIME_STEP).is_some());
+        assert!(verify(&secret, &code, 1111111111 + 3 * TIME_STEP).is_none());
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        assert_eq!(
+            provisioning_uri("Hospital", "dr john", "JBSWY3DPEHPK3PXP"),
+            "otpauth://totp/Hospital:dr%20john?secret=JBSWY3DPEHPK3PXP&issuer=Hospital&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[0].to_uppercase()));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}